@@ -0,0 +1,46 @@
+/// Systemd units touched by `switch-to-configuration`, as reported on stderr, e.g.
+/// `restarting the following units: nginx.service, sshd.service`.
+#[derive(Debug, Default, PartialEq)]
+pub struct UnitChanges {
+    pub stopped: Vec<String>,
+    pub restarted: Vec<String>,
+    pub reloaded: Vec<String>,
+    pub started: Vec<String>,
+}
+
+impl UnitChanges {
+    pub fn is_empty(&self) -> bool {
+        self.stopped.is_empty() && self.restarted.is_empty() && self.reloaded.is_empty() && self.started.is_empty()
+    }
+}
+
+/// Collects the unit lists from an activation's output. `.service` is dropped from unit
+/// names; other unit types keep their suffix.
+pub fn parse(output: &str) -> UnitChanges {
+    let mut changes = UnitChanges::default();
+    for line in output.lines() {
+        let line = line.trim();
+        let (list, units) = if let Some(units) = line.strip_prefix("stopping the following units: ") {
+            (&mut changes.stopped, units)
+        } else if let Some(units) = line.strip_prefix("restarting the following units: ") {
+            (&mut changes.restarted, units)
+        } else if let Some(units) = line.strip_prefix("reloading the following units: ") {
+            (&mut changes.reloaded, units)
+        } else if let Some(units) = line
+            .strip_prefix("starting the following units: ")
+            .or_else(|| line.strip_prefix("the following new units were started: "))
+        {
+            (&mut changes.started, units)
+        } else {
+            continue;
+        };
+
+        for unit in units.split(',').map(str::trim).filter(|u| !u.is_empty()) {
+            let unit = unit.strip_suffix(".service").unwrap_or(unit).to_string();
+            if !list.contains(&unit) {
+                list.push(unit);
+            }
+        }
+    }
+    changes
+}
@@ -0,0 +1,37 @@
+use std::{fs, io, path::PathBuf};
+
+/// Remembers the derivation of the last failed build so `nix rebuild-log` can show its log.
+///
+/// Stored at `~/.config/negma/last-failed-drv` as a single store path.
+pub fn path(home_dir: &str) -> PathBuf {
+    PathBuf::from(format!("{}/.config/negma/last-failed-drv", home_dir))
+}
+
+/// Extracts the first failing derivation from nix's stderr, e.g. from
+/// `error: builder for '/nix/store/<hash>-foo.drv' failed with exit code 2` or
+/// `error: Cannot build '/nix/store/<hash>-foo.drv'.`
+pub fn failed_derivation(stderr: &str) -> Option<String> {
+    ["builder for '", "Cannot build '"].iter().find_map(|marker| {
+        stderr.lines().find_map(|line| {
+            let rest = &line[line.find(marker)? + marker.len()..];
+            let drv = &rest[..rest.find('\'')?];
+            (drv.starts_with("/nix/store/") && drv.ends_with(".drv")).then(|| drv.to_string())
+        })
+    })
+}
+
+/// How often nix warned that a flake's git tree is dirty, i.e. has uncommitted changes:
+/// `warning: Git tree '/etc/nixos' is dirty`.
+pub fn dirty_tree_warnings(stderr: &str) -> usize {
+    stderr.lines().filter(|line| line.contains("Git tree '") && line.ends_with("' is dirty")).count()
+}
+
+pub fn record(home_dir: &str, drv: &str) -> io::Result<()> {
+    fs::write(path(home_dir), format!("{}\n", drv))
+}
+
+pub fn last(home_dir: &str) -> Option<String> {
+    let drv = fs::read_to_string(path(home_dir)).ok()?;
+    let drv = drv.trim();
+    (!drv.is_empty()).then(|| drv.to_string())
+}
@@ -0,0 +1,106 @@
+/// Top-level commands offered by the completion scripts.
+const COMMANDS: &str = "home h nix n edit-cfg config history doctor status explain gc completions uninstall";
+const HOME_SUBCOMMANDS: &str =
+    "edit fmt make switch check gc clean backup list-generations rollback diff news";
+const NIX_SUBCOMMANDS: &str = "edit fmt make switch boot test build check gc clean list-generations \
+                               list-profiles gen-info rollback why-depends rebuild-log optimise show-config upgrade";
+const CONFIG_SUBCOMMANDS: &str = "init reset default get set check";
+
+pub const SHELLS: &[&str] = &["bash", "zsh", "fish"];
+
+/// Completion script for `shell`. With `dynamic`, generation arguments (`rollback`,
+/// `home diff`) are completed by calling the hidden `negma __complete rollback <home|nix>`.
+pub fn script(shell: &str, dynamic: bool) -> Option<String> {
+    match shell {
+        "bash" => Some(bash(dynamic)),
+        "zsh" => Some(zsh(dynamic)),
+        "fish" => Some(fish(dynamic)),
+        _ => None,
+    }
+}
+
+fn bash(dynamic: bool) -> String {
+    let generations = if dynamic {
+        r#"
+    if [[ "${COMP_WORDS[2]}" == rollback || "${COMP_WORDS[2]}" == diff ]]; then
+        local scope=nix
+        [[ "${COMP_WORDS[1]}" == home || "${COMP_WORDS[1]}" == h ]] && scope=home
+        COMPREPLY=($(compgen -W "$(negma __complete rollback $scope 2>/dev/null)" -- "$cur"))
+    fi"#
+    } else {
+        ""
+    };
+    format!(
+        r#"# bash completion for negma
+_negma() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    if [[ $COMP_CWORD -eq 1 ]]; then
+        COMPREPLY=($(compgen -W "{COMMANDS}" -- "$cur"))
+        return
+    fi
+    if [[ $COMP_CWORD -eq 2 ]]; then
+        case "${{COMP_WORDS[1]}}" in
+            home|h) COMPREPLY=($(compgen -W "{HOME_SUBCOMMANDS}" -- "$cur")) ;;
+            nix|n) COMPREPLY=($(compgen -W "{NIX_SUBCOMMANDS}" -- "$cur")) ;;
+            config) COMPREPLY=($(compgen -W "{CONFIG_SUBCOMMANDS}" -- "$cur")) ;;
+            completions) COMPREPLY=($(compgen -W "bash zsh fish" -- "$cur")) ;;
+        esac
+        return
+    fi{generations}
+}}
+complete -F _negma negma
+"#
+    )
+}
+
+fn zsh(dynamic: bool) -> String {
+    let generations = if dynamic {
+        r#"
+    *)
+        if [[ $words[3] == rollback || $words[3] == diff ]]; then
+            local scope=nix
+            [[ $words[2] == (home|h) ]] && scope=home
+            compadd -- ${(f)"$(negma __complete rollback $scope 2>/dev/null)"}
+        fi ;;"#
+    } else {
+        ""
+    };
+    format!(
+        r#"#compdef negma
+_negma() {{
+    case $CURRENT in
+    2) compadd -- {COMMANDS} ;;
+    3)
+        case $words[2] in
+        home|h) compadd -- {HOME_SUBCOMMANDS} ;;
+        nix|n) compadd -- {NIX_SUBCOMMANDS} ;;
+        config) compadd -- {CONFIG_SUBCOMMANDS} ;;
+        completions) compadd -- bash zsh fish ;;
+        esac ;;{generations}
+    esac
+}}
+compdef _negma negma
+"#
+    )
+}
+
+fn fish(dynamic: bool) -> String {
+    let mut script = format!(
+        r#"# fish completion for negma
+complete -c negma -f
+complete -c negma -n __fish_use_subcommand -a "{COMMANDS}"
+complete -c negma -n "__fish_seen_subcommand_from home h; and not __fish_seen_subcommand_from {HOME_SUBCOMMANDS}" -a "{HOME_SUBCOMMANDS}"
+complete -c negma -n "__fish_seen_subcommand_from nix n; and not __fish_seen_subcommand_from {NIX_SUBCOMMANDS}" -a "{NIX_SUBCOMMANDS}"
+complete -c negma -n "__fish_seen_subcommand_from config" -a "{CONFIG_SUBCOMMANDS}"
+complete -c negma -n "__fish_seen_subcommand_from completions" -a "bash zsh fish"
+"#
+    );
+    if dynamic {
+        script.push_str(
+            r#"complete -c negma -n "__fish_seen_subcommand_from home h; and __fish_seen_subcommand_from rollback diff" -a "(negma __complete rollback home 2>/dev/null)"
+complete -c negma -n "__fish_seen_subcommand_from nix n; and __fish_seen_subcommand_from rollback" -a "(negma __complete rollback nix 2>/dev/null)"
+"#,
+        );
+    }
+    script
+}
@@ -1,10 +1,21 @@
+use crate::{
+    generations,
+    logger::Level,
+    theme::{self, Theme},
+    tools,
+};
 use colored::*;
 use std::{
+    collections::HashMap,
     env,
     fs::{self, File},
-    io::{BufRead, BufReader, Write},
-    path::PathBuf,
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
     process::exit,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
 };
 
 /// Configuration for Negma: A NixOS management tool for advanced users.
@@ -22,17 +33,89 @@ use std::{
 #[derive(Debug)]
 pub struct CFG {
     pub editor: String,
+    /// `EDITOR_ARGS`: arguments passed before the file; replaces the built-in wait flag.
+    pub editor_args: Option<Vec<String>>,
+    /// Pager for `--pager`: `PAGER`, else `$PAGER`, else `less`.
+    pub pager: String,
+    /// `--pager`: page long read-only output when stdout is a terminal.
+    pub use_pager: bool,
     pub git: String,
     pub issu : bool,
-    pub keep: i32,
+    pub keep: u32,
     pub alias: Vec<(String, String)>,
     pub system_flake: Option<String>,
     pub rebuild_flags: Option<String>,
+    /// Per-command flags (`NIX_CHECK_FLAGS`, `HOME_MAKE_FLAGS`, ...), keyed by config key.
+    pub command_flags: HashMap<&'static str, Vec<String>>,
     pub channel: Option<String>,
     pub auto_gc: bool,
     pub gc_age_days: Option<u32>,
+    /// Keep build outputs / derivations of live derivations alive during GC.
+    pub gc_keep_outputs: bool,
+    pub gc_keep_derivations: bool,
     pub formatter: Option<String>,
     pub auto_fmt: bool,
+    pub substituters: Vec<String>,
+    pub trusted_keys: Vec<String>,
+    pub dry_run_default: bool,
+    pub builders: Option<String>,
+    /// Let remote builders fetch dependencies from binary caches themselves.
+    pub builders_use_substitutes: bool,
+    /// `BUILD_HOST` / `--build-host`: host that builds the system (`nixos-rebuild --build-host`).
+    pub build_host: Option<String>,
+    /// `TARGET_HOST` / `--target-host`: host that activates the system (`nixos-rebuild --target-host`).
+    pub target_host: Option<String>,
+    pub retry: u32,
+    /// `COMMAND_TIMEOUT` / `--timeout`: seconds before a spawned command is killed (0 = unlimited).
+    pub command_timeout: u64,
+    /// `--cores` for rebuilds: cores per build job (0 = all).
+    pub build_cores: Option<u32>,
+    /// `--max-jobs` for rebuilds: parallel build jobs.
+    pub build_max_jobs: Option<u32>,
+    /// `FALLBACK` / `--fallback`: build locally when a substituter can't provide a path.
+    pub fallback: bool,
+    pub accept_flake_config: bool,
+    /// Never update or write `flake.lock` during flake rebuilds.
+    pub no_update_lockfile: bool,
+    /// Build as the invoking user and only elevate activation (`nixos-rebuild --use-remote-sudo`).
+    pub use_remote_sudo: bool,
+    pub make_mode: String,
+    pub allow_unfree: bool,
+    pub allow_broken: bool,
+    /// `PERMITTED_INSECURE` / `--permit-insecure`: insecure packages the user accepts; any
+    /// entry sets `NIXPKGS_ALLOW_INSECURE=1`, which nixpkgs applies to all insecure packages.
+    pub permitted_insecure: Vec<String>,
+    /// Privilege escalation command, e.g. `sudo`, `doas`, or `run0`.
+    pub sudo_cmd: String,
+    /// `NIXOS_DIR`: where the NixOS configuration lives, if not `/etc/nixos`.
+    pub nixos_dir: Option<String>,
+    /// `HOME_MANAGER_DIR`: where the home-manager configuration lives, if not `~/.config/home-manager`.
+    pub home_manager_dir: Option<String>,
+    /// `HOME_MANAGER_MODE`: `standalone`, `module` (applied by the system rebuild), or `auto`.
+    pub home_manager_mode: String,
+    pub require_clean_git: bool,
+    pub pre_check: bool,
+    /// `CONFIRM_MAKE`: ask before a system rebuild starts, after printing what it will run.
+    pub confirm_make: bool,
+    pub theme: Theme,
+    /// `LOG_LEVEL`: what goes into `~/.config/negma/negma.log`.
+    pub log_level: Level,
+    /// Extra environment for spawned nix/home-manager commands, from `env` lines and `--env`.
+    pub env: Vec<(String, String)>,
+    /// Resolved at startup from `--dry-run`, `--apply` and `DRY_RUN_DEFAULT`.
+    pub dry_run: bool,
+    /// Set by `--keep-going`: keep building other derivations after a failure.
+    pub keep_going: bool,
+    /// Set by `--impure`: allow impure flake evaluation for this invocation.
+    pub impure: bool,
+    /// Set by `--dirty`: building an uncommitted flake is intended, so nix's warning is silenced.
+    pub allow_dirty: bool,
+    /// Set by `--verbose-nix[=level]`: how many `-v` to pass to `nixos-rebuild` (0 = none).
+    pub nix_verbosity: u8,
+    /// Set by `--target-profile`: build into `system-profiles/<name>` instead of the system profile.
+    pub target_profile: Option<String>,
+    /// Set by `--option <key> <value>` (repeatable): nix options forwarded verbatim.
+    pub extra_options: Vec<(String, String)>,
 }
 
 impl CFG {
@@ -41,191 +124,156 @@ impl CFG {
         let home_dir = env::var("HOME").unwrap_or_else(|e| {
             eprintln!(
                 "{} {} {}",
-                "[negma:config]".red().bold(),
-                "error: unable to retrieve home directory.".red(),
-                format!(
+                theme::style_error("[negma:config]"),
+                "error: unable to retrieve home directory.".color(theme::palette().error),
+                theme::style_dim(format!(
                     "\n  → hint: ensure the HOME environment variable is set.\n  → context: std::env::var(\"HOME\")\n  → underlying error: {}",
                     e
-                )
-                .bright_black()
+                ))
             );
             exit(1);
         });
 
-        let config_path = PathBuf::from(format!("{}/.config/negma/config.cfg", home_dir));
-
-        if !config_path.exists() {
-            println!(
-                "{} {} {}",
-                "[negma:config]".yellow().bold(),
-                "configuration file not found.".yellow(),
-                "Creating default configuration...".bright_black()
-            );
+        let config_path = config_path(&home_dir);
 
-            if let Some(parent) = config_path.parent() {
-                if let Err(e) = fs::create_dir_all(parent) {
-                    eprintln!(
-                        "{} {} {}",
-                        "[negma:config]".red().bold(),
-                        "error: failed to create configuration directory.".red(),
-                        format!(
-                            "\n  → context: {}\n  → underlying error: {}",
-                            parent.display(),
-                            e
-                        )
-                        .bright_black()
-                    );
-                    exit(1);
-                }
+        let blank = fs::read_to_string(&config_path).is_ok_and(|c| c.trim().is_empty());
+        if !config_path.exists() || blank {
+            if blank {
+                println!(
+                    "{} {} {}",
+                    theme::style_warning("[negma:config]"),
+                    "configuration file is empty.".color(theme::palette().warning),
+                    theme::style_dim("Writing the documented default...")
+                );
+            } else {
+                println!(
+                    "{} {} {}",
+                    theme::style_warning("[negma:config]"),
+                    "configuration file not found.".color(theme::palette().warning),
+                    theme::style_dim("Creating default configuration...")
+                );
             }
 
-            let mut file = File::create(&config_path).unwrap_or_else(|e| {
+            if let Some(parent) = config_path.parent()
+                && let Err(e) = fs::create_dir_all(parent)
+            {
                 eprintln!(
                     "{} {} {}",
-                    "[negma:config]".red().bold(),
-                    "error: failed to create configuration file.".red(),
-                    format!(
+                    theme::style_error("[negma:config]"),
+                    "error: failed to create configuration directory.".color(theme::palette().error),
+                    theme::style_dim(format!(
                         "\n  → context: {}\n  → underlying error: {}",
-                        config_path.display(),
+                        parent.display(),
                         e
-                    )
-                    .bright_black()
+                    ))
                 );
                 exit(1);
-            });
-
-            let default_content = r#"# Negma Configuration File
-
-###############################################################
-#                                                             #
-#                 Negma - NixOS Manager Config                #
-#                                                             #
-# This file controls how Negma manages your NixOS system.     #
-# It is automatically created and updated by Negma.           #
-#                                                             #
-###############################################################
-
-# === Basic Configuration ===
-
-# EDITOR specifies your preferred editor for editing config files.
-# Example: EDITOR = helix
-EDITOR = nano
-
-# GIT specifies your system configuration git repo (optional).
-# Example: GIT = https://github.com/username/nixos-config
-GIT = 
-
-# KEEP specifies how many system generations to keep when cleanup is called.
-# 0 = keep current, 1 = keep current + last one, etc.
-# Example: KEEP = 5
-KEEP = 5
-
-# === Advanced Configuration ===
-
-# SYSTEM_FLAKE specifies a flake URI or local path for nixos-rebuild.
-# Example: SYSTEM_FLAKE = github:username/nixos-config
-SYSTEM_FLAKE = 
-
-# REBUILD_FLAGS specifies additional flags for nixos-rebuild.
-# Example: REBUILD_FLAGS = --impure --show-trace
-REBUILD_FLAGS =
-
-# CHANNEL specifies your preferred Nix channel.
-# Example: CHANNEL = nixos-unstable
-CHANNEL = 
-
-# AUTO_GC specifies if automatic garbage collection should run during rebuild.
-# Valid values: true / false
-AUTO_GC = true
-
-# GC_AGE_DAYS specifies the maximum age (in days) before GC removal.
-# Example: GC_AGE_DAYS = 15
-GC_AGE_DAYS = 15
-
-# FORMATTER specifies which Nix formatter to use for autofmt operations.
-# Supported: nixfmt-rfc-style, alejandra, nixpkgs-fmt, etc.
-# Example: FORMATTER = alejandra
-FORMATTER = alejandra
-
-# AUTO_FMT specifies whether Negma should auto-format system config before rebuild.
-# Valid values: true / false
-AUTO_FMT = true
-
-# === Aliases ===
-# Aliases allow you to create shortcuts for common commands.
-# Example:
-# alias mk = build
-# alias bkup = backup
-
-"#;
+            }
 
-            if let Err(e) = file.write_all(default_content.as_bytes()) {
+            if let Err(e) = write_default(&config_path) {
                 eprintln!(
                     "{} {} {}",
-                    "[negma:config]".red().bold(),
-                    "error: failed to write default configuration.".red(),
-                    format!(
+                    theme::style_error("[negma:config]"),
+                    "error: failed to write default configuration.".color(theme::palette().error),
+                    theme::style_dim(format!(
                         "\n  → context: {}\n  → underlying error: {}",
                         config_path.display(),
                         e
-                    )
-                    .bright_black()
+                    ))
                 );
                 exit(1);
             }
 
             println!(
                 "{} {} {}",
-                "[negma:config]".green().bold(),
-                "created default configuration at".green(),
-                config_path.display().to_string().bright_black()
+                theme::style_header("[negma:config]"),
+                "created default configuration at".color(theme::palette().ok),
+                theme::style_dim(config_path.display().to_string())
             );
         }
 
         let file = File::open(&config_path).unwrap_or_else(|e| {
             eprintln!(
                 "{} {} {}",
-                "[negma:config]".red().bold(),
-                "error: unable to open configuration file.".red(),
-                format!(
+                theme::style_error("[negma:config]"),
+                "error: unable to open configuration file.".color(theme::palette().error),
+                theme::style_dim(format!(
                     "\n  → context: {}\n  → underlying error: {}",
                     config_path.display(),
                     e
-                )
-                .bright_black()
+                ))
             );
             exit(1);
         });
 
-        let reader = BufReader::new(file);
+        Self::parse_from(BufReader::new(file), &config_path.display().to_string())
+    }
+
+    /// Parses configuration lines from any reader; `source` names it in warnings.
+    ///
+    /// Used for the config file as well as `--config -` (stdin).
+    pub fn parse_from(reader: impl BufRead, source: &str) -> CFG {
 
         let mut editor = String::from("nano");
+        let mut editor_args = None;
+        let mut pager = env::var("PAGER").ok().filter(|p| !p.is_empty()).unwrap_or_else(|| "less".to_string());
         let mut git = String::new();
         let mut clrupam = 5;
         let mut alias = Vec::new();
         let mut system_flake = None;
         let mut rebuild_flags = None;
+        let mut command_flags = HashMap::new();
         let mut channel = None;
         let mut auto_gc = false;
         let mut gc_age_days = None;
+        let mut gc_keep_outputs = false;
+        let mut gc_keep_derivations = false;
         let mut formatter = None;
         let mut auto_fmt = false;
+        let mut substituters = Vec::new();
+        let mut trusted_keys = Vec::new();
+        let mut dry_run_default = false;
+        let mut builders = None;
+        let mut builders_use_substitutes = false;
+        let mut build_host = None;
+        let mut target_host = None;
+        let mut retry = 0;
+        let mut command_timeout = 0;
+        let mut build_cores = None;
+        let mut build_max_jobs = None;
+        let mut fallback = false;
+        let mut accept_flake_config = false;
+        let mut no_update_lockfile = false;
+        let mut use_remote_sudo = false;
+        let mut make_mode = String::from("switch");
+        let mut env = Vec::new();
+        let mut allow_unfree = false;
+        let mut allow_broken = false;
+        let mut permitted_insecure = Vec::new();
+        let mut sudo_cmd = String::from("sudo");
+        let mut nixos_dir = None;
+        let mut home_manager_dir = None;
+        let mut home_manager_mode = String::from("auto");
+        let mut require_clean_git = false;
+        let mut pre_check = false;
+        let mut confirm_make = false;
+        let mut theme = Theme::Dark;
+        let mut log_level = Level::Off;
 
         for (index, line) in reader.lines().enumerate() {
             let line_number = index + 1;
             let line = match line {
                 Ok(l) => l.trim().to_string(),
                 Err(e) => {
-                    eprintln!(
-                        "{} {} {}",
-                        "[negma:config]".yellow().bold(),
-                        format!("warning: failed to read line {}.", line_number).yellow(),
+                    report(
+                        Severity::Error,
+                        line_number,
+                        format!("failed to read line {}.", line_number),
                         format!(
                             "\n  → context: {}\n  → underlying error: {}",
-                            config_path.display(),
+                            source,
                             e
-                        )
-                        .bright_black()
+                        ),
                     );
                     continue;
                 }
@@ -241,65 +289,96 @@ AUTO_FMT = true
                 if parts.len() == 2 {
                     Some(parts[1].trim().to_string())
                 } else {
-                    eprintln!(
-                        "{} {} {}",
-                        "[negma:config]".yellow().bold(),
+                    report(
+                        Severity::Error,
+                        line_number,
                         format!(
-                            "warning: invalid {} syntax at line {}.",
+                            "invalid {} syntax at line {}.",
                             prefix.trim(),
                             line_number
-                        )
-                        .yellow(),
+                        ),
                         format!(
                             "\n  → hint: use '{} = value'\n  → line content: '{}'",
                             prefix.trim(),
                             line
-                        )
-                        .bright_black()
+                        ),
                     );
                     None
                 }
             };
 
-            if line.starts_with("alias") {
-                let parts: Vec<&str> = line["alias".len()..].trim().splitn(2, '=').collect();
+            if let Some(rest) = line.strip_prefix("alias") {
+                let parts: Vec<&str> = rest.trim().splitn(2, '=').collect();
                 if parts.len() == 2 {
                     alias.push((parts[0].trim().to_string(), parts[1].trim().to_string()));
                 } else {
-                    eprintln!(
-                        "{} {} {}",
-                        "[negma:config]".yellow().bold(),
-                        format!("warning: invalid alias syntax at line {}.", line_number).yellow(),
+                    report(
+                        Severity::Error,
+                        line_number,
+                        format!("invalid alias syntax at line {}.", line_number),
                         format!(
                             "\n  → hint: use 'alias name = command'\n  → line content: '{}'",
                             line
-                        )
-                        .bright_black()
+                        ),
                     );
                 }
+            } else if let Some(rest) = line.strip_prefix("env ") {
+                match rest.split_once('=').map(|(k, v)| (k.trim(), v.trim())) {
+                    Some((key, val)) if is_valid_env_key(key) => env.push((key.to_string(), val.to_string())),
+                    _ => report(
+                        Severity::Error,
+                        line_number,
+                        format!("invalid env syntax at line {}.", line_number),
+                        format!(
+                            "\n  → hint: use 'env NAME = value'\n  → line content: '{}'",
+                            line
+                        ),
+                    ),
+                }
+            } else if let Some(val) = parse_kv(&line, "EDITOR_ARGS") {
+                if !val.is_empty() {
+                    editor_args = Some(val.split_whitespace().map(String::from).collect());
+                }
             } else if let Some(val) = parse_kv(&line, "EDITOR") {
                 editor = val;
+            } else if let Some(val) = parse_kv(&line, "PAGER") {
+                if !val.is_empty() {
+                    pager = val;
+                }
             } else if let Some(val) = parse_kv(&line, "GIT") {
                 git = val;
             } else if let Some(val) = parse_kv(&line, "KEEP") {
-                match val.parse::<i32>() {
+                // Parsed as u32, so a negative KEEP is rejected here and the default kept.
+                match val.parse::<u32>() {
                     Ok(n) => clrupam = n,
-                    Err(_) => eprintln!(
-                        "{} {} {}",
-                        "[negma:config]".yellow().bold(),
-                        format!("warning: invalid KEEP value at line {}.", line_number).yellow(),
-                        format!("\n  → hint: use an integer.\n  → line content: '{}'", line)
-                            .bright_black()
+                    Err(_) => report(
+                        Severity::Warning,
+                        line_number,
+                        format!("invalid KEEP value at line {}, using {}.", line_number, clrupam),
+                        format!(
+                            "\n  → hint: use a non-negative integer (0 keeps only the current generation).\n  → line content: '{}'",
+                            line
+                        ),
                     ),
                 }
             } else if let Some(val) = parse_kv(&line, "SYSTEM_FLAKE") {
                 if !val.is_empty() {
-                    system_flake = Some(val);
+                    system_flake = Some(expand_tilde(&val));
                 }
             } else if let Some(val) = parse_kv(&line, "REBUILD_FLAGS") {
                 if !val.is_empty() {
                     rebuild_flags = Some(val);
                 }
+            } else if let Some((key, val)) =
+                COMMAND_FLAG_KEYS.iter().find_map(|&key| parse_kv(&line, key).map(|val| (key, val)))
+            {
+                command_flags.insert(key, val.split_whitespace().map(String::from).collect());
+            } else if let Some(val) = parse_kv(&line, "REQUIRE_CLEAN_GIT") {
+                require_clean_git = matches!(val.to_lowercase().as_str(), "true" | "yes" | "1");
+            } else if let Some(val) = parse_kv(&line, "PRE_CHECK") {
+                pre_check = matches!(val.to_lowercase().as_str(), "true" | "yes" | "1");
+            } else if let Some(val) = parse_kv(&line, "CONFIRM_MAKE") {
+                confirm_make = matches!(val.to_lowercase().as_str(), "true" | "yes" | "1");
             } else if let Some(val) = parse_kv(&line, "CHANNEL") {
                 if !val.is_empty() {
                     channel = Some(val);
@@ -309,47 +388,1068 @@ AUTO_FMT = true
             } else if let Some(val) = parse_kv(&line, "GC_AGE_DAYS") {
                 match val.parse::<u32>() {
                     Ok(n) => gc_age_days = Some(n),
-                    Err(_) => eprintln!(
-                        "{} {} {}",
-                        "[negma:config]".yellow().bold(),
+                    Err(_) => report(
+                        Severity::Warning,
+                        line_number,
                         format!(
-                            "warning: invalid GC_AGE_DAYS value at line {}.",
+                            "invalid GC_AGE_DAYS value at line {}.",
                             line_number
-                        )
-                        .yellow(),
-                        format!("\n  → hint: use an integer.\n  → line content: '{}'", line)
-                            .bright_black()
+                        ),
+                        format!("\n  → hint: use an integer.\n  → line content: '{}'", line),
                     ),
                 }
+            } else if let Some(val) = parse_kv(&line, "GC_KEEP_OUTPUTS") {
+                gc_keep_outputs = matches!(val.to_lowercase().as_str(), "true" | "yes" | "1");
+            } else if let Some(val) = parse_kv(&line, "GC_KEEP_DERIVATIONS") {
+                gc_keep_derivations = matches!(val.to_lowercase().as_str(), "true" | "yes" | "1");
             } else if let Some(val) = parse_kv(&line, "FORMATTER") {
                 if !val.is_empty() {
                     formatter = Some(val);
                 }
             } else if let Some(val) = parse_kv(&line, "AUTO_FMT") {
                 auto_fmt = matches!(val.to_lowercase().as_str(), "true" | "yes" | "1");
+            } else if let Some(val) = parse_kv(&line, "DRY_RUN_DEFAULT") {
+                dry_run_default = matches!(val.to_lowercase().as_str(), "true" | "yes" | "1");
+            } else if let Some(val) = parse_kv(&line, "BUILDERS_USE_SUBSTITUTES") {
+                // Checked before BUILDERS, which would otherwise match this key by prefix.
+                builders_use_substitutes = matches!(val.to_lowercase().as_str(), "true" | "yes" | "1");
+            } else if let Some(val) = parse_kv(&line, "BUILDERS") {
+                if is_valid_builders(&val) {
+                    builders = Some(val);
+                } else if !val.is_empty() {
+                    report(
+                        Severity::Warning,
+                        line_number,
+                        format!("invalid BUILDERS value at line {}.", line_number),
+                        format!(
+                            "\n  → hint: use 'ssh://host [system] [key] [jobs]' entries separated by ';', or '@/path/to/machines'\n  → line content: '{}'",
+                            line
+                        ),
+                    );
+                }
+            } else if let Some((key, val)) =
+                ["BUILD_HOST", "TARGET_HOST"].iter().find_map(|&key| parse_kv(&line, key).map(|val| (key, val)))
+            {
+                if is_valid_host(&val) && key == "BUILD_HOST" {
+                    build_host = Some(val);
+                } else if is_valid_host(&val) {
+                    target_host = Some(val);
+                } else if !val.is_empty() {
+                    report(
+                        Severity::Warning,
+                        line_number,
+                        format!("invalid {} value at line {}.", key, line_number),
+                        format!(
+                            "\n  → hint: use an ssh destination such as 'root@webserver'.\n  → line content: '{}'",
+                            line
+                        ),
+                    );
+                }
+            } else if let Some(val) = parse_kv(&line, "THEME") {
+                match Theme::parse(&val) {
+                    Some(t) => theme = t,
+                    None if val.is_empty() => {}
+                    None => report(
+                        Severity::Warning,
+                        line_number,
+                        format!("invalid THEME value at line {}.", line_number),
+                        format!("\n  → hint: use dark, light, or mono.\n  → line content: '{}'", line),
+                    ),
+                }
+            } else if let Some(val) = parse_kv(&line, "LOG_LEVEL") {
+                match Level::parse(&val) {
+                    Some(level) => log_level = level,
+                    None if val.is_empty() => {}
+                    None => report(
+                        Severity::Warning,
+                        line_number,
+                        format!("invalid LOG_LEVEL value at line {}.", line_number),
+                        format!(
+                            "\n  → hint: use off, error, warn, info, or debug.\n  → line content: '{}'",
+                            line
+                        ),
+                    ),
+                }
+            } else if let Some(val) = parse_kv(&line, "MAKE_MODE") {
+                match val.to_lowercase().as_str() {
+                    "switch" | "boot" | "test" => make_mode = val.to_lowercase(),
+                    "" => {}
+                    _ => report(
+                        Severity::Warning,
+                        line_number,
+                        format!("invalid MAKE_MODE value at line {}.", line_number),
+                        format!("\n  → hint: use switch, boot, or test.\n  → line content: '{}'", line),
+                    ),
+                }
+            } else if let Some(val) = parse_kv(&line, "USE_REMOTE_SUDO") {
+                use_remote_sudo = matches!(val.to_lowercase().as_str(), "true" | "yes" | "1");
+            } else if let Some(val) = parse_kv(&line, "FALLBACK") {
+                fallback = matches!(val.to_lowercase().as_str(), "true" | "yes" | "1");
+            } else if let Some(val) = parse_kv(&line, "ACCEPT_FLAKE_CONFIG") {
+                accept_flake_config = matches!(val.to_lowercase().as_str(), "true" | "yes" | "1");
+            } else if let Some(val) = parse_kv(&line, "NO_UPDATE_LOCKFILE") {
+                no_update_lockfile = matches!(val.to_lowercase().as_str(), "true" | "yes" | "1");
+            } else if let Some(val) = parse_kv(&line, "ALLOW_UNFREE") {
+                allow_unfree = matches!(val.to_lowercase().as_str(), "true" | "yes" | "1");
+            } else if let Some(val) = parse_kv(&line, "ALLOW_BROKEN") {
+                allow_broken = matches!(val.to_lowercase().as_str(), "true" | "yes" | "1");
+            } else if let Some(val) = parse_kv(&line, "PERMITTED_INSECURE") {
+                permitted_insecure = split_list(&val);
+            } else if let Some(val) = parse_kv(&line, "NIXOS_DIR") {
+                if !val.is_empty() {
+                    nixos_dir = Some(expand_tilde(&val).trim_end_matches('/').to_string());
+                }
+            } else if let Some(val) = parse_kv(&line, "HOME_MANAGER_DIR") {
+                if !val.is_empty() {
+                    home_manager_dir = Some(expand_tilde(&val).trim_end_matches('/').to_string());
+                }
+            } else if let Some(val) = parse_kv(&line, "HOME_MANAGER_MODE") {
+                match val.to_lowercase().as_str() {
+                    "auto" | "standalone" | "module" => home_manager_mode = val.to_lowercase(),
+                    "" => {}
+                    _ => report(
+                        Severity::Warning,
+                        line_number,
+                        format!("invalid HOME_MANAGER_MODE value at line {}.", line_number),
+                        format!(
+                            "\n  → hint: use auto, standalone, or module.\n  → line content: '{}'",
+                            line
+                        ),
+                    ),
+                }
+            } else if let Some(val) = parse_kv(&line, "SUDO_CMD") {
+                if !val.is_empty() {
+                    sudo_cmd = val;
+                }
+            } else if let Some(val) = parse_kv(&line, "RETRY") {
+                match val.parse::<u32>() {
+                    Ok(n) => retry = n,
+                    Err(_) => report(
+                        Severity::Warning,
+                        line_number,
+                        format!("invalid RETRY value at line {}.", line_number),
+                        format!("\n  → hint: use a non-negative integer.\n  → line content: '{}'", line),
+                    ),
+                }
+            } else if let Some(val) = parse_kv(&line, "COMMAND_TIMEOUT") {
+                match val.parse::<u64>() {
+                    Ok(n) => command_timeout = n,
+                    Err(_) => report(
+                        Severity::Warning,
+                        line_number,
+                        format!("invalid COMMAND_TIMEOUT value at line {}.", line_number),
+                        format!(
+                            "\n  → hint: use a number of seconds, or 0 for no limit.\n  → line content: '{}'",
+                            line
+                        ),
+                    ),
+                }
+            } else if let Some(val) = parse_kv(&line, "BUILD_CORES") {
+                match val.parse::<u32>() {
+                    Ok(n) => build_cores = Some(n),
+                    Err(_) if val.is_empty() => {}
+                    Err(_) => report(
+                        Severity::Warning,
+                        line_number,
+                        format!("invalid BUILD_CORES value at line {}.", line_number),
+                        format!("\n  → hint: use a non-negative integer.\n  → line content: '{}'", line),
+                    ),
+                }
+            } else if let Some(val) = parse_kv(&line, "BUILD_MAX_JOBS") {
+                match val.parse::<u32>() {
+                    Ok(n) => build_max_jobs = Some(n),
+                    Err(_) if val.is_empty() => {}
+                    Err(_) => report(
+                        Severity::Warning,
+                        line_number,
+                        format!("invalid BUILD_MAX_JOBS value at line {}.", line_number),
+                        format!("\n  → hint: use a non-negative integer.\n  → line content: '{}'", line),
+                    ),
+                }
+            } else if let Some(val) = parse_kv(&line, "SUBSTITUTERS") {
+                substituters = split_list(&val);
+            } else if let Some(val) = parse_kv(&line, "TRUSTED_KEYS") {
+                trusted_keys = split_list(&val);
+                for key in trusted_keys.iter().filter(|k| !is_valid_public_key(k)) {
+                    report(
+                        Severity::Warning,
+                        line_number,
+                        format!("suspicious TRUSTED_KEYS entry at line {}.", line_number),
+                        format!("\n  → hint: keys look like 'name:base64'\n  → entry: '{}'", key),
+                    );
+                }
             } else {
-                eprintln!(
-                    "{} {} {}",
-                    "[negma:config]".yellow().bold(),
-                    format!("warning: unrecognized line at {}.", line_number).yellow(),
-                    format!("\n  → line content: '{}'", line).bright_black()
+                report(
+                    Severity::Error,
+                    line_number,
+                    format!("unrecognized line at {}.", line_number),
+                    format!("\n  → line content: '{}'", line),
                 );
             }
         }
 
         CFG {
             editor,
+            editor_args,
+            pager,
             git,
             keep: clrupam,
             alias,
             system_flake,
             rebuild_flags,
+            command_flags,
             channel,
             auto_gc,
             gc_age_days,
+            gc_keep_outputs,
+            gc_keep_derivations,
             formatter,
             auto_fmt,
+            substituters,
+            trusted_keys,
+            dry_run_default,
+            builders,
+            builders_use_substitutes,
+            build_host,
+            target_host,
+            retry,
+            command_timeout,
+            build_cores,
+            build_max_jobs,
+            fallback,
+            accept_flake_config,
+            no_update_lockfile,
+            use_remote_sudo,
+            make_mode,
+            env,
+            allow_unfree,
+            allow_broken,
+            permitted_insecure,
+            sudo_cmd,
+            nixos_dir,
+            home_manager_dir,
+            home_manager_mode,
+            require_clean_git,
+            pre_check,
+            confirm_make,
+            theme,
+            log_level,
             issu: false,
+            use_pager: false,
+            dry_run: false,
+            keep_going: false,
+            impure: false,
+            nix_verbosity: 0,
+            target_profile: None,
+            extra_options: Vec::new(),
+            allow_dirty: false,
+        }
+    }
+
+    /// The resolved value of a config key as `config get` prints it, after command-line
+    /// overrides; unset optional values are empty. `None` for an unknown key.
+    pub fn get(&self, key: &str) -> Option<String> {
+        let opt = |v: &Option<String>| v.clone().unwrap_or_default();
+        let num = |v: Option<u32>| v.map(|n| n.to_string()).unwrap_or_default();
+        Some(match key.to_uppercase().as_str() {
+            key if COMMAND_FLAG_KEYS.contains(&key) => {
+                self.command_flags.get(key).map(|f| f.join(" ")).unwrap_or_default()
+            }
+            "EDITOR" => self.editor.clone(),
+            "EDITOR_ARGS" => self.editor_args.as_deref().unwrap_or_default().join(" "),
+            "PAGER" => self.pager.clone(),
+            "GIT" => self.git.clone(),
+            "KEEP" => self.keep.to_string(),
+            "SYSTEM_FLAKE" => opt(&self.system_flake),
+            "REBUILD_FLAGS" => opt(&self.rebuild_flags),
+            "REQUIRE_CLEAN_GIT" => self.require_clean_git.to_string(),
+            "PRE_CHECK" => self.pre_check.to_string(),
+            "CONFIRM_MAKE" => self.confirm_make.to_string(),
+            "CHANNEL" => opt(&self.channel),
+            "AUTO_GC" => self.auto_gc.to_string(),
+            "GC_AGE_DAYS" => num(self.gc_age_days),
+            "GC_KEEP_OUTPUTS" => self.gc_keep_outputs.to_string(),
+            "GC_KEEP_DERIVATIONS" => self.gc_keep_derivations.to_string(),
+            "FORMATTER" => opt(&self.formatter),
+            "AUTO_FMT" => self.auto_fmt.to_string(),
+            "DRY_RUN_DEFAULT" => self.dry_run_default.to_string(),
+            "BUILDERS" => opt(&self.builders),
+            "BUILDERS_USE_SUBSTITUTES" => self.builders_use_substitutes.to_string(),
+            "BUILD_HOST" => opt(&self.build_host),
+            "TARGET_HOST" => opt(&self.target_host),
+            "THEME" => self.theme.name().to_string(),
+            "LOG_LEVEL" => self.log_level.name().to_string(),
+            "MAKE_MODE" => self.make_mode.clone(),
+            "USE_REMOTE_SUDO" => self.use_remote_sudo.to_string(),
+            "FALLBACK" => self.fallback.to_string(),
+            "ACCEPT_FLAKE_CONFIG" => self.accept_flake_config.to_string(),
+            "NO_UPDATE_LOCKFILE" => self.no_update_lockfile.to_string(),
+            "ALLOW_UNFREE" => self.allow_unfree.to_string(),
+            "ALLOW_BROKEN" => self.allow_broken.to_string(),
+            "PERMITTED_INSECURE" => self.permitted_insecure.join(" "),
+            "NIXOS_DIR" => opt(&self.nixos_dir),
+            "HOME_MANAGER_DIR" => opt(&self.home_manager_dir),
+            "HOME_MANAGER_MODE" => self.home_manager_mode.clone(),
+            "SUDO_CMD" => self.sudo_cmd.clone(),
+            "RETRY" => self.retry.to_string(),
+            "COMMAND_TIMEOUT" => self.command_timeout.to_string(),
+            "BUILD_CORES" => num(self.build_cores),
+            "BUILD_MAX_JOBS" => num(self.build_max_jobs),
+            "SUBSTITUTERS" => self.substituters.join(" "),
+            "TRUSTED_KEYS" => self.trusted_keys.join(" "),
+            _ => return None,
+        })
+    }
+
+    /// Arguments for the editor before the file: `EDITOR_ARGS`, or the wait flag of a known
+    /// GUI editor, which would otherwise return before the file is saved.
+    pub fn editor_args(&self) -> Vec<String> {
+        if let Some(args) = &self.editor_args {
+            return args.clone();
+        }
+        let name = Path::new(&self.editor).file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        GUI_EDITORS
+            .iter()
+            .find(|(editor, _)| *editor == name)
+            .map(|(_, wait)| vec![wait.to_string()])
+            .unwrap_or_default()
+    }
+
+    /// Extra `--option` arguments for rebuild commands derived from the config and `--option`.
+    pub fn nix_options(&self) -> Vec<String> {
+        let mut opts = Vec::new();
+        if !self.substituters.is_empty() {
+            opts.push("--option".to_string());
+            opts.push("substituters".to_string());
+            opts.push(self.substituters.join(" "));
+        }
+        if !self.trusted_keys.is_empty() {
+            opts.push("--option".to_string());
+            opts.push("trusted-public-keys".to_string());
+            opts.push(self.trusted_keys.join(" "));
+        }
+        for (key, value) in &self.extra_options {
+            opts.extend(["--option".to_string(), key.clone(), value.clone()]);
+        }
+        opts
+    }
+
+    /// `--option` arguments for garbage collection from `GC_KEEP_OUTPUTS` / `GC_KEEP_DERIVATIONS`.
+    pub fn gc_options(&self) -> Vec<String> {
+        let mut opts = Vec::new();
+        let keep = [(self.gc_keep_outputs, "keep-outputs"), (self.gc_keep_derivations, "keep-derivations")];
+        for (enabled, name) in keep {
+            if enabled {
+                opts.extend(["--option".to_string(), name.to_string(), "true".to_string()]);
+            }
+        }
+        opts
+    }
+
+    /// Appends the `<SCOPE>_<COMMAND>_FLAGS` of `command` (e.g. `"nix check"`) to `args`.
+    /// Flags `args` already carries are skipped, so command-line flags win; `--option`
+    /// is matched by its name.
+    pub fn with_command_flags(&self, command: &str, mut args: Vec<String>) -> Vec<String> {
+        let key = format!("{}_FLAGS", command.replace(' ', "_").to_uppercase());
+        let Some(flags) = self.command_flags.get(key.as_str()) else {
+            return args;
+        };
+        let mut start = 0;
+        while start < flags.len() {
+            // A flag and the values following it up to the next flag.
+            let end = flags[start + 1..]
+                .iter()
+                .position(|f| f.starts_with('-'))
+                .map_or(flags.len(), |p| start + 1 + p);
+            let present = match &flags[start..end] {
+                [opt, name, ..] if opt == "--option" => {
+                    args.windows(2).any(|w| w[0] == "--option" && w[1] == *name)
+                }
+                [flag, ..] => args.contains(flag),
+                [] => false,
+            };
+            if !present {
+                args.extend(flags[start..end].iter().cloned());
+            }
+            start = end;
+        }
+        args
+    }
+
+    /// Full set of extra arguments for `nixos-rebuild`: the flake (if any), `REBUILD_FLAGS`,
+    /// config options, remote builders, and per-invocation shortcuts.
+    pub fn rebuild_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(flake) = &self.system_flake {
+            args.push("--flake".to_string());
+            args.push(flake.clone());
+        }
+        args.extend(
+            self.rebuild_flags
+                .as_deref()
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(String::from),
+        );
+        self.push_shortcuts(&mut args);
+        if self.nix_verbosity > 0 && !args.iter().any(|a| a == "--verbose" || is_verbosity_flag(a)) {
+            args.push(format!("-{}", "v".repeat(self.nix_verbosity as usize)));
+        }
+        if self.needs_impure_env() && !args.iter().any(|a| a == "--impure") {
+            args.push("--impure".to_string());
+        }
+        if self.accept_flake_config
+            && self.system_flake.is_some()
+            && !args.iter().any(|a| a == "--accept-flake-config")
+        {
+            args.push("--accept-flake-config".to_string());
+        }
+        if self.no_update_lockfile && self.system_flake.is_some() {
+            for flag in ["--no-update-lock-file", "--no-write-lock-file"] {
+                if !args.iter().any(|a| a == flag) {
+                    args.push(flag.to_string());
+                }
+            }
+        }
+        if self.allow_dirty && self.system_flake.is_some() {
+            args.extend(dirty_option());
+        }
+        if self.remote_sudo() && !args.iter().any(|a| a == "--use-remote-sudo") {
+            args.push("--use-remote-sudo".to_string());
+        }
+        args.extend(self.nix_options());
+        if self.system_flake.is_some() {
+            for (flag, host) in [("--build-host", &self.build_host), ("--target-host", &self.target_host)] {
+                if let Some(host) = host
+                    && !args.iter().any(|a| a == flag)
+                {
+                    args.push(flag.to_string());
+                    args.push(host.clone());
+                }
+            }
+        }
+        if let Some(name) = &self.target_profile {
+            args.push("--profile-name".to_string());
+            args.push(name.clone());
+        }
+        if let Some(builders) = &self.builders {
+            args.push("--builders".to_string());
+            args.push(builders.clone());
+            if !args.iter().any(|a| a == "--max-jobs" || a == "-j") {
+                args.push("--max-jobs".to_string());
+                args.push("0".to_string());
+            }
+            if self.builders_use_substitutes {
+                args.push("--option".to_string());
+                args.push("builders-use-substitutes".to_string());
+                args.push("true".to_string());
+            }
+        }
+        args
+    }
+
+    /// Extra arguments for `home-manager switch`: per-invocation shortcuts and config options.
+    pub fn home_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        self.push_shortcuts(&mut args);
+        args.extend(self.nix_options());
+        if self.allow_dirty {
+            args.extend(dirty_option());
+        }
+        args
+    }
+
+    /// Whether `USE_REMOTE_SUDO` applies: only flake rebuilds support building unprivileged.
+    pub fn remote_sudo(&self) -> bool {
+        self.use_remote_sudo && self.system_flake.is_some()
+    }
+
+    /// Whether `ALLOW_UNFREE`/`ALLOW_BROKEN`/`PERMITTED_INSECURE` are in effect for a flake, which only reads
+    /// the `NIXPKGS_ALLOW_*` variables when evaluated with `--impure`.
+    pub fn needs_impure_env(&self) -> bool {
+        self.system_flake.is_some()
+            && (self.allow_unfree || self.allow_broken || !self.permitted_insecure.is_empty())
+    }
+
+    /// Directory of the NixOS configuration: `NIXOS_DIR`, or `/etc/nixos`.
+    pub fn nixos_config_dir(&self) -> &str {
+        self.nixos_dir.as_deref().unwrap_or("/etc/nixos")
+    }
+
+    /// Directory of the home-manager configuration: `HOME_MANAGER_DIR`, or `~/.config/home-manager`.
+    pub fn home_config_dir(&self, home_dir: &str) -> String {
+        match &self.home_manager_dir {
+            Some(dir) => dir.clone(),
+            None => format!("{}/.config/home-manager", home_dir),
+        }
+    }
+
+    /// Whether home-manager runs as a NixOS module, so `nixos-rebuild` applies the home
+    /// configuration and there is no `home-manager` command. `auto` decides by the latter.
+    pub fn home_manager_module(&self) -> bool {
+        match self.home_manager_mode.as_str() {
+            "module" => true,
+            "standalone" => false,
+            _ => tools::find_in_path("home-manager").is_none(),
+        }
+    }
+
+    /// The profile rebuilds write to: the named target profile, or the main system profile.
+    pub fn system_profile(&self) -> PathBuf {
+        match &self.target_profile {
+            Some(name) => generations::named_system_profile(name),
+            None => PathBuf::from(generations::SYSTEM_PROFILE),
+        }
+    }
+
+    /// The extra environment as `Command::envs` pairs.
+    pub fn envs(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.env.iter().map(|(k, v)| (k, v))
+    }
+
+    /// Appends the per-invocation shortcut flags not already present in `args`.
+    pub fn push_shortcuts(&self, args: &mut Vec<String>) {
+        if self.keep_going && !args.iter().any(|a| a == "--keep-going" || a == "-k") {
+            args.push("--keep-going".to_string());
+        }
+        if self.impure && !args.iter().any(|a| a == "--impure") {
+            args.push("--impure".to_string());
+        }
+        if self.fallback && !args.iter().any(|a| a == "--fallback") {
+            args.push("--fallback".to_string());
         }
+        if let Some(cores) = self.build_cores
+            && !args.iter().any(|a| a == "--cores")
+        {
+            args.push("--cores".to_string());
+            args.push(cores.to_string());
+        }
+        if let Some(jobs) = self.build_max_jobs
+            && !args.iter().any(|a| a == "--max-jobs" || a == "-j")
+        {
+            args.push("--max-jobs".to_string());
+            args.push(jobs.to_string());
+        }
+    }
+}
+
+/// Whether `arg` is a short verbosity flag such as `-v` or `-vvv`.
+fn is_verbosity_flag(arg: &str) -> bool {
+    arg.strip_prefix('-').is_some_and(|v| !v.is_empty() && v.chars().all(|c| c == 'v'))
+}
+
+/// `--no-warn-dirty` as an option `nixos-rebuild` and `home-manager` pass through to nix.
+fn dirty_option() -> [String; 3] {
+    ["--option".to_string(), "warn-dirty".to_string(), "false".to_string()]
+}
+
+/// Expands a leading `~/` or a bare `~` in a path-like value to `$HOME`; `~user` and a `~`
+/// anywhere else are left alone.
+fn expand_tilde(value: &str) -> String {
+    let Ok(home) = env::var("HOME") else {
+        return value.to_string();
+    };
+    if value == "~" {
+        return home;
     }
+    match value.strip_prefix("~/") {
+        Some(rest) => format!("{}/{}", home.trim_end_matches('/'), rest),
+        None => value.to_string(),
+    }
+}
+
+/// How bad a config problem is, as reported by `config check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// A value negma could not use; the default was kept.
+    Warning,
+    /// A line negma could not parse at all; it was ignored.
+    Error,
+}
+
+impl Severity {
+    pub fn name(self) -> &'static str {
+        match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+/// A problem found while parsing the config.
+#[derive(Debug, Clone)]
+pub struct Issue {
+    pub line: usize,
+    pub severity: Severity,
+    pub message: String,
+    /// Hint and offending line, printed under the message.
+    pub detail: String,
+}
+
+/// Every problem found by the parses so far, in order.
+static ISSUES: Mutex<Vec<Issue>> = Mutex::new(Vec::new());
+/// Set while the theme is not known yet, and by `config check`, which prints the issues itself.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Stops printing config problems as they are found; they are still recorded.
+pub fn quiet_issues() {
+    QUIET.store(true, Ordering::Relaxed);
+}
+
+/// The problems recorded so far.
+pub fn issues() -> Vec<Issue> {
+    ISSUES.lock().map(|issues| issues.clone()).unwrap_or_default()
+}
+
+/// Prints the problems recorded while quieted, then goes back to printing new ones as they
+/// are found.
+pub fn print_issues() {
+    QUIET.store(false, Ordering::Relaxed);
+    for issue in issues() {
+        print_issue(&issue);
+    }
+}
+
+/// Records a config problem at `line` and, unless quieted, prints it with `detail`.
+fn report(severity: Severity, line: usize, message: String, detail: String) {
+    let issue = Issue { line, severity, message, detail };
+    if !QUIET.load(Ordering::Relaxed) {
+        print_issue(&issue);
+    }
+    if let Ok(mut issues) = ISSUES.lock() {
+        issues.push(issue);
+    }
+}
+
+/// Prints one problem on stderr in the current theme's colors.
+fn print_issue(issue: &Issue) {
+    let (tag, color) = match issue.severity {
+        Severity::Warning => (theme::style_warning("[negma:config]"), theme::palette().warning),
+        Severity::Error => (theme::style_error("[negma:config]"), theme::palette().error),
+    };
+    let message = format!("{}: {}", issue.severity.name(), issue.message);
+    eprintln!("{} {} {}", tag, message.color(color), theme::style_dim(&issue.detail));
+}
+
+/// GUI editors that detach from the terminal, and the flag that makes them wait until
+/// the file is closed.
+const GUI_EDITORS: &[(&str, &str)] = &[
+    ("code", "--wait"),
+    ("code-insiders", "--wait"),
+    ("codium", "--wait"),
+    ("cursor", "--wait"),
+    ("zed", "--wait"),
+    ("subl", "--wait"),
+    ("gedit", "--wait"),
+    ("gnome-text-editor", "--wait"),
+    ("kate", "--block"),
+    ("gvim", "--nofork"),
+];
+
+/// Config keys holding extra flags for a single command.
+const COMMAND_FLAG_KEYS: &[&str] = &[
+    "NIX_MAKE_FLAGS",
+    "NIX_BOOT_FLAGS",
+    "NIX_TEST_FLAGS",
+    "NIX_BUILD_FLAGS",
+    "NIX_CHECK_FLAGS",
+    "HOME_MAKE_FLAGS",
+    "HOME_CHECK_FLAGS",
+];
+
+/// Location of the negma config file.
+pub fn config_path(home_dir: &str) -> PathBuf {
+    PathBuf::from(format!("{}/.config/negma/config.cfg", home_dir))
+}
+
+/// The self-documented configuration written on first run and by `config reset`,
+/// and printed by `config default`.
+pub const DEFAULT_CONFIG: &str = r#"# Negma Configuration File
+
+###############################################################
+#                                                             #
+#                 Negma - NixOS Manager Config                #
+#                                                             #
+# This file controls how Negma manages your NixOS system.     #
+# It is automatically created and updated by Negma.           #
+#                                                             #
+###############################################################
+
+# === Basic Configuration ===
+
+# EDITOR specifies your preferred editor for editing config files.
+# Example: EDITOR = helix
+EDITOR = nano
+
+# EDITOR_ARGS are passed to the editor before the file. GUI editors that return at once
+# (code, codium, zed, subl, gedit, gnome-text-editor, kate, gvim, ...) get their wait flag
+# automatically so auto-format doesn't run before you save; setting EDITOR_ARGS replaces it.
+# Example: EDITOR_ARGS = --wait --new-window
+EDITOR_ARGS =
+
+# PAGER is the pager --pager uses for long output (list-generations, history).
+# Empty uses $PAGER, or less if that is unset.
+# Example: PAGER = less -S
+PAGER =
+
+# GIT specifies your system configuration git repo (optional).
+# Example: GIT = https://github.com/username/nixos-config
+GIT = 
+
+# KEEP specifies how many system generations to keep when cleanup is called.
+# 0 = keep current, 1 = keep current + last one, etc.
+# Example: KEEP = 5
+KEEP = 5
+
+# NIXOS_DIR / HOME_MANAGER_DIR point negma at your configuration if it does not live in
+# /etc/nixos or ~/.config/home-manager. Used by edit, fmt, backup, and the git checks.
+# A leading ~/ (or a bare ~) expands to your home directory.
+# Example: NIXOS_DIR = ~/nixos
+NIXOS_DIR =
+HOME_MANAGER_DIR =
+
+# HOME_MANAGER_MODE says how home-manager is installed: standalone (the home-manager
+# command applies it), module (imported into the NixOS configuration, so 'negma nix make'
+# applies it and 'home make' only points there), or auto (module when there is no
+# home-manager command on PATH).
+# Example: HOME_MANAGER_MODE = module
+HOME_MANAGER_MODE = auto
+
+# THEME selects the output colors: dark, light (for light terminal backgrounds),
+# or mono (no colors, like --no-color).
+# Example: THEME = light
+THEME = dark
+
+# LOG_LEVEL controls negma's own diagnostic log at ~/.config/negma/negma.log
+# (which command ran, the resolved config, why auto GC did or didn't run).
+# It is rotated to negma.log.1 past 1 MiB.
+# Valid values: off / error / warn / info / debug
+LOG_LEVEL = off
+
+# === Advanced Configuration ===
+
+# SYSTEM_FLAKE specifies a flake URI or local path for nixos-rebuild.
+# A leading ~/ expands to your home directory, as in NIXOS_DIR / HOME_MANAGER_DIR.
+# Example: SYSTEM_FLAKE = github:username/nixos-config
+# Example: SYSTEM_FLAKE = ~/nixos#myhost
+SYSTEM_FLAKE = 
+
+# MAKE_MODE specifies what 'negma nix make' does: switch, boot, or test.
+# 'boot' only activates the new generation on next reboot. Default: switch
+# Example: MAKE_MODE = boot
+MAKE_MODE = switch
+
+# REBUILD_FLAGS specifies additional flags for nixos-rebuild.
+# Example: REBUILD_FLAGS = --impure --show-trace
+REBUILD_FLAGS =
+
+# NIX_MAKE_FLAGS, NIX_BOOT_FLAGS, NIX_TEST_FLAGS, NIX_BUILD_FLAGS, NIX_CHECK_FLAGS,
+# HOME_MAKE_FLAGS and HOME_CHECK_FLAGS add flags to that one command only
+# ('nix switch' uses NIX_MAKE_FLAGS). Flags already given on the command line
+# or by REBUILD_FLAGS win; the duplicate here is dropped.
+# Example: NIX_CHECK_FLAGS = --show-trace
+NIX_MAKE_FLAGS =
+NIX_BOOT_FLAGS =
+NIX_TEST_FLAGS =
+NIX_BUILD_FLAGS =
+NIX_CHECK_FLAGS =
+HOME_MAKE_FLAGS =
+HOME_CHECK_FLAGS =
+
+# REQUIRE_CLEAN_GIT refuses 'make' while the config dir has uncommitted git changes.
+# Only applies when GIT is set and the dir is a git repo; --force overrides it once.
+# Valid values: true / false
+REQUIRE_CLEAN_GIT = false
+
+# PRE_CHECK parses every .nix file in the config dir with 'nix-instantiate --parse'
+# before 'make', so syntax errors fail in under a second instead of after evaluation.
+# Valid values: true / false
+PRE_CHECK = false
+
+# CONFIRM_MAKE asks y/N before a system 'make' (also switch/boot/test) starts, after the
+# one-line summary of mode, flake and flags it prints anyway. --yes skips the question.
+# Valid values: true / false
+CONFIRM_MAKE = false
+
+# CHANNEL specifies your preferred Nix channel.
+# Example: CHANNEL = nixos-unstable
+CHANNEL = 
+
+# AUTO_GC specifies if automatic garbage collection should run during rebuild.
+# Valid values: true / false
+AUTO_GC = true
+
+# GC_AGE_DAYS specifies the maximum age (in days) before GC removal.
+# Example: GC_AGE_DAYS = 15
+GC_AGE_DAYS = 15
+
+# GC_KEEP_OUTPUTS / GC_KEEP_DERIVATIONS keep the build dependencies of anything still
+# alive (e.g. a 'nix develop' shell) when collecting garbage. GC frees less space, but the
+# next development build doesn't have to fetch or rebuild them.
+# Valid values: true / false
+GC_KEEP_OUTPUTS = false
+GC_KEEP_DERIVATIONS = false
+
+# FORMATTER specifies which Nix formatter to use for autofmt operations.
+# Supported: nixfmt-rfc-style, alejandra, nixpkgs-fmt, etc.
+# Example: FORMATTER = alejandra
+FORMATTER = alejandra
+
+# AUTO_FMT specifies whether Negma should auto-format system config before rebuild.
+# Valid values: true / false
+AUTO_FMT = true
+
+# SUBSTITUTERS specifies extra binary caches passed to rebuilds (comma or space separated).
+# Example: SUBSTITUTERS = https://cache.nixos.org, https://nix-community.cachix.org
+SUBSTITUTERS =
+
+# TRUSTED_KEYS specifies the public keys for those caches, in 'name:base64' form.
+# Example: TRUSTED_KEYS = nix-community.cachix.org-1:mB9FSh9qf2dCimDSUo8Zy7bkq5CX+/rkCWyvRCYg3Fs=
+TRUSTED_KEYS =
+
+# BUILDERS specifies remote build machines for nixos-rebuild (nix 'builders' syntax).
+# When set, local builds are disabled (--max-jobs 0) so everything builds remotely.
+# The remote user usually has to be listed in nix.settings.trusted-users on the builder.
+# Example: BUILDERS = ssh://builder x86_64-linux - 16
+BUILDERS =
+
+# BUILDERS_USE_SUBSTITUTES lets the remote builders download dependencies from binary
+# caches instead of receiving them from this machine. Only used when BUILDERS is set.
+# Valid values: true / false
+BUILDERS_USE_SUBSTITUTES = false
+
+# BUILD_HOST / TARGET_HOST split a flake rebuild across machines: the system is built on
+# BUILD_HOST and activated on TARGET_HOST (nixos-rebuild --build-host / --target-host, over
+# ssh). Leave one empty to use this machine for that step. Flake mode only.
+# Example: TARGET_HOST = root@webserver
+BUILD_HOST =
+TARGET_HOST =
+
+# FALLBACK passes --fallback to rebuilds: a path a substituter fails to provide is
+# built locally instead of failing the rebuild. No effect with --offline.
+# Valid values: true / false
+FALLBACK = false
+
+# ACCEPT_FLAKE_CONFIG passes --accept-flake-config to flake rebuilds so a flake's
+# nixConfig is applied without prompting. Only used when SYSTEM_FLAKE is set.
+# Valid values: true / false
+ACCEPT_FLAKE_CONFIG = false
+
+# NO_UPDATE_LOCKFILE keeps flake rebuilds from touching flake.lock
+# (--no-update-lock-file --no-write-lock-file): a lock that is out of date with the
+# flake's inputs fails the build instead of being updated. Only used when SYSTEM_FLAKE is set.
+# Valid values: true / false
+NO_UPDATE_LOCKFILE = false
+
+# USE_REMOTE_SUDO builds as your user and only runs the activation through sudo
+# (nixos-rebuild --use-remote-sudo), so 'negma nix make' works without running negma as root.
+# Only used when SYSTEM_FLAKE is set.
+# Valid values: true / false
+USE_REMOTE_SUDO = false
+
+# RETRY specifies how many times 'make' is retried after a transient network failure
+# (e.g. "unable to download", "Connection timed out"), with exponential backoff.
+# Genuine evaluation or build failures are never retried.
+# Example: RETRY = 2
+RETRY = 0
+
+# COMMAND_TIMEOUT kills a spawned command (and everything it started) that runs longer
+# than this many seconds, e.g. a rebuild stuck on a prompt in automation. 0 = no limit.
+# Example: COMMAND_TIMEOUT = 3600
+COMMAND_TIMEOUT = 0
+
+# BUILD_CORES / BUILD_MAX_JOBS limit build parallelism (nix --cores / --max-jobs).
+# BUILD_CORES = 0 uses all cores per job. Leave empty for nix's own defaults.
+# Example: BUILD_MAX_JOBS = 2
+BUILD_CORES =
+BUILD_MAX_JOBS =
+
+# ALLOW_UNFREE / ALLOW_BROKEN set NIXPKGS_ALLOW_UNFREE=1 / NIXPKGS_ALLOW_BROKEN=1 for rebuilds.
+# Flakes only see these variables under --impure, which negma then adds automatically.
+# Valid values: true / false
+ALLOW_UNFREE = false
+ALLOW_BROKEN = false
+
+# PERMITTED_INSECURE lists insecure packages you accept (comma or space separated).
+# nixpkgs has no per-package variable, so any entry sets NIXPKGS_ALLOW_INSECURE=1, which
+# permits every insecure package; flakes again need --impure, added automatically.
+# Example: PERMITTED_INSECURE = openssl-1.1.1w
+PERMITTED_INSECURE =
+
+# SUDO_CMD specifies the command negma uses when it needs root (sudo, doas, run0, ...).
+# Unset, it is sudo. It is checked against PATH only when negma asks you to use it.
+# SUDO_CMD = doas
+
+# DRY_RUN_DEFAULT makes mutating commands only print what they would run,
+# unless --apply is passed. --dry-run always previews.
+# Valid values: true / false
+DRY_RUN_DEFAULT = false
+
+# === Aliases ===
+# Aliases allow you to create shortcuts for common commands: 'negma mk' runs 'negma nix make'.
+# $1, $2, ... are replaced by the arguments given to the alias and $@ by all of them;
+# arguments the alias doesn't use are appended. Built-in commands can't be overridden.
+# Example:
+# alias mk = nix make
+# alias bkup = home backup
+# alias deploy = nix make --label $1
+
+# === Environment ===
+# Extra environment variables set for every nix / home-manager command negma runs.
+# A single run can add more with --env KEY=VALUE.
+# Example:
+# env NIXPKGS_ALLOW_UNFREE = 1
+# env NIX_PATH = nixpkgs=/etc/nixpkgs
+
+"#;
+
+/// Writes the self-documented default configuration to `path`, replacing its contents.
+pub fn write_default(path: &Path) -> io::Result<()> {
+    fs::write(path, DEFAULT_CONFIG)
+}
+
+/// Keys of the `KEY = value` settings, in the order of the default config.
+pub fn known_keys() -> Vec<&'static str> {
+    DEFAULT_CONFIG
+        .lines()
+        .filter_map(|line| {
+            let key = line.split_once('=')?.0.trim();
+            (!key.is_empty() && key.chars().all(|c| c.is_ascii_uppercase() || c == '_')).then_some(key)
+        })
+        .collect()
+}
+
+/// The setting a config line assigns: `KEEP` for `KEEP = 5`, `alias mk` for `alias mk = nix make`.
+fn line_key(line: &str) -> Option<String> {
+    let line = line.trim();
+    if line.starts_with('#') {
+        return None;
+    }
+    let key = line.split_once('=')?.0.trim();
+    Some(match key.strip_prefix("alias ") {
+        Some(name) => format!("alias {}", name.trim()),
+        None => key.to_string(),
+    })
+}
+
+/// Returns `text` with every line assigning `key` rewritten to `key = value`, or with such a
+/// line appended if there is none. Comments, order, and all other lines are kept.
+pub fn set_value(text: &str, key: &str, value: &str) -> String {
+    let assignment = format!("{} = {}", key, value);
+    let mut found = false;
+    let mut lines: Vec<&str> = text
+        .lines()
+        .map(|line| {
+            if line_key(line).as_deref() == Some(key) {
+                found = true;
+                assignment.as_str()
+            } else {
+                line
+            }
+        })
+        .collect();
+    if !found {
+        lines.push(&assignment);
+    }
+    let mut updated = lines.join("\n");
+    updated.push('\n');
+    updated
+}
+
+/// Replaces the file at `path` with `contents` atomically, so a crash never leaves it half-written.
+pub fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    let tmp = path.with_extension("cfg.tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+/// Splits a comma and/or whitespace separated config value into its entries.
+fn split_list(val: &str) -> Vec<String> {
+    val.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Checks that a binary cache key has the `name:base64` shape nix expects.
+fn is_valid_public_key(key: &str) -> bool {
+    match key.split_once(':') {
+        Some((name, data)) => {
+            !name.is_empty()
+                && !data.is_empty()
+                && data
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '/' | '='))
+        }
+        None => false,
+    }
+}
+
+/// Whether `key` is a usable environment variable name (`[A-Za-z_][A-Za-z0-9_]*`).
+pub fn is_valid_env_key(key: &str) -> bool {
+    key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Splits a `KEY=VALUE` assignment as given to `--env`.
+pub fn parse_env_assignment(assignment: &str) -> Option<(String, String)> {
+    let (key, value) = assignment.split_once('=')?;
+    is_valid_env_key(key).then(|| (key.to_string(), value.to_string()))
+}
+
+/// Expands an alias `template` with the arguments given after the alias name.
+///
+/// `$1`, `$2`, ... take the matching argument and `$@` all of them. Arguments not used by a
+/// positional placeholder are appended unless `$@` is present. Fails with the number of
+/// the first placeholder that has no argument.
+pub fn expand_alias(template: &str, given: &[String]) -> Result<Vec<String>, usize> {
+    let mut expanded = Vec::new();
+    let mut used = 0;
+    let mut all_used = false;
+    for word in template.split_whitespace() {
+        if word == "$@" {
+            expanded.extend(given.iter().cloned());
+            all_used = true;
+        } else if let Some(n) = word.strip_prefix('$').and_then(|n| n.parse::<usize>().ok()).filter(|&n| n > 0) {
+            expanded.push(given.get(n - 1).ok_or(n)?.clone());
+            used = used.max(n);
+        } else {
+            expanded.push(word.to_string());
+        }
+    }
+    if !all_used {
+        expanded.extend(given.iter().skip(used).cloned());
+    }
+    Ok(expanded)
+}
+
+/// Whether `host` looks like an ssh destination for `--build-host` / `--target-host`:
+/// non-empty, a single word, and not an option.
+pub fn is_valid_host(host: &str) -> bool {
+    !host.is_empty() && !host.starts_with('-') && !host.contains(char::is_whitespace)
+}
+
+/// Minimal sanity check for a nix `builders` spec.
+///
+/// Accepts `@/path/to/machines` or `;`-separated entries whose first field is a
+/// host or `ssh://`/`ssh-ng://` URI.
+pub fn is_valid_builders(spec: &str) -> bool {
+    let spec = spec.trim();
+    if let Some(path) = spec.strip_prefix('@') {
+        return !path.trim().is_empty();
+    }
+
+    let mut entries = spec.split(';').map(str::trim).filter(|e| !e.is_empty()).peekable();
+    entries.peek().is_some()
+        && entries.all(|entry| {
+            let uri = entry.split_whitespace().next().unwrap_or_default();
+            let host = uri
+                .strip_prefix("ssh://")
+                .or_else(|| uri.strip_prefix("ssh-ng://"))
+                .unwrap_or(uri);
+            !host.is_empty()
+                && host
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '@' | ':' | '[' | ']'))
+        })
 }
@@ -0,0 +1,78 @@
+use crate::{config::CFG, tools};
+use std::path::Path;
+
+/// One self-diagnosis result of `negma doctor`.
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn tool(name: &'static str, program: &str) -> Check {
+        match tools::find_in_path(program) {
+            Some(path) => Check {
+                name,
+                ok: true,
+                detail: path.display().to_string(),
+            },
+            None => Check {
+                name,
+                ok: false,
+                detail: format!("'{}' not found in PATH", program),
+            },
+        }
+    }
+}
+
+/// Runs every check against the current environment and config.
+pub fn run(cfg: &CFG, home_dir: &str) -> Vec<Check> {
+    let mut checks = vec![
+        Check::tool("nix_on_path", "nix"),
+        Check::tool("nix_env_on_path", "nix-env"),
+        Check::tool("nixos_rebuild_on_path", "nixos-rebuild"),
+        Check::tool("home_manager_on_path", "home-manager"),
+        Check::tool("editor_on_path", cfg.editor.split_whitespace().next().unwrap_or_default()),
+        Check::tool("sudo_cmd_on_path", cfg.sudo_cmd.split_whitespace().next().unwrap_or_default()),
+    ];
+
+    if let Some(fmt) = &cfg.formatter {
+        checks.push(Check::tool("formatter_on_path", fmt));
+    }
+
+    let config_path = crate::config::config_path(home_dir);
+    checks.push(Check {
+        name: "config_present",
+        ok: config_path.is_file(),
+        detail: config_path.display().to_string(),
+    });
+
+    // Only local flake paths can be checked without evaluating anything.
+    if let Some(flake) = &cfg.system_flake {
+        let path = flake.split('#').next().unwrap_or(flake);
+        if path.starts_with('/') || path.starts_with('.') {
+            let flake_nix = Path::new(path).join("flake.nix");
+            checks.push(Check {
+                name: "system_flake_present",
+                ok: flake_nix.is_file(),
+                detail: flake_nix.display().to_string(),
+            });
+        }
+    }
+
+    checks
+}
+
+/// Renders the checks as `{"checks":[{"name":..,"ok":..,"detail":..}],"ok":..}`.
+pub fn to_json(checks: &[Check]) -> String {
+    let checks_json: Vec<serde_json::Value> = checks
+        .iter()
+        .map(|c| serde_json::json!({ "name": c.name, "ok": c.ok, "detail": c.detail }))
+        .collect();
+    serde_json::json!({
+        "checks": checks_json,
+        "ok": checks.iter().all(|c| c.ok),
+    })
+    .to_string()
+}
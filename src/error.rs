@@ -0,0 +1,51 @@
+use std::fmt;
+
+/// A failure surfaced to the user, rendered by `main` via `print_error`.
+#[derive(Debug)]
+pub struct NegmaError {
+    pub title: String,
+    pub details: Option<String>,
+    pub hint: Option<String>,
+    /// Process exit code negma terminates with.
+    pub code: i32,
+}
+
+pub type Result<T> = std::result::Result<T, NegmaError>;
+
+impl NegmaError {
+    pub fn new(title: impl Into<String>) -> Self {
+        Self {
+            title: title.into(),
+            details: None,
+            hint: None,
+            code: 1,
+        }
+    }
+
+    pub fn details(mut self, details: impl Into<String>) -> Self {
+        self.details = Some(details.into());
+        self
+    }
+
+    pub fn hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+
+    pub fn code(mut self, code: i32) -> Self {
+        self.code = code;
+        self
+    }
+}
+
+impl fmt::Display for NegmaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.title)?;
+        if let Some(details) = &self.details {
+            write!(f, ": {}", details)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for NegmaError {}
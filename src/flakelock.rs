@@ -0,0 +1,56 @@
+use chrono::DateTime;
+use std::collections::BTreeMap;
+
+/// A flake input whose locked revision differs between two `flake.lock` files.
+#[derive(Debug, PartialEq)]
+pub struct InputChange {
+    pub name: String,
+    /// Short revision (and date) before; `None` for a newly added input.
+    pub from: Option<String>,
+    /// Short revision (and date) after; `None` for a removed input.
+    pub to: Option<String>,
+}
+
+/// Lists the inputs whose locked revision changes from `old` to `new`, by name.
+pub fn diff(old: &str, new: &str) -> serde_json::Result<Vec<InputChange>> {
+    let old = locked_revisions(old)?;
+    let mut new = locked_revisions(new)?;
+
+    let mut changes = Vec::new();
+    for (name, from) in old {
+        let to = new.remove(&name);
+        if to.as_ref() != Some(&from) {
+            changes.push(InputChange { name, from: Some(from), to });
+        }
+    }
+    changes.extend(new.into_iter().map(|(name, to)| InputChange { name, from: None, to: Some(to) }));
+    changes.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(changes)
+}
+
+/// Every non-root node of a lock file, as `rev (YYYY-MM-DD)`.
+fn locked_revisions(lock: &str) -> serde_json::Result<BTreeMap<String, String>> {
+    let lock: serde_json::Value = serde_json::from_str(lock)?;
+    let root = lock["root"].as_str().unwrap_or("root");
+    let Some(nodes) = lock["nodes"].as_object() else {
+        return Ok(BTreeMap::new());
+    };
+
+    Ok(nodes
+        .iter()
+        .filter(|(name, _)| name.as_str() != root)
+        .filter_map(|(name, node)| {
+            let locked = &node["locked"];
+            let rev = locked["rev"].as_str().or(locked["narHash"].as_str())?;
+            let rev = rev.trim_start_matches("sha256-");
+            let mut shown = rev.chars().take(7).collect::<String>();
+            if let Some(day) = locked["lastModified"]
+                .as_i64()
+                .and_then(|t| DateTime::from_timestamp(t, 0))
+            {
+                shown.push_str(&format!(" ({})", day.format("%Y-%m-%d")));
+            }
+            Some((name.clone(), shown))
+        })
+        .collect())
+}
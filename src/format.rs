@@ -0,0 +1,83 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Contents of the files a formatter is about to rewrite, kept in memory so a
+/// crashing or misbehaving formatter can be undone.
+pub struct Snapshot {
+    files: Vec<(PathBuf, Vec<u8>)>,
+}
+
+impl Snapshot {
+    /// Captures `target` itself if it is a file, or every `.nix` file below it if it is a directory.
+    pub fn take(target: &Path) -> Snapshot {
+        let paths = if target.is_dir() { nix_files(target) } else { vec![target.to_path_buf()] };
+        let files = paths
+            .into_iter()
+            .filter_map(|path| {
+                let content = fs::read(&path).ok()?;
+                Some((path, content))
+            })
+            .collect();
+        Snapshot { files }
+    }
+
+    /// How many files were captured.
+    pub fn file_count(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Files whose contents now differ from the captured ones.
+    pub fn changed(&self) -> Vec<&Path> {
+        self.files
+            .iter()
+            .filter(|(path, before)| fs::read(path).map_or(true, |now| now != *before))
+            .map(|(path, _)| path.as_path())
+            .collect()
+    }
+
+    /// Files that had content before formatting but are now empty or gone.
+    pub fn emptied(&self) -> Vec<&Path> {
+        self.files
+            .iter()
+            .filter(|(path, before)| {
+                !before.is_empty() && fs::metadata(path).map_or(true, |m| m.len() == 0)
+            })
+            .map(|(path, _)| path.as_path())
+            .collect()
+    }
+
+    /// Writes every captured file back, returning how many were restored.
+    pub fn restore(&self) -> io::Result<usize> {
+        for (path, content) in &self.files {
+            fs::write(path, content)?;
+        }
+        Ok(self.files.len())
+    }
+}
+
+/// Every `.nix` file below `dir`, in a stable order.
+pub fn nix_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    collect_nix_files(dir, &mut files);
+    files.sort();
+    files
+}
+
+fn collect_nix_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            collect_nix_files(&path, files);
+        } else if file_type.is_file() && path.extension().is_some_and(|ext| ext == "nix") {
+            files.push(path);
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use chrono::NaiveDate;
+use std::{
+    collections::{BTreeSet, HashMap},
+    env, fs, io,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+/// The NixOS system profile managed by `nixos-rebuild`.
+pub const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
+
+/// Directory holding the system-wide nix profiles.
+pub const PROFILES_DIR: &str = "/nix/var/nix/profiles";
+
+/// Whether `name` can safely become `system-profiles/<name>`: a single non-empty path
+/// component, so it can't reach outside the profiles directory, that can't be mistaken for
+/// an option and contains no whitespace. `system` is accepted; callers map it to the main
+/// system profile.
+pub fn is_valid_profile_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.starts_with('-')
+        && !name.contains(|c: char| c == '/' || c == '\0' || c.is_whitespace())
+}
+
+/// Path of a named system profile, as created by `nixos-rebuild --profile-name <name>`.
+pub fn named_system_profile(name: &str) -> PathBuf {
+    PathBuf::from(format!("{}/system-profiles/{}", PROFILES_DIR, name))
+}
+
+/// Resolves the home-manager profile, preferring the XDG state location used by
+/// recent home-manager releases over the legacy per-user profile.
+pub fn home_profile(home_dir: &str) -> PathBuf {
+    let state = PathBuf::from(format!("{}/.local/state/nix/profiles/home-manager", home_dir));
+    if state.exists() {
+        return state;
+    }
+
+    let user = env::var("USER").unwrap_or_default();
+    let legacy = PathBuf::from(format!("/nix/var/nix/profiles/per-user/{}/home-manager", user));
+    if legacy.exists() { legacy } else { state }
+}
+
+/// Returns the generation number the profile symlink currently points at.
+///
+/// Profiles link to `<name>-<n>-link`, so the number is taken from the link target.
+pub fn current_generation(profile: &Path) -> Option<u32> {
+    let target = fs::read_link(profile).ok()?;
+    let name = target.file_name()?.to_str()?;
+    name.strip_suffix("-link")?.rsplit('-').next()?.parse().ok()
+}
+
+/// Finds the profiles in `dir` (and its `system-profiles/` subdirectory), with the
+/// generation each currently points at.
+///
+/// A profile is a symlink to one of its own `<name>-<n>-link` generation links; the
+/// generation links themselves are skipped.
+pub fn list_profiles(dir: &Path) -> Vec<(String, Option<u32>)> {
+    let mut profiles = Vec::new();
+    collect_profiles(dir, "", &mut profiles);
+    collect_profiles(&dir.join("system-profiles"), "system-profiles/", &mut profiles);
+    profiles.sort();
+    profiles
+}
+
+fn collect_profiles(dir: &Path, prefix: &str, profiles: &mut Vec<(String, Option<u32>)>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let is_generation_link = name
+            .strip_suffix("-link")
+            .and_then(|n| n.rsplit_once('-'))
+            .is_some_and(|(_, num)| num.parse::<u32>().is_ok());
+        if is_generation_link || !entry.file_type().is_ok_and(|t| t.is_symlink()) {
+            continue;
+        }
+
+        let points_at_own_generation = fs::read_link(entry.path()).is_ok_and(|target| {
+            target
+                .file_name()
+                .and_then(|f| f.to_str())
+                .is_some_and(|f| f.starts_with(&format!("{}-", name)) && f.ends_with("-link"))
+        });
+        if points_at_own_generation {
+            profiles.push((format!("{}{}", prefix, name), current_generation(&entry.path())));
+        }
+    }
+}
+
+/// Path of the `<profile>-<n>-link` entry for a specific generation.
+pub fn generation_link(profile: &Path, generation: u32) -> PathBuf {
+    let mut link = profile.as_os_str().to_owned();
+    link.push(format!("-{}-link", generation));
+    PathBuf::from(link)
+}
+
+/// Store path a generation link points at, if the generation still exists.
+pub fn store_path(profile: &Path, generation: u32) -> Option<PathBuf> {
+    fs::read_link(generation_link(profile, generation)).ok()
+}
+
+/// Closure size in bytes of `path`, from `nix path-info -S`.
+pub fn closure_size(path: &Path) -> io::Result<u64> {
+    let output = Command::new("nix")
+        .args(["--extra-experimental-features", "nix-command"])
+        .args(["path-info", "-S"])
+        .arg(path)
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("nix path-info exited with {}", output.status)));
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .last()
+        .and_then(|size| size.parse().ok())
+        .ok_or_else(|| io::Error::other("unexpected nix path-info output"))
+}
+
+/// Package names in the closure of `path`, from `nix-store --query --requisites`, with the
+/// hash and version stripped the way `nix store diff-closures` groups them.
+pub fn closure_packages(path: &Path) -> io::Result<BTreeSet<String>> {
+    let output = Command::new("nix-store")
+        .args(["--query", "--requisites"])
+        .arg(path)
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("nix-store exited with {}", output.status)));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).lines().filter_map(package_name).map(String::from).collect())
+}
+
+/// `firefox` for `/nix/store/<hash>-firefox-128.0`: the part of the name before the first
+/// dash followed by a digit.
+fn package_name(store_path: &str) -> Option<&str> {
+    let (_, name) = store_path.trim().rsplit('/').next()?.split_once('-')?;
+    let end = name.match_indices('-').find(|(i, _)| name[i + 1..].starts_with(|c: char| c.is_ascii_digit()));
+    Some(end.map_or(name, |(i, _)| &name[..i]))
+}
+
+/// Closure package sets by store path, so comparing adjacent generations queries each
+/// closure once.
+#[derive(Default)]
+pub struct ClosureCache(HashMap<PathBuf, Option<BTreeSet<String>>>);
+
+impl ClosureCache {
+    /// Counts of packages added and removed going from `old` to `new`, or `None` if either
+    /// closure could not be queried.
+    pub fn changes(&mut self, old: &Path, new: &Path) -> Option<(usize, usize)> {
+        for path in [old, new] {
+            self.0.entry(path.to_path_buf()).or_insert_with(|| closure_packages(path).ok());
+        }
+        let (old, new) = (self.0[old].as_ref()?, self.0[new].as_ref()?);
+        Some((new.difference(old).count(), old.difference(new).count()))
+    }
+}
+
+/// Generation numbers that still have a `<profile>-<n>-link`, oldest first.
+pub fn generation_numbers(profile: &Path) -> Vec<u32> {
+    let (Some(dir), Some(name)) = (profile.parent(), profile.file_name().and_then(|n| n.to_str())) else {
+        return Vec::new();
+    };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let mut numbers: Vec<u32> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let file = entry.file_name();
+            let rest = file.to_str()?.strip_prefix(name)?.strip_prefix('-')?;
+            rest.strip_suffix("-link")?.parse().ok()
+        })
+        .collect();
+    numbers.sort_unstable();
+    numbers
+}
+
+/// One entry of a generation listing, for either the system or home-manager profile.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Generation {
+    pub number: u32,
+    pub date: String,
+    /// Calendar day parsed from `date`, if it has the usual `YYYY-MM-DD ...` form.
+    pub day: Option<NaiveDate>,
+    pub current: bool,
+    /// Store path, when the listing includes it (home-manager does, nix-env does not).
+    pub path: Option<String>,
+    /// User label from negma's side table, if one was given with `--label`.
+    pub label: Option<String>,
+}
+
+/// Parses `nix-env --list-generations` output, e.g. `  42   2024-01-02 10:00:00   (current)`.
+pub fn parse_system_generations(output: &str) -> Vec<Generation> {
+    let mut gens: Vec<Generation> = output
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let number = words.next()?.parse().ok()?;
+            let date = format!("{} {}", words.next()?, words.next()?);
+            let current = words.next() == Some("(current)");
+            Some(Generation {
+                number,
+                day: parse_day(&date),
+                date,
+                current,
+                path: None,
+                label: None,
+            })
+        })
+        .collect();
+    gens.sort_by_key(|g| g.number);
+    gens
+}
+
+/// Parses the leading `YYYY-MM-DD` of a listing's date column.
+pub fn parse_day(date: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(date.split_whitespace().next()?, "%Y-%m-%d").ok()
+}
+
+/// Parses `home-manager generations` output, e.g.
+/// `2024-01-02 10:00 : id 42 -> /nix/store/...-home-manager-generation (current)`.
+pub fn parse_home_generations(output: &str) -> Vec<Generation> {
+    let mut gens: Vec<Generation> = output
+        .lines()
+        .filter_map(|line| {
+            let (date, rest) = line.split_once(" : id ")?;
+            let (number, rest) = rest.split_once(" -> ")?;
+            let mut words = rest.split_whitespace();
+            let path = words.next()?.to_string();
+            let current = words.next() == Some("(current)");
+            Some(Generation {
+                number: number.trim().parse().ok()?,
+                day: parse_day(date.trim()),
+                date: date.trim().to_string(),
+                current,
+                path: Some(path),
+                label: None,
+            })
+        })
+        .collect();
+    gens.sort_by_key(|g| g.number);
+    gens
+}
+
+/// Renders a listing as a JSON array for scripts; `path` and `label` are `null` when unknown.
+pub fn to_json(gens: &[Generation]) -> String {
+    let gens: Vec<serde_json::Value> = gens
+        .iter()
+        .map(|g| {
+            serde_json::json!({
+                "number": g.number,
+                "date": g.date,
+                "current": g.current,
+                "path": g.path,
+                "label": g.label,
+            })
+        })
+        .collect();
+    serde_json::Value::Array(gens).to_string()
+}
+
+/// Lists the generations of a system profile, oldest first.
+pub fn list_system(profile: &Path) -> io::Result<Vec<Generation>> {
+    let output = Command::new("nix-env")
+        .arg("--profile")
+        .arg(profile)
+        .arg("--list-generations")
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("nix-env exited with {}", output.status)));
+    }
+    Ok(parse_system_generations(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Lists home-manager generations, oldest first.
+pub fn list_home() -> io::Result<Vec<Generation>> {
+    let output = Command::new("home-manager")
+        .arg("generations")
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("home-manager exited with {}", output.status)));
+    }
+    Ok(parse_home_generations(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Generations a keep-N cleanup removes: everything except the current generation
+/// and the `keep` newest others, oldest first.
+pub fn to_delete(gens: &[Generation], keep: usize) -> Vec<u32> {
+    let others: Vec<u32> = gens.iter().filter(|g| !g.current).map(|g| g.number).collect();
+    let cut = others.len().saturating_sub(keep);
+    others[..cut].to_vec()
+}
+
+/// The generation `steps` before the current one. If there are not that many, returns
+/// how many generations older than the current one exist instead.
+pub fn steps_back(gens: &[Generation], steps: usize) -> Result<&Generation, usize> {
+    let current = gens.iter().position(|g| g.current).unwrap_or(gens.len().saturating_sub(1));
+    current.checked_sub(steps).map(|i| &gens[i]).ok_or(current)
+}
+
+/// Keeps the newest `limit` generations, plus the current one if it falls outside them.
+pub fn newest(gens: Vec<Generation>, limit: usize) -> Vec<Generation> {
+    let skip = gens.len().saturating_sub(limit);
+    let current = gens[..skip].iter().find(|g| g.current).cloned();
+    current.into_iter().chain(gens.into_iter().skip(skip)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_valid_profile_name;
+
+    #[test]
+    fn profile_names_must_be_a_single_plain_component() {
+        assert!(!is_valid_profile_name(""));
+        assert!(!is_valid_profile_name("a/b"));
+        assert!(!is_valid_profile_name("/etc"));
+        assert!(!is_valid_profile_name("."));
+        assert!(!is_valid_profile_name(".."));
+        assert!(!is_valid_profile_name("-rf"));
+        assert!(!is_valid_profile_name("my profile"));
+        assert!(!is_valid_profile_name("tab\tname"));
+    }
+
+    #[test]
+    fn plain_names_and_the_system_alias_are_accepted() {
+        assert!(is_valid_profile_name("system"));
+        assert!(is_valid_profile_name("testing"));
+        assert!(is_valid_profile_name("web-2024.1"));
+    }
+}
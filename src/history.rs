@@ -0,0 +1,142 @@
+use chrono::Local;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+/// Compact action log of mutating negma operations.
+///
+/// Stored at `~/.config/negma/history.log`, one tab-separated line per operation:
+/// `<timestamp>\t<exit status>\t<command>[\t<generation before>]`.
+///
+/// The trailing generation is only present for `make` operations and lets
+/// `rollback --last` return to the exact generation that was active beforehand.
+///
+/// This is not a rebuild output log, only a record of *what* negma did and how it ended.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub timestamp: String,
+    pub status: i32,
+    pub command: String,
+    pub previous_generation: Option<u32>,
+}
+
+impl Entry {
+    /// Whether this entry is a `<scope> <sub>` invocation, ignoring any trailing args.
+    pub fn is(&self, scope: &str, sub: &str) -> bool {
+        let mut words = self.command.split_whitespace();
+        words.next() == Some(scope) && words.next() == Some(sub)
+    }
+}
+
+struct Pending {
+    path: PathBuf,
+    command: String,
+    previous_generation: Option<u32>,
+}
+
+/// The operation currently in flight, recorded once it finishes.
+static PENDING: Mutex<Option<Pending>> = Mutex::new(None);
+
+pub fn log_path(home_dir: &str) -> PathBuf {
+    PathBuf::from(format!("{}/.config/negma/history.log", home_dir))
+}
+
+/// Marks `command` as the operation to record when [`finish`] is called.
+pub fn begin(home_dir: &str, command: String) {
+    if let Ok(mut pending) = PENDING.lock() {
+        *pending = Some(Pending {
+            path: log_path(home_dir),
+            command,
+            previous_generation: None,
+        });
+    }
+}
+
+/// Attaches the generation that was active before the pending operation ran.
+pub fn set_previous_generation(generation: Option<u32>) {
+    if let Ok(mut pending) = PENDING.lock()
+        && let Some(pending) = pending.as_mut()
+    {
+        pending.previous_generation = generation;
+    }
+}
+
+/// Drops the pending operation without recording it, for an operation the user cancelled
+/// before anything ran.
+pub fn cancel() {
+    if let Ok(mut pending) = PENDING.lock() {
+        pending.take();
+    }
+}
+
+/// Appends the pending operation (if any) with the given exit status.
+///
+/// Failing to write the history never aborts the actual operation.
+pub fn finish(status: i32) {
+    let Some(pending) = PENDING.lock().ok().and_then(|mut p| p.take()) else {
+        return;
+    };
+
+    let mut line = format!(
+        "{}\t{}\t{}",
+        Local::now().format("%Y-%m-%d %H:%M:%S"),
+        status,
+        pending.command
+    );
+    if let Some(generation) = pending.previous_generation {
+        line.push_str(&format!("\t{}", generation));
+    }
+    line.push('\n');
+
+    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(&pending.path) {
+        let _ = file.write_all(line.as_bytes());
+    }
+}
+
+/// Reads all well-formed entries, oldest first.
+pub fn read(home_dir: &str) -> Vec<Entry> {
+    let Ok(content) = fs::read_to_string(log_path(home_dir)) else {
+        return Vec::new();
+    };
+
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(4, '\t');
+            let timestamp = parts.next()?.to_string();
+            let status = parts.next()?.parse().ok()?;
+            let command = parts.next()?.to_string();
+            let previous_generation = parts.next().and_then(|g| g.parse().ok());
+            Some(Entry {
+                timestamp,
+                status,
+                command,
+                previous_generation,
+            })
+        })
+        .collect()
+}
+
+/// The most recent successful `<scope> make` (or its `switch` synonym). Makes into a
+/// `--target-profile` are skipped: they never touched the main profile. Its
+/// `previous_generation` is `None` when that make could not record one, e.g. with
+/// `--target-host`.
+pub fn last_make(home_dir: &str, scope: &str) -> Option<Entry> {
+    read(home_dir)
+        .into_iter()
+        .rev()
+        .filter(|e| !e.command.split_whitespace().any(|w| w == "--target-profile"))
+        .find(|e| e.status == 0 && (e.is(scope, "make") || e.is(scope, "switch")))
+}
+
+/// Wipes the history log.
+pub fn clear(home_dir: &str) -> std::io::Result<()> {
+    let path = log_path(home_dir);
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,200 @@
+use crate::{history, lock, logger, theme};
+use nix::{
+    libc::STDIN_FILENO,
+    sys::signal::{killpg, signal, SigHandler, Signal},
+    unistd::{getpgrp, getpid, tcgetpgrp, tcsetpgrp, Pid},
+};
+use std::{
+    io::{self, IsTerminal, Read, Write},
+    os::{
+        fd::BorrowedFd,
+        unix::process::{CommandExt, ExitStatusExt},
+    },
+    process::{exit, Child, Command, ExitStatus, Output, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// PID of the child negma is currently waiting on, or 0 when idle.
+static CHILD: AtomicI32 = AtomicI32::new(0);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// Whether the current child's process group was given the terminal.
+static FOREGROUND: AtomicBool = AtomicBool::new(false);
+/// Seconds a child may run before it is killed, or 0 for no limit.
+static TIMEOUT: AtomicU64 = AtomicU64::new(0);
+
+/// Exit code used after Ctrl-C, following the shell's 128 + SIGINT convention.
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+/// Installs the Ctrl-C handler.
+///
+/// While a child runs, SIGINT is forwarded to its process group (so whatever it started
+/// gets it too) and negma keeps waiting so the child is never orphaned; [`status`] then
+/// reports the interruption. When idle, negma cleans up and exits immediately.
+///
+/// A child that owns the terminal gets Ctrl-C from the terminal directly; negma notices
+/// from how it exited.
+pub fn install() {
+    let _ = ctrlc::set_handler(|| {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        let pid = CHILD.load(Ordering::SeqCst);
+        if pid > 0 {
+            let _ = killpg(Pid::from_raw(pid), Signal::SIGINT);
+        } else {
+            abort();
+        }
+    });
+}
+
+/// Limits every child spawned from now on to `secs` seconds (`COMMAND_TIMEOUT`); 0 disables it.
+pub fn set_timeout(secs: u64) {
+    TIMEOUT.store(secs, Ordering::Relaxed);
+}
+
+/// Spawns `cmd` and waits for it, tracking it so Ctrl-C reaches the child.
+///
+/// Exits with [`EXIT_INTERRUPTED`] once the child is gone if Ctrl-C was pressed.
+pub fn status(cmd: &mut Command) -> io::Result<ExitStatus> {
+    let (mut child, watchdog) = spawn(cmd)?;
+    let status = child.wait();
+    finish(watchdog, status.as_ref().ok())?;
+    status
+}
+
+/// Like [`status`], but also captures stderr while still echoing it live.
+pub fn status_capturing_stderr(cmd: &mut Command) -> io::Result<(ExitStatus, String)> {
+    let (mut child, watchdog) = spawn(cmd.stderr(Stdio::piped()))?;
+
+    let mut captured = Vec::new();
+    if let Some(mut stderr) = child.stderr.take() {
+        let mut buf = [0u8; 4096];
+        loop {
+            match stderr.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    let _ = io::stderr().write_all(&buf[..n]);
+                    captured.extend_from_slice(&buf[..n]);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+        }
+    }
+    let status = child.wait();
+    finish(watchdog, status.as_ref().ok())?;
+    Ok((status?, String::from_utf8_lossy(&captured).into_owned()))
+}
+
+/// Like [`status`], but captures stdout and stderr instead of showing them.
+pub fn output(cmd: &mut Command) -> io::Result<Output> {
+    let (child, watchdog) = spawn(cmd.stdout(Stdio::piped()).stderr(Stdio::piped()))?;
+    let output = child.wait_with_output();
+    finish(watchdog, output.as_ref().ok().map(|o| &o.status))?;
+    output
+}
+
+/// Kills the child's process group once the timeout expires, unless told the child is done.
+struct Watchdog {
+    done: mpsc::Sender<()>,
+    fired: JoinHandle<bool>,
+    secs: u64,
+}
+
+/// Spawns and tracks `cmd` in its own process group, so Ctrl-C and the timeout reach
+/// everything it started. If negma owns the terminal, the child's group takes it over so
+/// editors and password prompts keep working. With a timeout set, a [`Watchdog`] is armed.
+fn spawn(cmd: &mut Command) -> io::Result<(Child, Option<Watchdog>)> {
+    logger::debug(&format!("running {:?}", cmd));
+    let secs = TIMEOUT.load(Ordering::Relaxed);
+    cmd.process_group(0);
+    let terminal = io::stdin().is_terminal() && tcgetpgrp(io::stdin()).is_ok_and(|group| group == getpgrp());
+    if terminal {
+        // Done in the child as well, so it can't read from the terminal before it owns it.
+        // SAFETY: `give_terminal` only makes async-signal-safe calls.
+        unsafe {
+            cmd.pre_exec(|| {
+                give_terminal(getpid());
+                Ok(())
+            });
+        }
+    }
+    let child = cmd.spawn()?;
+    let pid = child.id() as i32;
+    CHILD.store(pid, Ordering::SeqCst);
+    if terminal {
+        give_terminal(Pid::from_raw(pid));
+        FOREGROUND.store(true, Ordering::SeqCst);
+    }
+
+    let watchdog = (secs > 0).then(|| {
+        let (done, rx) = mpsc::channel();
+        let fired = thread::spawn(move || {
+            if rx.recv_timeout(Duration::from_secs(secs)) != Err(RecvTimeoutError::Timeout) {
+                return false;
+            }
+            let _ = killpg(Pid::from_raw(pid), Signal::SIGKILL);
+            true
+        });
+        Watchdog { done, fired, secs }
+    });
+    Ok((child, watchdog))
+}
+
+/// Makes `pgrp` the terminal's foreground process group. SIGTTOU is ignored meanwhile,
+/// since a background group can't change it otherwise.
+fn give_terminal(pgrp: Pid) {
+    // SAFETY: stdin stays open for the whole process; `signal` only swaps dispositions.
+    unsafe {
+        let stdin = BorrowedFd::borrow_raw(STDIN_FILENO);
+        let previous = signal(Signal::SIGTTOU, SigHandler::SigIgn);
+        let _ = tcsetpgrp(stdin, pgrp);
+        if let Ok(previous) = previous {
+            let _ = signal(Signal::SIGTTOU, previous);
+        }
+    }
+}
+
+/// Stops tracking the child once it has exited (with `status`, if it could be waited on),
+/// handling Ctrl-C and an expired timeout.
+fn finish(watchdog: Option<Watchdog>, status: Option<&ExitStatus>) -> io::Result<()> {
+    CHILD.store(0, Ordering::SeqCst);
+    if FOREGROUND.swap(false, Ordering::SeqCst) {
+        give_terminal(getpgrp());
+        // Ctrl-C went to the child's group only; it died of SIGINT or exited like a shell does.
+        let sigint = status.is_some_and(|s| {
+            s.signal() == Some(Signal::SIGINT as i32) || s.code() == Some(EXIT_INTERRUPTED)
+        });
+        if sigint {
+            INTERRUPTED.store(true, Ordering::SeqCst);
+        }
+    }
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        abort();
+    }
+
+    let Some(Watchdog { done, fired, secs }) = watchdog else {
+        return Ok(());
+    };
+    drop(done);
+    if fired.join().unwrap_or(false) {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, format!("timed out after {}s", secs)));
+    }
+    Ok(())
+}
+
+/// Restores the terminal, records the interrupted operation, and exits.
+fn abort() -> ! {
+    // Reset colors and make sure the cursor is visible again.
+    if io::stdout().is_terminal() {
+        print!("\x1b[0m\x1b[?25h");
+    }
+    eprintln!("\n{} Interrupted.", theme::style_warning("[negma]"));
+    let _ = io::stdout().flush();
+    history::finish(EXIT_INTERRUPTED);
+    lock::release();
+    exit(EXIT_INTERRUPTED);
+}
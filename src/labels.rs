@@ -0,0 +1,36 @@
+use std::{collections::BTreeMap, fs, io, path::PathBuf};
+
+/// Side table of user-given system generation labels, since nix has no place for
+/// arbitrary per-generation names.
+///
+/// Stored at `~/.config/negma/labels.json` as `{"<generation>": "<label>", ...}`.
+pub fn path(home_dir: &str) -> PathBuf {
+    PathBuf::from(format!("{}/.config/negma/labels.json", home_dir))
+}
+
+/// All recorded labels; a missing or unreadable file means no labels.
+pub fn read(home_dir: &str) -> BTreeMap<u32, String> {
+    let Ok(content) = fs::read_to_string(path(home_dir)) else {
+        return BTreeMap::new();
+    };
+    let Ok(serde_json::Value::Object(map)) = serde_json::from_str(&content) else {
+        return BTreeMap::new();
+    };
+
+    map.into_iter()
+        .filter_map(|(generation, label)| Some((generation.parse().ok()?, label.as_str()?.to_string())))
+        .collect()
+}
+
+/// Labels `generation`, replacing any previous label.
+pub fn set(home_dir: &str, generation: u32, label: &str) -> io::Result<()> {
+    let mut labels = read(home_dir);
+    labels.insert(generation, label.to_string());
+
+    let map: serde_json::Map<String, serde_json::Value> = labels
+        .into_iter()
+        .map(|(generation, label)| (generation.to_string(), label.into()))
+        .collect();
+    let json = serde_json::to_string_pretty(&map).map_err(io::Error::other)?;
+    fs::write(path(home_dir), json + "\n")
+}
@@ -0,0 +1,46 @@
+use nix::fcntl::{Flock, FlockArg};
+use std::{
+    fs::{File, OpenOptions},
+    sync::Mutex,
+};
+
+/// Advisory lock held for the duration of a mutating operation.
+///
+/// Uses `flock` on `~/.config/negma/negma.lock`, so the kernel also drops it if negma dies.
+static HELD: Mutex<Option<Flock<File>>> = Mutex::new(None);
+
+pub fn lock_path(home_dir: &str) -> String {
+    format!("{}/.config/negma/negma.lock", home_dir)
+}
+
+/// Takes the negma lock without blocking. Returns `false` if another process holds it.
+pub fn try_acquire(home_dir: &str) -> std::io::Result<bool> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_path(home_dir))?;
+
+    match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+        Ok(lock) => {
+            if let Ok(mut held) = HELD.lock() {
+                *held = Some(lock);
+            }
+            Ok(true)
+        }
+        Err((_, nix::errno::Errno::EWOULDBLOCK)) => Ok(false),
+        Err((_, errno)) => Err(errno.into()),
+    }
+}
+
+/// Whether this process currently holds the lock.
+pub fn held() -> bool {
+    HELD.lock().is_ok_and(|held| held.is_some())
+}
+
+/// Releases the lock if this process holds it.
+pub fn release() {
+    if let Ok(mut held) = HELD.lock() {
+        held.take();
+    }
+}
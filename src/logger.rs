@@ -0,0 +1,105 @@
+use chrono::Local;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+/// negma's own diagnostic log: which command ran, what the config resolved to, and why
+/// auto-GC did or didn't run.
+///
+/// Stored at `~/.config/negma/negma.log`, one `<timestamp> <LEVEL> <message>` line per event,
+/// filtered by `LOG_LEVEL`. Unlike the history this is meant for debugging, not for replay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    pub fn parse(name: &str) -> Option<Level> {
+        match name.to_lowercase().as_str() {
+            "off" => Some(Level::Off),
+            "error" => Some(Level::Error),
+            "warn" | "warning" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Off => "off",
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+/// Past this size the log is moved to `negma.log.1`, replacing the previous one.
+const MAX_SIZE: u64 = 1024 * 1024;
+
+struct Logger {
+    path: PathBuf,
+    level: Level,
+}
+
+static LOGGER: Mutex<Option<Logger>> = Mutex::new(None);
+
+pub fn log_path(home_dir: &str) -> PathBuf {
+    PathBuf::from(format!("{}/.config/negma/negma.log", home_dir))
+}
+
+/// Starts logging at `level` and below, rotating an oversized log first.
+/// With [`Level::Off`] nothing is written.
+pub fn init(home_dir: &str, level: Level) {
+    let path = log_path(home_dir);
+    if level != Level::Off && fs::metadata(&path).is_ok_and(|m| m.len() > MAX_SIZE) {
+        let _ = fs::rename(&path, path.with_extension("log.1"));
+    }
+    if let Ok(mut logger) = LOGGER.lock() {
+        *logger = Some(Logger { path, level });
+    }
+}
+
+/// Appends `message` if `level` is enabled. Failing to write never aborts the operation.
+pub fn log(level: Level, message: &str) {
+    let Ok(logger) = LOGGER.lock() else {
+        return;
+    };
+    let Some(logger) = logger.as_ref().filter(|l| level != Level::Off && level <= l.level) else {
+        return;
+    };
+    let line = format!(
+        "{} {:<5} {}\n",
+        Local::now().format("%Y-%m-%d %H:%M:%S"),
+        level.name().to_uppercase(),
+        message
+    );
+    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(&logger.path) {
+        let _ = file.write_all(line.as_bytes());
+    }
+}
+
+pub fn error(message: &str) {
+    log(Level::Error, message);
+}
+
+pub fn warn(message: &str) {
+    log(Level::Warn, message);
+}
+
+pub fn info(message: &str) {
+    log(Level::Info, message);
+}
+
+pub fn debug(message: &str) {
+    log(Level::Debug, message);
+}
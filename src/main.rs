@@ -1,412 +1,3058 @@
 use colored::*;
 use std::{
     env::{self, args},
+    fmt::Write as _,
     fs::{self, File},
-    path::Path,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
     process::{exit, Command, Stdio},
     time::{Duration, SystemTime},
 };
-use std::os::unix::fs::MetadataExt;
+use std::os::unix::{
+    fs::MetadataExt,
+    process::{CommandExt, ExitStatusExt},
+};
 
+pub mod activation;
+pub mod buildlog;
+pub mod completions;
 pub mod config;
+pub mod doctor;
+pub mod error;
+pub mod flakelock;
+pub mod format;
+pub mod generations;
+pub mod history;
+pub mod interrupt;
+pub mod labels;
+pub mod lock;
+pub mod logger;
+pub mod nixconfig;
+pub mod optimise;
+pub mod pager;
+pub mod theme;
+pub mod tools;
 use crate::config::CFG;
+use crate::error::{NegmaError, Result};
+use crate::generations::SYSTEM_PROFILE;
 
 fn main() {
+    interrupt::install();
+
+    let code = match run() {
+        Ok(()) => 0,
+        Err(e) => {
+            logger::error(&match &e.details {
+                Some(details) => format!("{}: {}", e.title, details),
+                None => e.title.clone(),
+            });
+            print_error(&e.title, e.details.as_deref(), e.hint.as_deref());
+            e.code
+        }
+    };
+
+    history::finish(code);
+    lock::release();
+    exit(code);
+}
+
+fn run() -> Result<()> {
     let issu = nix::unistd::Uid::effective().is_root();
 
-    let home_dir = env::var("HOME").unwrap_or_else(|e| {
-        print_error(
-            "Unable to retrieve HOME environment variable",
-            Some(&format!("{}", e)),
-            Some("Ensure HOME is set correctly"),
-        );
-        exit(1);
-    });
+    let home_dir = env::var("HOME").map_err(|e| {
+        NegmaError::new("Unable to retrieve HOME environment variable")
+            .details(e.to_string())
+            .hint("Ensure HOME is set correctly")
+    })?;
+    check_home_dir(&home_dir)?;
+
+    let mut args = args().collect::<Vec<String>>();
+
+    // Called from shell completion scripts and redirected into files: keep stdout clean of
+    // config warnings and auto-GC output by handling them before the config is loaded.
+    match args.get(1).map(String::as_str) {
+        Some("__complete") => return handle_complete(&args, &home_dir),
+        Some("completions") => return handle_completions(&args),
+        _ => {}
+    }
+    // Stored verbatim, so `config set REBUILD_FLAGS --impure` keeps its `--impure`.
+    let set_value = take_config_set_value(&mut args);
+    let config_source = take_value(&mut args, "--config")?;
+    let mut flags = GlobalFlags::default();
+    flags.take_from(&mut args)?;
+    // Output that is piped or captured gets no escape codes, whatever `colored` decides on
+    // its own; CLICOLOR_FORCE still forces colors.
+    let forced = env::var("CLICOLOR_FORCE").is_ok_and(|v| v != "0");
+    let terminal = io::stdout().is_terminal() && io::stderr().is_terminal();
+    if take_flag(&mut args, "--no-color") || (!terminal && !forced) {
+        colored::control::set_override(false);
+    }
+    args.extend(set_value);
+
+    // Config problems are printed once the theme is set, so they use its colors; `config check`
+    // reports them itself, after parsing.
+    let checking_config = args.get(1).is_some_and(|a| a == "config") && args.get(2).is_some_and(|a| a == "check");
+    config::quiet_issues();
+    let mut cfg = match config_source.as_deref() {
+        None => CFG::parse(),
+        Some("-") => {
+            let mut cfg = CFG::parse_from(io::stdin().lock(), "<stdin>");
+            // No stable place for the auto-GC marker in an ephemeral run.
+            cfg.auto_gc = false;
+            cfg
+        }
+        Some(path) => {
+            let file = File::open(path).map_err(|e| {
+                NegmaError::new(format!("Unable to open config '{}'", path)).details(e.to_string())
+            })?;
+            CFG::parse_from(io::BufReader::new(file), path)
+        }
+    };
+    theme::set(cfg.theme);
+    if !checking_config {
+        config::print_issues();
+    }
 
-    let args = args().collect::<Vec<String>>();
-    let mut cfg = CFG::parse();
+    if let Some(name) = args.get(1).filter(|c| !BUILTIN_COMMANDS.contains(&c.as_str()))
+        && let Some((_, template)) = cfg.alias.iter().find(|(alias, _)| alias == name)
+    {
+        let expanded = config::expand_alias(template, &args[2..]).map_err(|n| {
+            NegmaError::new(format!("Alias '{}' needs an argument for ${}", name, n))
+                .details(format!("alias {} = {}", name, template))
+                .hint(format!("Pass at least {} argument(s): negma {} ...", n, name))
+        })?;
+        args.truncate(1);
+        args.extend(expanded);
+        // Flags written into the alias body count like ones typed on the command line.
+        let set_value = take_config_set_value(&mut args);
+        flags.take_from(&mut args)?;
+        args.extend(set_value);
+    }
+    let GlobalFlags {
+        dry_run, apply, builders, flake, builders_use_substitutes, build_host, target_host, keep_going, fallback, cores,
+        max_jobs, timeout, impure, accept_flake_config, no_update_lockfile, dirty, substituters_from_lock,
+        use_remote_sudo, env_overrides, nix_option_overrides, unfree, broken, permit_insecure, auto_fmt, no_auto_fmt,
+        fast, skip_gc, target_profile, verbose_nix, use_pager,
+    } = flags;
     cfg.issu = issu;
+    cfg.use_pager = use_pager;
+    cfg.dry_run = dry_run || (cfg.dry_run_default && !apply);
+    cfg.keep_going = keep_going;
+    cfg.fallback |= fallback;
+    if let Some(n) = cores {
+        cfg.build_cores = Some(parse_count(&n, "--cores")?);
+    }
+    if let Some(n) = max_jobs {
+        cfg.build_max_jobs = Some(parse_count(&n, "--max-jobs")?);
+    }
+    if let Some(secs) = timeout {
+        cfg.command_timeout = parse_count(&secs, "--timeout")?.into();
+    }
+    interrupt::set_timeout(cfg.command_timeout);
+    if let Some(flake) = flake {
+        cfg.system_flake = Some(resolve_flake_ref(&flake)?);
+    }
+    cfg.extra_options = nix_option_overrides;
+    if substituters_from_lock {
+        cfg.extra_options.extend(flake_cache_options(&cfg));
+    }
+    cfg.impure = impure;
+    cfg.accept_flake_config |= accept_flake_config;
+    cfg.no_update_lockfile |= no_update_lockfile;
+    cfg.allow_dirty = dirty;
+    cfg.use_remote_sudo |= use_remote_sudo;
+    if let Some(spec) = builders {
+        if !config::is_valid_builders(&spec) {
+            return Err(NegmaError::new(format!("Invalid builders spec '{}'", spec)).hint(
+                "Use 'ssh://host [system] [key] [jobs]' entries separated by ';', or '@/path/to/machines'",
+            ));
+        }
+        cfg.builders = Some(spec);
+    }
+    cfg.builders_use_substitutes |= builders_use_substitutes;
+    for (flag, host, slot) in [
+        ("--build-host", build_host, &mut cfg.build_host),
+        ("--target-host", target_host, &mut cfg.target_host),
+    ] {
+        if let Some(host) = host {
+            if !config::is_valid_host(&host) {
+                return Err(NegmaError::new(format!("Invalid {} value '{}'", flag, host))
+                    .hint("Use an ssh destination such as 'root@webserver'"));
+            }
+            *slot = Some(host);
+        }
+    }
+    if let Some(name) = target_profile {
+        cfg.target_profile = profile_name("--target-profile", &name)?;
+    }
+    if let Some(level) = verbose_nix {
+        let level = match level {
+            None => 1,
+            Some(level) => level.parse::<u8>().map_err(|_| {
+                NegmaError::new(format!("Invalid --verbose-nix level '{}'", level))
+                    .hint(format!("Use a level from 1 to {}, e.g. --verbose-nix=3", MAX_NIX_VERBOSITY))
+            })?,
+        };
+        if level > MAX_NIX_VERBOSITY {
+            eprintln!(
+                "{} --verbose-nix={} capped at {}",
+                theme::style_warning("[negma warning]"),
+                level,
+                MAX_NIX_VERBOSITY
+            );
+        }
+        cfg.nix_verbosity = level.min(MAX_NIX_VERBOSITY);
+    }
+    if fast {
+        cfg.auto_gc = false;
+        cfg.auto_fmt = false;
+    }
+    if skip_gc {
+        cfg.auto_gc = false;
+    }
+    match (auto_fmt, no_auto_fmt) {
+        (true, true) => {
+            return Err(NegmaError::new("--auto-fmt and --no-auto-fmt cannot be combined"));
+        }
+        (true, false) => cfg.auto_fmt = true,
+        (false, true) => cfg.auto_fmt = false,
+        (false, false) => {}
+    }
+    cfg.allow_unfree |= unfree;
+    cfg.allow_broken |= broken;
+    if cfg.allow_unfree {
+        cfg.env.push(("NIXPKGS_ALLOW_UNFREE".to_string(), "1".to_string()));
+    }
+    if cfg.allow_broken {
+        cfg.env.push(("NIXPKGS_ALLOW_BROKEN".to_string(), "1".to_string()));
+    }
+    cfg.permitted_insecure.extend(permit_insecure);
+    if !cfg.permitted_insecure.is_empty() {
+        cfg.env.push(("NIXPKGS_ALLOW_INSECURE".to_string(), "1".to_string()));
+    }
+    for assignment in env_overrides {
+        let pair = config::parse_env_assignment(&assignment).ok_or_else(|| {
+            NegmaError::new(format!("Invalid --env value '{}'", assignment))
+                .hint("Use --env KEY=VALUE, e.g. --env NIXPKGS_ALLOW_UNFREE=1")
+        })?;
+        cfg.env.push(pair);
+    }
+
+    // Built-in short forms, resolved before anything else looks at the command.
+    if let Some(command) = args.get_mut(1) {
+        match command.as_str() {
+            "n" => *command = "nix".to_string(),
+            "h" => *command = "home".to_string(),
+            _ => {}
+        }
+    }
+
+    logger::init(&home_dir, cfg.log_level);
+    logger::info(format!("negma {}", args[1..].join(" ")).trim_end());
+    logger::debug(&format!(
+        "config {}: {}, make mode {}, dry-run {}, auto GC {}",
+        config_source.unwrap_or_else(|| config::config_path(&home_dir).display().to_string()),
+        match &cfg.system_flake {
+            Some(flake) => format!("flake {}", flake),
+            None => "channels".to_string(),
+        },
+        cfg.make_mode,
+        cfg.dry_run,
+        cfg.auto_gc
+    ));
+
+    let mutating = args.len() >= 2 && is_mutating(&args) && !cfg.dry_run;
+    if mutating {
+        match lock::try_acquire(&home_dir) {
+            Ok(true) => {}
+            Ok(false) => {
+                return Err(NegmaError::new("Another negma operation is in progress")
+                    .details(lock::lock_path(&home_dir))
+                    .hint("Wait for it to finish, then try again"));
+            }
+            Err(e) => {
+                return Err(NegmaError::new("Failed to acquire the negma lock").details(e.to_string()));
+            }
+        }
+    }
 
-    if cfg.auto_gc {
-        perform_auto_gc(&cfg, &home_dir);
+    // These print output meant for scripts or reading, which a GC run would clutter.
+    let quiet_command = matches!(
+        (args.get(1).map(String::as_str), args.get(2).map(String::as_str)),
+        (Some("explain" | "uninstall"), _) | (Some("config"), Some("get" | "check"))
+    ) || args.iter().any(|a| a == "--json");
+    if cfg.auto_gc && !quiet_command {
+        perform_auto_gc(&cfg, &home_dir)?;
     }
 
     if args.len() < 2 {
         print_help();
-        exit(0);
+        return Ok(());
+    }
+
+    if mutating {
+        let mut command = args[1..].join(" ");
+        // Taken out of `args` with the other global flags, but it changes which profile was built.
+        if let Some(name) = &cfg.target_profile {
+            command.push_str(&format!(" --target-profile {}", name));
+        }
+        history::begin(&home_dir, command);
     }
 
     match args[1].as_str() {
         "home" => handle_home(&args, &cfg, &home_dir),
         "edit-cfg" => handle_edit_cfg(&cfg, &home_dir),
+        "config" => handle_config(&args, &cfg, &home_dir),
+        "history" => handle_history(&args, &cfg, &home_dir),
+        "doctor" => handle_doctor(&args, &cfg, &home_dir),
+        "status" => handle_status(&cfg, &home_dir),
+        "explain" => handle_explain(&args, &cfg, &home_dir),
+        "uninstall" => handle_uninstall(&args, &cfg, &home_dir),
+        "gc" => handle_gc(&cfg),
         "nix" => {
-            if cfg.issu {
-                handle_nix(&args, &cfg);
-            } else {
-                print_error(
-                    "Nix commands require superuser privileges",
-                    None,
-                    Some("Use: sudo negma nix <subcommand>"),
-                );
-                exit(1);
+            // With USE_REMOTE_SUDO a flake rebuild only elevates its activation step.
+            let unprivileged_rebuild = cfg.remote_sudo()
+                && matches!(args.get(2).map(String::as_str), Some("make" | "switch" | "boot" | "test"));
+            if !cfg.issu && !unprivileged_rebuild {
+                return Err(NegmaError::new("Nix commands require superuser privileges")
+                    .hint(escalation_hint(&cfg, "nix <subcommand>")));
             }
+            handle_nix(&args, &cfg, &home_dir)
         }
         _ => {
-            print_error(
-                &format!("Unknown command '{}'", args[1]),
-                None,
-                Some("Run 'negma' without arguments to see available commands"),
-            );
             print_help();
-            exit(1);
+            Err(NegmaError::new(format!("Unknown command '{}'", args[1]))
+                .hint("Run 'negma' without arguments to see available commands"))
         }
     }
 }
 
-/// Auto GC using marker file in config dir
-fn perform_auto_gc(cfg: &CFG, home_dir: &str) {
-    let marker_path = format!("{}/.config/negma/auto_gc_marker", home_dir);
-    let marker = Path::new(&marker_path);
-    let now = SystemTime::now();
-    let interval = Duration::from_secs(cfg.gc_age_days.unwrap_or(7) as u64 * 86400);
+/// Top-level commands, which aliases cannot override.
+const BUILTIN_COMMANDS: &[&str] = &[
+    "home", "h", "nix", "n", "edit-cfg", "config", "history", "doctor", "status", "explain", "gc", "completions",
+    "uninstall",
+];
 
-    if marker.exists() {
-        let metadata = fs::metadata(&marker).unwrap();
-        let birth_time = SystemTime::UNIX_EPOCH + Duration::from_secs(metadata.ctime() as u64);
-        if now.duration_since(birth_time).unwrap_or(Duration::from_secs(0)) >= interval {
-            println!(
-                "{} Auto GC: Collecting garbage, keeping last {} generations...",
-                "[negma]".green().bold(),
-                cfg.keep
-            );
-            let status = Command::new("nix-collect-garbage")
-                .arg("-d")
-                .stdin(Stdio::inherit())
-                .stdout(Stdio::inherit())
-                .stderr(Stdio::inherit())
-                .status();
-            exit_if_fail(status, "Auto GC failed");
-            fs::remove_file(&marker).unwrap_or_else(|e| {
-                print_error("Failed to remove old GC marker", Some(&e.to_string()), None);
-                exit(1);
-            });
-            File::create(&marker).unwrap();
-        }
-    } else {
-        File::create(&marker).unwrap();
+/// Fails early when `HOME` is not an existing, writable directory, instead of deep inside
+/// config creation.
+fn check_home_dir(home_dir: &str) -> Result<()> {
+    let path = Path::new(home_dir);
+    if !path.is_dir() {
+        return Err(NegmaError::new(format!("Home directory '{}' does not exist", home_dir))
+            .details("HOME must point to an existing directory; negma keeps its config in ~/.config/negma")
+            .hint("Create the directory or set HOME to the right path for this account"));
     }
+    if let Err(e) = nix::unistd::access(path, nix::unistd::AccessFlags::W_OK) {
+        return Err(NegmaError::new(format!("Home directory '{}' is not writable", home_dir))
+            .details(e.to_string())
+            .hint("Fix its permissions or set HOME to a directory this account can write to"));
+    }
+    Ok(())
 }
 
-fn handle_edit_cfg(cfg: &CFG, home_dir: &str) {
-    let path = format!("{}/.config/negma/config.cfg", home_dir);
-    let status = Command::new(&cfg.editor)
-        .arg(&path)
-        .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .status();
+/// Whether the invocation changes system, home, or negma state and belongs in the history log.
+fn is_mutating(args: &[String]) -> bool {
+    match (args[1].as_str(), args.get(2).map(String::as_str)) {
+        ("edit-cfg", _) | ("gc", _) => true,
+        ("config", Some(sub)) => matches!(sub, "init" | "reset" | "set"),
+        ("home", Some(sub)) => matches!(
+            sub,
+            "edit" | "fmt" | "make" | "switch" | "gc" | "clean" | "backup" | "rollback"
+        ),
+        ("nix", Some(sub)) => matches!(
+            sub,
+            "edit" | "fmt" | "make" | "switch" | "boot" | "test" | "build" | "gc" | "clean" | "rollback"
+                | "optimise" | "upgrade"
+        ),
+        _ => false,
+    }
+}
 
-    if let Ok(status) = status {
-        if status.success() && cfg.auto_fmt {
-            if let Some(fmt) = &cfg.formatter {
-                let _ = Command::new(fmt)
-                    .arg(&path)
-                    .stdout(Stdio::null())
-                    .stderr(Stdio::null())
-                    .status();
+/// Parses a non-negative integer flag value such as `--cores 4`.
+fn parse_count(value: &str, flag: &str) -> Result<u32> {
+    value.parse().map_err(|_| {
+        NegmaError::new(format!("Invalid {} value '{}'", flag, value))
+            .hint(format!("Use a non-negative integer, e.g. {} 4", flag))
+    })
+}
+
+/// negma's own flags, taken out of the command's words wherever they appear.
+#[derive(Default)]
+struct GlobalFlags {
+    dry_run: bool,
+    apply: bool,
+    builders: Option<String>,
+    flake: Option<String>,
+    builders_use_substitutes: bool,
+    build_host: Option<String>,
+    target_host: Option<String>,
+    keep_going: bool,
+    fallback: bool,
+    cores: Option<String>,
+    max_jobs: Option<String>,
+    timeout: Option<String>,
+    impure: bool,
+    accept_flake_config: bool,
+    no_update_lockfile: bool,
+    dirty: bool,
+    substituters_from_lock: bool,
+    use_remote_sudo: bool,
+    env_overrides: Vec<String>,
+    nix_option_overrides: Vec<(String, String)>,
+    unfree: bool,
+    broken: bool,
+    permit_insecure: Vec<String>,
+    auto_fmt: bool,
+    no_auto_fmt: bool,
+    fast: bool,
+    skip_gc: bool,
+    target_profile: Option<String>,
+    verbose_nix: Option<Option<String>>,
+    use_pager: bool,
+}
+
+impl GlobalFlags {
+    /// Takes the flags out of `args`, on top of any taken before: switches stay set, a later
+    /// value replaces an earlier one and repeatable flags add to the list.
+    fn take_from(&mut self, args: &mut Vec<String>) -> Result<()> {
+        self.dry_run |= take_flag(args, "--dry-run");
+        self.apply |= take_flag(args, "--apply");
+        self.builders = take_value(args, "--builders")?.or(self.builders.take());
+        self.flake = take_value(args, "--flake")?.or(self.flake.take());
+        self.builders_use_substitutes |= take_flag(args, "--builders-use-substitutes");
+        self.build_host = take_value(args, "--build-host")?.or(self.build_host.take());
+        self.target_host = take_value(args, "--target-host")?.or(self.target_host.take());
+        self.keep_going |= take_flag(args, "--keep-going");
+        self.fallback |= take_flag(args, "--fallback");
+        self.cores = take_value(args, "--cores")?.or(self.cores.take());
+        self.max_jobs = take_value(args, "--max-jobs")?.or(self.max_jobs.take());
+        self.timeout = take_value(args, "--timeout")?.or(self.timeout.take());
+        self.impure |= take_flag(args, "--impure");
+        self.accept_flake_config |= take_flag(args, "--accept-flake-config");
+        self.no_update_lockfile |= take_flag(args, "--no-update-lockfile");
+        self.dirty |= take_flag(args, "--dirty");
+        self.substituters_from_lock |= take_flag(args, "--substituters-from-lock");
+        self.use_remote_sudo |= take_flag(args, "--use-remote-sudo");
+        self.env_overrides.extend(take_values(args, "--env")?);
+        self.nix_option_overrides.extend(take_pairs(args, "--option")?);
+        self.unfree |= take_flag(args, "--unfree");
+        self.broken |= take_flag(args, "--broken");
+        self.permit_insecure.extend(take_values(args, "--permit-insecure")?);
+        self.auto_fmt |= take_flag(args, "--auto-fmt");
+        self.no_auto_fmt |= take_flag(args, "--no-auto-fmt");
+        self.fast |= take_flag(args, "--fast");
+        self.skip_gc |= take_flag(args, "--skip-gc");
+        self.target_profile = take_value(args, "--target-profile")?.or(self.target_profile.take());
+        self.verbose_nix = take_optional_value(args, "--verbose-nix").or(self.verbose_nix.take());
+        self.use_pager |= take_flag(args, "--pager");
+        Ok(())
+    }
+}
+
+/// Splits off the words after `config set <KEY>`: they are the value to store, not negma flags.
+fn take_config_set_value(args: &mut Vec<String>) -> Vec<String> {
+    match args.windows(2).position(|w| w[0] == "config" && w[1] == "set") {
+        Some(i) if args.len() > i + 3 => args.split_off(i + 3),
+        _ => Vec::new(),
+    }
+}
+
+/// Removes every occurrence of `flag` from `args`, returning whether it was present.
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let before = args.len();
+    args.retain(|a| a != flag);
+    args.len() != before
+}
+
+/// Removes `flag <value>` (or `flag=<value>`) from `args`, returning the last value given.
+fn take_value(args: &mut Vec<String>, flag: &str) -> Result<Option<String>> {
+    Ok(take_values(args, flag)?.pop())
+}
+
+/// Removes every bare `flag` or `flag=<value>` from `args`. Returns `None` if absent,
+/// `Some(None)` for the bare flag, and the last `Some(Some(value))` otherwise.
+fn take_optional_value(args: &mut Vec<String>, flag: &str) -> Option<Option<String>> {
+    let mut found = None;
+    args.retain(|a| {
+        if a == flag {
+            found = Some(None);
+            false
+        } else if let Some(v) = a.strip_prefix(flag).and_then(|r| r.strip_prefix('=')) {
+            found = Some(Some(v.to_string()));
+            false
+        } else {
+            true
+        }
+    });
+    found
+}
+
+/// Removes every `flag <value>` (or `flag=<value>`) from `args`, returning the values in order.
+fn take_values(args: &mut Vec<String>, flag: &str) -> Result<Vec<String>> {
+    let mut values = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        if args[i] == flag {
+            if i + 1 >= args.len() {
+                return Err(NegmaError::new(format!("Missing value for '{}'", flag)));
             }
-        } else if !status.success() {
-            print_error("Editor exited with error", Some(&format!("Code: {}", status)), None);
-            exit(1);
+            values.push(args.remove(i + 1));
+            args.remove(i);
+        } else if let Some(v) = args[i].strip_prefix(flag).and_then(|r| r.strip_prefix('=')) {
+            values.push(v.to_string());
+            args.remove(i);
+        } else {
+            i += 1;
         }
-    } else {
-        print_error("Failed to launch editor", None, None);
-        exit(1);
     }
+    Ok(values)
 }
 
-fn handle_home(args: &[String], cfg: &CFG, home_dir: &str) {
-    if args.len() < 3 {
-        print_error(
-            "Missing subcommand for 'home'",
-            None,
-            Some("Run 'negma' to see available home subcommands"),
+/// Removes every `flag <key> <value>` from `args`, returning the pairs in order.
+fn take_pairs(args: &mut Vec<String>, flag: &str) -> Result<Vec<(String, String)>> {
+    let mut pairs = Vec::new();
+    while let Some(i) = args.iter().position(|a| a == flag) {
+        let pair = args.get(i + 1..i + 3).filter(|kv| !kv.iter().any(|a| a.starts_with("--")));
+        let Some([key, value]) = pair else {
+            return Err(NegmaError::new(format!("'{}' needs a key and a value", flag))
+                .hint(format!("Use e.g. {} sandbox false", flag)));
+        };
+        pairs.push((key.clone(), value.clone()));
+        args.drain(i..i + 3);
+    }
+    Ok(pairs)
+}
+
+/// Highest `--verbose-nix` level; nix itself stops adding detail around `-vvvvv`.
+const MAX_NIX_VERBOSITY: u8 = 5;
+
+/// Marker whose creation time records the last auto GC.
+fn auto_gc_marker(home_dir: &str) -> PathBuf {
+    PathBuf::from(format!("{}/.config/negma/auto_gc_marker", home_dir))
+}
+
+/// Auto GC using marker file in config dir.
+///
+/// The marker's creation time is the last GC: a missing marker is created (starting the
+/// interval), and an expired one is recreated after collecting. When this is not called at
+/// all (`AUTO_GC = false`, `--fast`, `--skip-gc`) the marker and schedule stay as they were.
+///
+/// A due GC runs under the negma lock, so of several runs starting at once only one collects;
+/// the others skip it instead of waiting.
+fn perform_auto_gc(cfg: &CFG, home_dir: &str) -> Result<()> {
+    let marker_path = auto_gc_marker(home_dir);
+    let marker = marker_path.as_path();
+    let interval = Duration::from_secs(cfg.gc_age_days.unwrap_or(7) as u64 * 86400);
+    let due = || {
+        fs::metadata(marker).is_ok_and(|metadata| {
+            let birth_time = SystemTime::UNIX_EPOCH + Duration::from_secs(metadata.ctime() as u64);
+            SystemTime::now().duration_since(birth_time).unwrap_or(Duration::from_secs(0)) >= interval
+        })
+    };
+
+    if !marker.exists() {
+        logger::info("auto GC: no marker yet, starting the interval now");
+        if !cfg.dry_run {
+            File::create(marker)
+                .map_err(|e| NegmaError::new("Failed to create GC marker").details(e.to_string()))?;
+        }
+        return Ok(());
+    }
+    if !due() {
+        logger::debug(&format!("auto GC: not due, interval is {} days", interval.as_secs() / 86400));
+        return Ok(());
+    }
+
+    // Mutating commands already hold the lock; anything else takes it just for the GC.
+    let locked_here = !cfg.dry_run && !lock::held();
+    if locked_here && !lock::try_acquire(home_dir).unwrap_or(false) {
+        logger::warn("auto GC: due but skipped, another negma operation holds the lock");
+        println!(
+            "{} Auto GC skipped: another negma operation is in progress.",
+            theme::style_warning("[negma]")
         );
-        return;
+        return Ok(());
+    }
+    let result = collect_auto_gc(cfg, marker, due);
+    if locked_here {
+        lock::release();
+    }
+    result
+}
+
+/// The locked part of [`perform_auto_gc`]. The marker is checked again because a run that
+/// held the lock a moment ago may have just collected and reset it.
+fn collect_auto_gc(cfg: &CFG, marker: &Path, due: impl Fn() -> bool) -> Result<()> {
+    if !due() {
+        logger::info("auto GC: already collected by another run");
+        return Ok(());
+    }
+    logger::info("auto GC: due, collecting garbage");
+    println!(
+        "{} Auto GC: Collecting garbage, keeping last {} generations...",
+        theme::style_header("[negma]"),
+        cfg.keep
+    );
+    run_command(
+        cfg,
+        Command::new("nix-collect-garbage").arg("-d").args(cfg.gc_options()),
+        "Auto GC failed",
+    )?;
+    if cfg.dry_run {
+        return Ok(());
     }
+    fs::remove_file(marker).map_err(|e| {
+        NegmaError::new("Failed to remove old GC marker").details(e.to_string())
+    })?;
+    File::create(marker).map_err(|e| NegmaError::new("Failed to create GC marker").details(e.to_string()))?;
+    Ok(())
+}
 
-    let home_config_dir = format!("{}/.config/home-manager", home_dir);
+fn handle_edit_cfg(cfg: &CFG, home_dir: &str) -> Result<()> {
+    let path = config::config_path(home_dir);
+    let status = interrupt::status(
+        Command::new(&cfg.editor)
+            .args(cfg.editor_args())
+            .arg(&path)
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit()),
+    );
 
-    match args[2].as_str() {
-        "edit" => {
-            println!("{} Editing {}...", "[negma]".green().bold(), home_config_dir.bright_black());
-            let status = Command::new(&cfg.editor)
-                .arg(&format!("{}/home.nix",home_config_dir))
-                .stdin(Stdio::inherit())
-                .stdout(Stdio::inherit())
-                .stderr(Stdio::inherit())
-                .status();
-            exit_if_fail(status, "Editing home-manager config failed");
-
-            if cfg.auto_fmt {
-                if let Some(fmt) = &cfg.formatter {
-                    println!("{} Formatting {}...", "[negma]".green().bold(), home_config_dir.bright_black());
-                    let status = Command::new(fmt)
-                        .arg(&home_config_dir)
-                        .stdin(Stdio::inherit())
-                        .stdout(Stdio::inherit())
-                        .stderr(Stdio::inherit())
-                        .status();
-                    exit_if_fail(status, "Formatting home-manager config failed");
-                }
+    let Ok(status) = status else {
+        return Err(NegmaError::new("Failed to launch editor"));
+    };
+    if !status.success() {
+        return Err(NegmaError::new("Editor exited with error").details(format!("Code: {}", status)));
+    }
+
+    if cfg.auto_fmt && let Some(fmt) = &cfg.formatter {
+        let mut cmd = Command::new(fmt);
+        cmd.arg(&path);
+        if cfg.dry_run {
+            println!("{} {}", theme::style_warning("[dry-run]"), theme::style_dim(describe(&cmd)));
+        } else {
+            let _ = interrupt::status(cmd.stdout(Stdio::null()).stderr(Stdio::null()));
+        }
+    }
+    Ok(())
+}
+
+fn handle_config(args: &[String], cfg: &CFG, home_dir: &str) -> Result<()> {
+    let path = config::config_path(home_dir);
+
+    match args.get(2).map(String::as_str) {
+        Some("init") => {
+            // A missing or empty config has already been written by `CFG::parse`.
+            if !args[3..].iter().any(|a| a == "--force") {
+                println!(
+                    "{} Configuration already exists at {}.",
+                    theme::style_header("[negma]"),
+                    theme::style_dim(path.display().to_string())
+                );
+                println!(
+                    "{} Use 'negma config init --force' to overwrite it with the default.",
+                    theme::style_warning("hint:")
+                );
+                return Ok(());
+            }
+
+            if cfg.dry_run {
+                println!(
+                    "{} write default configuration -> {}",
+                    theme::style_warning("[dry-run]"),
+                    theme::style_dim(path.display().to_string())
+                );
+                return Ok(());
             }
+            config::write_default(&path).map_err(|e| {
+                NegmaError::new("Failed to write default configuration").details(e.to_string())
+            })?;
+            println!(
+                "{} Default configuration written to {}",
+                theme::style_header("[negma]"),
+                theme::style_dim(path.display().to_string())
+            );
+            Ok(())
         }
-        "fmt" => {
-            if let Some(fmt) = &cfg.formatter {
-                println!("{} Formatting {}...", "[negma]".green().bold(), home_config_dir.bright_black());
-                let status = Command::new(fmt)
-                    .arg(&home_config_dir)
-                    .stdin(Stdio::inherit())
-                    .stdout(Stdio::inherit())
-                    .stderr(Stdio::inherit())
-                    .status();
-                exit_if_fail(status, "Formatting home-manager config failed");
+        Some("check") => {
+            // The startup parse already collected every problem.
+            let issues = config::issues();
+            if args[3..].iter().any(|a| a == "--json") {
+                let issues: Vec<serde_json::Value> = issues
+                    .iter()
+                    .map(|i| serde_json::json!({ "line": i.line, "severity": i.severity.name(), "message": i.message }))
+                    .collect();
+                println!("{}", serde_json::Value::Array(issues));
+            } else if issues.is_empty() {
+                println!("{} Configuration is valid.", theme::style_header("[negma]"));
             } else {
-                print_error("No formatter configured", None, Some("Set 'formatter' in negma config"));
+                for issue in &issues {
+                    let severity = match issue.severity {
+                        config::Severity::Warning => theme::style_warning(issue.severity.name()),
+                        config::Severity::Error => theme::style_error(issue.severity.name()),
+                    };
+                    println!("{} {}: {}", theme::style_dim(format!("line {}:", issue.line)), severity, issue.message);
+                }
+            }
+
+            let errors = issues.iter().filter(|i| i.severity == config::Severity::Error).count();
+            let warnings = issues.len() - errors;
+            if errors > 0 {
+                Err(NegmaError::new(format!("Configuration has {} error(s) and {} warning(s)", errors, warnings))
+                    .hint("Unparsable lines are ignored; fix them or remove them")
+                    .code(3))
+            } else if warnings > 0 {
+                Err(NegmaError::new(format!("Configuration has {} warning(s)", warnings))
+                    .hint("Invalid values fall back to their defaults")
+                    .code(2))
+            } else {
+                Ok(())
             }
         }
-        "make" => {
-            println!("{} Applying home-manager switch...", "[negma]".green().bold());
-            let status = Command::new("home-manager")
-                .arg("switch")
-                .stdin(Stdio::inherit())
-                .stdout(Stdio::inherit())
-                .stderr(Stdio::inherit())
-                .status();
-            exit_if_fail(status, "home-manager switch failed");
+        Some("default") => {
+            print!("{}", config::DEFAULT_CONFIG);
+            Ok(())
         }
-        "gc" => {
-            println!("{} Expiring old home-manager generations...", "[negma]".green().bold());
-            let status = Command::new("home-manager")
-                .arg("expire-generations")
-                .arg("-d")
-                .stdin(Stdio::inherit())
-                .stdout(Stdio::inherit())
-                .stderr(Stdio::inherit())
-                .status();
-            exit_if_fail(status, "home-manager expire-generations failed");
+        Some("get") => {
+            let Some(key) = args.get(3) else {
+                return Err(NegmaError::new("Usage: negma config get <KEY>")
+                    .hint("Aliases: negma config get alias.<name>"));
+            };
+            let value = match key.strip_prefix("alias.") {
+                Some(name) => cfg.alias.iter().find(|(alias, _)| alias == name).map(|(_, cmd)| cmd.clone()),
+                None => cfg.get(key),
+            };
+            let value = value.ok_or_else(|| match key.strip_prefix("alias.") {
+                Some(name) => NegmaError::new(format!("No alias '{}'", name)),
+                None => NegmaError::new(format!("Unknown config key '{}'", key))
+                    .hint("See 'negma config default' for every key and its meaning"),
+            })?;
+            println!("{}", value);
+            Ok(())
         }
-        "clean" => {
-            println!("{} Cleaning old Home Manager generations, keeping current...", "[negma]".green().bold());
-            let status = Command::new("home-manager")
-                .arg("expire-generations")
-                .arg("0")
-                .stdin(Stdio::inherit())
-                .stdout(Stdio::inherit())
-                .stderr(Stdio::inherit())
-                .status();
-            exit_if_fail(status, "home-manager clean failed");
-        }
-        "backup" => {
-            let config_path = format!("{}/home.nix", home_config_dir);
-            let backup_path = format!("{}/home.nix.bak", home_config_dir);
-
-            fs::copy(&config_path, &backup_path).unwrap_or_else(|e| {
-                print_error("Failed to backup home.nix", Some(&e.to_string()), None);
-                exit(1);
-            });
+        Some("set") => {
+            let usage = || {
+                NegmaError::new("Usage: negma config set <KEY> <VALUE>")
+                    .hint("Aliases: negma config set alias <name> <command>")
+            };
+            let (key, value) = match args.get(3).map(String::as_str) {
+                Some("alias") if args.len() > 5 => (format!("alias {}", args[4]), args[5..].join(" ")),
+                Some("alias") => return Err(usage()),
+                Some(key) if args.len() > 4 => (key.to_uppercase(), args[4..].join(" ")),
+                _ => return Err(usage()),
+            };
+            if !key.starts_with("alias ") && !config::known_keys().contains(&key.as_str()) {
+                return Err(NegmaError::new(format!("Unknown config key '{}'", key))
+                    .hint("See 'negma config default' for every key and its meaning"));
+            }
+
+            let current = fs::read_to_string(&path).map_err(|e| {
+                NegmaError::new("Failed to read the configuration").details(e.to_string())
+            })?;
+            let updated = config::set_value(&current, &key, &value);
+            if cfg.dry_run {
+                println!(
+                    "{} set {} = {} in {}",
+                    theme::style_warning("[dry-run]"),
+                    key,
+                    value,
+                    theme::style_dim(path.display().to_string())
+                );
+                return Ok(());
+            }
+            config::write_atomic(&path, &updated).map_err(|e| {
+                NegmaError::new("Failed to write the configuration").details(e.to_string())
+            })?;
             println!(
-                "{} Backup created: {}",
-                "[negma]".green().bold(),
-                backup_path.bright_black()
+                "{} Set {} = {} in {}",
+                theme::style_header("[negma]"),
+                theme::style_accent(&key),
+                theme::style_value(&value),
+                theme::style_dim(path.display().to_string())
             );
+            // Parsing again surfaces the usual warnings if the new value is invalid.
+            CFG::parse_from(updated.as_bytes(), &path.display().to_string());
+            Ok(())
         }
-        "list-generations" => {
-            println!("{} Listing home-manager generations...", "[negma]".green().bold());
-            let status = Command::new("home-manager")
-                .arg("generations")
-                .stdin(Stdio::inherit())
-                .stdout(Stdio::inherit())
-                .stderr(Stdio::inherit())
-                .status();
-            exit_if_fail(status, "home-manager generations failed");
+        Some("reset") => {
+            let backup = path.with_extension("cfg.bak");
+            if cfg.dry_run {
+                println!(
+                    "{} copy {} -> {}",
+                    theme::style_warning("[dry-run]"),
+                    theme::style_dim(path.display().to_string()),
+                    theme::style_dim(backup.display().to_string())
+                );
+                println!(
+                    "{} write default configuration -> {}",
+                    theme::style_warning("[dry-run]"),
+                    theme::style_dim(path.display().to_string())
+                );
+                return Ok(());
+            }
+
+            let yes = args[3..].iter().any(|a| a == "--yes" || a == "-y");
+            if !yes && !confirm(&format!("Reset {} to the default configuration?", path.display())) {
+                println!("{} Reset cancelled.", theme::style_warning("[negma]"));
+                return Ok(());
+            }
+
+            fs::copy(&path, &backup).map_err(|e| {
+                NegmaError::new("Failed to back up the current configuration").details(e.to_string())
+            })?;
+            config::write_default(&path).map_err(|e| {
+                NegmaError::new("Failed to write default configuration").details(e.to_string())
+            })?;
+            println!(
+                "{} Configuration reset; previous version saved to {}",
+                theme::style_header("[negma]"),
+                theme::style_dim(backup.display().to_string())
+            );
+            Ok(())
         }
-        "rollback" => {
-            let r#gen = if args.len() > 3 { &args[3] } else { "--rollback" };
-            println!("{} Rolling back home-manager...", "[negma]".green().bold());
-            let status = Command::new("home-manager")
-                .args(&["switch", r#gen])
-                .stdin(Stdio::inherit())
-                .stdout(Stdio::inherit())
-                .stderr(Stdio::inherit())
-                .status();
-            exit_if_fail(status, "home-manager rollback failed");
+        Some(sub) => Err(NegmaError::new(format!("Unknown config subcommand '{}'", sub))
+            .hint("Run 'negma' for available subcommands")),
+        None => Err(NegmaError::new("Missing subcommand for 'config'")
+            .hint("Run 'negma' to see available config subcommands")),
+    }
+}
+
+/// Removes negma's own state in `~/.config/negma` (config, history, labels, markers, backups)
+/// after confirmation. NixOS and home-manager state are never touched.
+fn handle_uninstall(args: &[String], cfg: &CFG, home_dir: &str) -> Result<()> {
+    let dir = PathBuf::from(format!("{}/.config/negma", home_dir));
+    let keep_config = args.iter().any(|a| a == "--keep-config");
+    let config_path = config::config_path(home_dir);
+    // Don't pull state out from under a running make or GC. Taken before listing, so the
+    // lock file is removed along with everything else.
+    if !cfg.dry_run {
+        let acquired = lock::try_acquire(home_dir).map_err(|e| {
+            NegmaError::new("Failed to acquire the negma lock").details(e.to_string())
+        })?;
+        if !acquired {
+            return Err(NegmaError::new("Another negma operation is in progress")
+                .details(lock::lock_path(home_dir))
+                .hint("Wait for it to finish, then try again"));
         }
-        _ => {
-            print_error(
-                &format!("Unknown home subcommand '{}'", args[2]),
-                None,
-                Some("Run 'negma' for available subcommands"),
+    }
+
+    let mut doomed: Vec<PathBuf> = fs::read_dir(&dir)
+        .map(|entries| entries.flatten().map(|e| e.path()).collect())
+        .unwrap_or_default();
+    doomed.retain(|p| !(keep_config && *p == config_path));
+    doomed.sort();
+    if doomed.is_empty() {
+        println!(
+            "{} Nothing to remove in {}.",
+            theme::style_header("[negma]"),
+            theme::style_dim(dir.display().to_string())
+        );
+        return Ok(());
+    }
+
+    println!(
+        "{} negma's own files in {}:",
+        theme::style_header("[negma]"),
+        theme::style_dim(dir.display().to_string())
+    );
+    for path in &doomed {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        println!("  {}", name);
+    }
+    println!("{} NixOS and Home Manager state are left untouched.", theme::style_header("[negma]"));
+
+    if cfg.dry_run {
+        for path in &doomed {
+            println!("{} remove {}", theme::style_warning("[dry-run]"), theme::style_dim(path.display().to_string()));
+        }
+        return Ok(());
+    }
+    let yes = args.iter().any(|a| a == "--yes" || a == "-y");
+    if !yes && !confirm(&format!("Remove these {} item(s)?", doomed.len())) {
+        println!("{} Uninstall cancelled.", theme::style_warning("[negma]"));
+        return Ok(());
+    }
+    for path in &doomed {
+        let removed = if path.is_dir() { fs::remove_dir_all(path) } else { fs::remove_file(path) };
+        removed.map_err(|e| {
+            NegmaError::new(format!("Failed to remove {}", path.display())).details(e.to_string())
+        })?;
+        println!("{} Removed {}", theme::style_header("[negma]"), theme::style_dim(path.display().to_string()));
+    }
+    if !keep_config {
+        let _ = fs::remove_dir(&dir);
+    }
+    Ok(())
+}
+
+/// Combined cleanup: expires home-manager generations, then collects system garbage.
+///
+/// Under sudo the home phase runs as the invoking user (`SUDO_USER`) so it expires their
+/// generations rather than root's. Each phase is reported separately.
+fn handle_gc(cfg: &CFG) -> Result<()> {
+    let mut home = Command::new("home-manager");
+    home.args(["expire-generations", "-d"]);
+    if cfg.issu
+        && let Ok(name) = env::var("SUDO_USER")
+        && name != "root"
+    {
+        let user = nix::unistd::User::from_name(&name)
+            .ok()
+            .flatten()
+            .ok_or_else(|| NegmaError::new(format!("Unknown SUDO_USER '{}'", name)))?;
+        as_user(&mut home, &user);
+    }
+
+    println!("{} Expiring old home-manager generations...", theme::style_header("[negma]"));
+    let home_result = run_command(cfg, &mut home, "home-manager expire-generations failed");
+
+    let system_result = cfg.issu.then(|| {
+        println!("{} Collecting system garbage...", theme::style_header("[negma]"));
+        run_command(
+            cfg,
+            Command::new("nix-collect-garbage").arg("-d").args(cfg.gc_options()),
+            "nix-collect-garbage failed",
+        )
+    });
+
+    println!("\n{}", theme::style_title("GC summary:"));
+    let report = |phase: &str, result: Option<&Result<()>>| match result {
+        Some(Ok(())) => println!("  {:<8} {}", phase, theme::style_header("ok")),
+        Some(Err(e)) => println!("  {:<8} {} {}", phase, theme::style_error("failed"), theme::style_dim(&e.title)),
+        None => println!(
+            "  {:<8} {} {}",
+            phase,
+            theme::style_warning("skipped"),
+            theme::style_dim(format!("(needs root: {} negma gc)", cfg.sudo_cmd))
+        ),
+    };
+    report("home", Some(&home_result));
+    report("system", system_result.as_ref());
+
+    home_result.and(system_result.unwrap_or(Ok(())))
+}
+
+fn handle_doctor(args: &[String], cfg: &CFG, home_dir: &str) -> Result<()> {
+    let checks = doctor::run(cfg, home_dir);
+    let healthy = checks.iter().all(|c| c.ok);
+
+    if args[2..].iter().any(|a| a == "--json") {
+        println!("{}", doctor::to_json(&checks));
+    } else {
+        let width = checks.iter().map(|c| c.name.len()).max().unwrap_or(0);
+        for check in &checks {
+            let mark = if check.ok { theme::style_header("✓") } else { theme::style_error("✗") };
+            println!(
+                "  {} {:<width$}  {}",
+                mark,
+                check.name,
+                theme::style_dim(&check.detail),
+                width = width
             );
         }
     }
+
+    if healthy {
+        Ok(())
+    } else {
+        Err(NegmaError::new(format!(
+            "{} of {} checks failed",
+            checks.iter().filter(|c| !c.ok).count(),
+            checks.len()
+        )))
+    }
 }
 
-fn handle_nix(args: &[String], cfg: &CFG) {
-    if args.len() < 3 {
-        print_error(
-            "Missing subcommand for 'nix'",
-            None,
-            Some("Run 'negma' to see available nix subcommands"),
-        );
-        return;
+fn handle_completions(args: &[String]) -> Result<()> {
+    let shell = args.get(2).map(String::as_str).unwrap_or_default();
+    let dynamic = args[2..].iter().any(|a| a == "--dynamic");
+    let script = completions::script(shell, dynamic).ok_or_else(|| {
+        NegmaError::new(format!("Unknown shell '{}'", shell)).hint(format!(
+            "Use one of: {} (add --dynamic to complete generation numbers)",
+            completions::SHELLS.join(", ")
+        ))
+    })?;
+    print!("{}", script);
+    Ok(())
+}
+
+/// Hidden helper for the `--dynamic` completion scripts: `__complete rollback <home|nix>`
+/// prints the profile's generation numbers, newest first, one per line.
+fn handle_complete(args: &[String], home_dir: &str) -> Result<()> {
+    let profile = match (args.get(2).map(String::as_str), args.get(3).map(String::as_str)) {
+        (Some("rollback"), Some("home")) => generations::home_profile(home_dir),
+        (Some("rollback"), Some("nix")) => PathBuf::from(SYSTEM_PROFILE),
+        _ => return Err(NegmaError::new("Usage: negma __complete rollback <home|nix>")),
+    };
+    for number in generations::generation_numbers(&profile).iter().rev() {
+        println!("{}", number);
     }
+    Ok(())
+}
 
-    match args[2].as_str() {
-        "edit" => {
-            let config_path = "/etc/nixos/configuration.nix";
-            println!("{} Editing {}...", "[negma]".green().bold(), config_path.bright_black());
-            let status = Command::new(&cfg.editor)
-                .arg(config_path)
-                .stdin(Stdio::inherit())
-                .stdout(Stdio::inherit())
-                .stderr(Stdio::inherit())
-                .status();
-            exit_if_fail(status, "Failed to edit NixOS configuration");
-
-            if cfg.auto_fmt {
-                if let Some(fmt) = &cfg.formatter {
-                    println!("{} Formatting {}...", "[negma]".green().bold(), config_path.bright_black());
-                    let status = Command::new(fmt)
-                        .arg(config_path)
-                        .stdin(Stdio::inherit())
-                        .stdout(Stdio::inherit())
-                        .stderr(Stdio::inherit())
-                        .status();
-                    exit_if_fail(status, "Failed to format NixOS configuration");
-                }
-            }
+/// Prints the external commands a negma command maps to, with the configured flake, flags,
+/// and environment filled in, without running anything or checking prerequisites.
+fn handle_explain(args: &[String], cfg: &CFG, home_dir: &str) -> Result<()> {
+    fn command<S: AsRef<std::ffi::OsStr>>(program: &str, args: impl IntoIterator<Item = S>) -> Command {
+        let mut cmd = Command::new(program);
+        cmd.args(args);
+        cmd
+    }
+    let formatter = |dir: &str| -> Result<Command> {
+        let fmt = cfg.formatter.as_deref().ok_or_else(|| {
+            NegmaError::new("No formatter configured").hint("Set 'formatter' in negma config")
+        })?;
+        Ok(command(fmt, [dir]))
+    };
+
+    let scope = match args.get(2).map(String::as_str) {
+        Some("n") => "nix",
+        Some("h") => "home",
+        Some(scope) => scope,
+        None => {
+            return Err(NegmaError::new("Missing command to explain").hint("Use e.g. negma explain nix make"));
         }
-        "fmt" => {
-            if let Some(fmt) = &cfg.formatter {
-                let config_path = "/etc/nixos";
-                println!("{} Formatting {}...", "[negma]".green().bold(), config_path.bright_black());
-                let status = Command::new(fmt)
-                    .arg(config_path)
-                    .stdin(Stdio::inherit())
-                    .stdout(Stdio::inherit())
-                    .stderr(Stdio::inherit())
-                    .status();
-                exit_if_fail(status, "Failed to format NixOS configuration");
-            } else {
-                print_error("No formatter configured", None, Some("Set 'formatter' in negma config"));
-            }
-        }
-        "gc" => run_nix_env(vec!["collect-garbage", "-d"]),
-        "make" => {
-    println!("{} Running nixos-rebuild switch...", "[negma]".green().bold());
-    let status = Command::new("nixos-rebuild")
-        .arg("switch")
-        .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .status();
-    exit_if_fail(status, "nixos-rebuild switch failed");
-},
-
-        "list-generations" => run_nix_env(vec!["--profile", "/nix/var/nix/profiles/system", "--list-generations"]),
-        "rollback" => {
-            if args.len() > 3 {
-                run_nix_env(vec![
-                    "--profile",
-                    "/nix/var/nix/profiles/system",
-                    "--switch-generation",
-                    &args[3],
-                ]);
-            } else {
-                run_nix_env(vec![
-                    "--profile",
-                    "/nix/var/nix/profiles/system",
-                    "--rollback",
-                ]);
-            }
+    };
+    let (description, commands) = match (scope, args.get(3).map(String::as_str)) {
+        ("nix", Some(action @ ("make" | "switch" | "boot" | "test"))) => {
+            let flags = match action {
+                "make" | "switch" => cfg.with_command_flags("nix make", cfg.rebuild_args()),
+                _ => cfg.with_command_flags(&format!("nix {}", action), cfg.rebuild_args()),
+            };
+            let action = if action == "make" { cfg.make_mode.as_str() } else { action };
+            let rebuild = command("nixos-rebuild", std::iter::once(action.to_string()).chain(flags));
+            ("build the system configuration and activate it", vec![rebuild])
         }
-        "clean" => run_nix_env(vec![
-            "--profile",
-            "/nix/var/nix/profiles/system",
-            "--delete-generations",
-            "old",
-        ]),
+        ("nix", Some("build")) => {
+            let flags = cfg.with_command_flags("nix build", cfg.rebuild_args());
+            let mut rebuild = command("nixos-rebuild", std::iter::once("build".to_string()).chain(flags));
+            rebuild.args(["--out-link", flag_value(args, "--out-link").unwrap_or("result")]);
+            ("build the system configuration without activating it", vec![rebuild])
+        }
+        ("nix", Some("check")) => {
+            let mut check = match &cfg.system_flake {
+                Some(flake) => {
+                    let mut check = command("nix", ["--extra-experimental-features", "nix-command flakes"]);
+                    check
+                        .args(["flake", "check", flake.split('#').next().unwrap_or(flake)])
+                        .args((cfg.impure || cfg.needs_impure_env()).then_some("--impure"))
+                        .args(cfg.accept_flake_config.then_some("--accept-flake-config"));
+                    check
+                }
+                None => command("nix-instantiate", ["<nixpkgs/nixos>", "-A", "system"]),
+            };
+            check.args(cfg.with_command_flags("nix check", Vec::new()));
+            ("evaluate the system configuration without activating it", vec![check])
+        }
+        ("nix", Some("gc")) => (
+            "delete old system generations and collect garbage",
+            vec![command(
+                "nix-env",
+                ["collect-garbage", "-d"].map(String::from).into_iter().chain(cfg.gc_options()),
+            )],
+        ),
+        ("nix", Some("clean")) => (
+            "delete every system generation but the current one",
+            vec![command("nix-env", ["--profile", SYSTEM_PROFILE, "--delete-generations", "old"])],
+        ),
+        ("nix", Some("rollback")) => (
+            "switch back to the previous system generation",
+            vec![command("nix-env", ["--profile", SYSTEM_PROFILE, "--rollback"])],
+        ),
+        ("nix", Some("fmt")) => ("format the NixOS configuration", vec![formatter(cfg.nixos_config_dir())?]),
+        ("home", Some("make" | "switch")) => (
+            "build the Home Manager configuration and activate it",
+            vec![command(
+                "home-manager",
+                std::iter::once("switch".to_string()).chain(cfg.with_command_flags("home make", cfg.home_args())),
+            )],
+        ),
+        ("home", Some("check")) => (
+            "build the Home Manager configuration without activating it",
+            vec![command(
+                "home-manager",
+                std::iter::once("build".to_string()).chain(cfg.with_command_flags("home check", Vec::new())),
+            )],
+        ),
+        ("home", Some("gc")) => (
+            "expire old Home Manager generations",
+            vec![command("home-manager", ["expire-generations", "-d"])],
+        ),
+        ("home", Some("news")) => ("read Home Manager news", vec![command("home-manager", ["news"])]),
+        ("home", Some("fmt")) => (
+            "format the Home Manager configuration",
+            vec![formatter(&cfg.home_config_dir(home_dir))?],
+        ),
+        ("gc", _) => (
+            "expire Home Manager generations, then collect system garbage (as root)",
+            vec![
+                command("home-manager", ["expire-generations", "-d"]),
+                command("nix-collect-garbage", std::iter::once("-d".to_string()).chain(cfg.gc_options())),
+            ],
+        ),
         _ => {
-            print_error(
-                &format!("Unknown nix subcommand '{}'", args[2]),
-                None,
-                Some("Run 'negma' for available subcommands"),
-            );
+            return Err(NegmaError::new(format!("Nothing to explain for '{}'", args[2..].join(" "))).hint(
+                "Explainable: nix make|switch|boot|test|build|check|gc|clean|rollback|fmt, \
+                 home make|switch|check|gc|news|fmt, gc",
+            ));
         }
+    };
+
+    let shown = args[2..].iter().take(2).cloned().collect::<Vec<_>>().join(" ");
+    println!(
+        "{} {} — {}",
+        theme::style_header("[negma]"),
+        theme::style_accent(format!("negma {}", shown)).bold(),
+        description
+    );
+    for mut cmd in commands {
+        cmd.envs(cfg.envs());
+        println!("  {} {}", theme::style_dim("$"), theme::style_value(describe(&cmd)));
     }
+    Ok(())
 }
 
-fn run_nix_env(args: Vec<&str>) {
-    println!("{} Running nix-env {}...", "[negma]".green().bold(), args.join(" ").bright_black());
-    let status = Command::new("nix-env")
-        .args(args)
-        .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .status();
-    exit_if_fail(status, "nix-env command failed");
+/// One-glance summary of everything negma manages; read-only and works without sudo.
+fn handle_status(cfg: &CFG, home_dir: &str) -> Result<()> {
+    let generation = |profile: &Path| {
+        generations::current_generation(profile).map_or("unknown".to_string(), |g| g.to_string())
+    };
+    let changes = |dir: &Path| match git_changes(dir) {
+        None => theme::style_dim("not a git repo"),
+        Some(c) if c.trim().is_empty() => theme::style_header("clean"),
+        Some(c) => theme::style_warning(format!("{} uncommitted change(s)", c.lines().count())),
+    };
+    let last_gc = fs::metadata(auto_gc_marker(home_dir))
+        .map(|m| {
+            let at = SystemTime::UNIX_EPOCH + Duration::from_secs(m.ctime() as u64);
+            chrono::DateTime::<chrono::Local>::from(at).format("%Y-%m-%d %H:%M").to_string()
+        })
+        .unwrap_or_else(|_| "never".to_string());
+    let mode = match &cfg.system_flake {
+        Some(flake) => format!("flake ({})", flake),
+        None => "channels".to_string(),
+    };
+    let home_config_dir = cfg.home_config_dir(home_dir);
+
+    println!("\n{}", theme::style_title("System"));
+    println!("  {:<12} {}", "generation", theme::style_accent(generation(Path::new(SYSTEM_PROFILE))));
+    println!("  {:<12} {}", "config", changes(Path::new(cfg.nixos_config_dir())));
+    println!("  {:<12} {}", "mode", mode);
+    println!("\n{}", theme::style_title("Home Manager"));
+    println!(
+        "  {:<12} {}",
+        "generation",
+        theme::style_accent(generation(&generations::home_profile(home_dir)))
+    );
+    println!("  {:<12} {}", "config", changes(Path::new(&home_config_dir)));
+    println!("\n{}", theme::style_title("Maintenance"));
+    println!(
+        "  {:<12} {}{}",
+        "last auto GC",
+        last_gc,
+        if cfg.auto_gc { String::new() } else { theme::style_dim(" (AUTO_GC off)").to_string() }
+    );
+    Ok(())
 }
 
-fn exit_if_fail(status: Result<std::process::ExitStatus, std::io::Error>, msg: &str) {
-    match status {
-        Ok(s) if !s.success() => {
-            eprintln!("{} {}", "[error]".red().bold(), msg.bright_white());
-            exit(1);
-        }
-        Err(e) => {
-            eprintln!(
-                "{} {} ({})",
-                "[error]".red().bold(),
-                msg.bright_white(),
-                e.to_string().bright_black()
+fn handle_history(args: &[String], cfg: &CFG, home_dir: &str) -> Result<()> {
+    let flags = &args[2..];
+
+    if flags.iter().any(|a| a == "--clear") {
+        if cfg.dry_run {
+            println!(
+                "{} remove {}",
+                theme::style_warning("[dry-run]"),
+                theme::style_dim(history::log_path(home_dir).display().to_string())
             );
-            exit(1);
+            return Ok(());
         }
-        _ => {}
+        history::clear(home_dir)
+            .map_err(|e| NegmaError::new("Failed to clear history").details(e.to_string()))?;
+        println!("{} History cleared.", theme::style_header("[negma]"));
+        return Ok(());
     }
-}
 
-fn print_error(title: &str, details: Option<&str>, hint: Option<&str>) {
-    eprintln!("{} {}", "[negma error]".red().bold(), title.bright_white());
-    if let Some(d) = details {
-        eprintln!("{} {}", "↳".red(), d.bright_black());
+    let limit = if flags.iter().any(|a| a == "--all") {
+        None
+    } else if let Some(n) = flags.iter().find(|a| !a.starts_with("--")) {
+        match n.parse::<usize>() {
+            Ok(n) => Some(n),
+            Err(_) => {
+                return Err(NegmaError::new(format!("Invalid history count '{}'", n))
+                    .hint("Use: negma history [N] [--all] [--clear]"));
+            }
+        }
+    } else {
+        Some(20)
+    };
+
+    let entries = history::read(home_dir);
+    if entries.is_empty() {
+        println!("{} No recorded operations yet.", theme::style_header("[negma]"));
+        return Ok(());
     }
-    if let Some(h) = hint {
-        eprintln!("{} {}", "hint:".yellow().bold(), h.bright_white());
+
+    let skip = limit.map_or(0, |n| entries.len().saturating_sub(n));
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{} Showing {} of {} recorded operations:",
+        theme::style_header("[negma]"),
+        entries.len() - skip,
+        entries.len()
+    );
+    for entry in &entries[skip..] {
+        let status = if entry.status == 0 {
+            theme::style_header("ok")
+        } else {
+            theme::style_error(format!("exit {}", entry.status))
+        };
+        let _ = writeln!(
+            out,
+            "  {}  {:>7}  {}",
+            theme::style_dim(&entry.timestamp),
+            status,
+            theme::style_value(&entry.command)
+        );
     }
+    pager::show(cfg.use_pager.then_some(cfg.pager.as_str()), &out);
+    Ok(())
 }
 
-fn print_help() {
-    println!("\n{}\n{}", "[negma]".blue().bold(), "A clean, practical NixOS & Home Manager CLI helper.".bright_white());
-    println!("\n{} {}", "Usage:".bright_white().underline(), "negma <command> [subcommand] [args]".bright_yellow());
-    println!("\n{}", "Commands:".bright_white().underline());
-    println!("  {} {}", "home".bright_cyan().bold(), "<subcommand>".bright_white());
-    println!("  {} {}", "nix".bright_cyan().bold(), "<subcommand>".bright_white());
-    println!("  {}", "edit-cfg".bright_cyan().bold());
+fn handle_home(args: &[String], cfg: &CFG, home_dir: &str) -> Result<()> {
+    if args.len() < 3 {
+        return Err(NegmaError::new("Missing subcommand for 'home'")
+            .hint("Run 'negma' to see available home subcommands"));
+    }
+
+    let home_config_dir = cfg.home_config_dir(home_dir);
+    if matches!(args[2].as_str(), "edit" | "fmt" | "backup") {
+        require_config_dir(&home_config_dir, cfg.home_manager_dir.is_some(), "HOME_MANAGER_DIR")?;
+    }
+
+    match args[2].as_str() {
+        "edit" => {
+            println!("{} Editing {}...", theme::style_header("[negma]"), theme::style_dim(&home_config_dir));
+            run_passthrough(
+                Command::new(&cfg.editor).args(cfg.editor_args()).arg(format!("{}/home.nix", home_config_dir)),
+                "Editing home-manager config failed",
+            )?;
+
+            if cfg.auto_fmt && let Some(fmt) = &cfg.formatter {
+                println!("{} Formatting {}...", theme::style_header("[negma]"), theme::style_dim(&home_config_dir));
+                run_formatter(cfg, fmt, &home_config_dir, "Formatting home-manager config failed")?;
+            }
+        }
+        "fmt" => {
+            if let Some(fmt) = &cfg.formatter {
+                println!("{} Formatting {}...", theme::style_header("[negma]"), theme::style_dim(&home_config_dir));
+                let snapshot = run_formatter(cfg, fmt, &home_config_dir, "Formatting home-manager config failed")?;
+                print_format_summary(cfg, args, &snapshot);
+            } else {
+                return Err(NegmaError::new("No formatter configured").hint("Set 'formatter' in negma config"));
+            }
+        }
+        "make" | "switch" if cfg.home_manager_module() => {
+            // Nothing ran, so there is nothing to record or roll back.
+            history::cancel();
+            println!(
+                "{} Home Manager is installed as a NixOS module, so the system rebuild applies it.",
+                theme::style_header("[negma]")
+            );
+            println!(
+                "{} Run {} to apply your home configuration (HOME_MANAGER_MODE = {}).",
+                theme::style_warning("hint:"),
+                theme::style_accent("negma nix make"),
+                cfg.home_manager_mode
+            );
+        }
+        "make" | "switch" => {
+            let mut switch = Command::new("home-manager");
+            switch.arg("switch").args(cfg.with_command_flags("home make", cfg.home_args()));
+            // Root rebuilding another user's home: run as them, against their config and profile.
+            let target_user = flag_value(args, "--target-user").map(|name| target_user(cfg, name)).transpose()?;
+            let (user_home, home_config_dir) = match &target_user {
+                Some(user) => {
+                    as_user(&mut switch, user);
+                    let user_home = user.dir.display().to_string();
+                    let config_dir = cfg.home_config_dir(&user_home);
+                    (user_home, config_dir)
+                }
+                None => (home_dir.to_string(), home_config_dir),
+            };
+            ensure_clean_git(cfg, Path::new(&home_config_dir), args)?;
+            pre_check(cfg, Path::new(&home_config_dir))?;
+            if args.iter().any(|a| a == "--backup") {
+                backup_home_config(cfg, &home_config_dir)?;
+            }
+            history::set_previous_generation(generations::current_generation(
+                &generations::home_profile(&user_home),
+            ));
+            require_tool(cfg, "home-manager")?;
+            match &target_user {
+                Some(user) => println!(
+                    "{} Applying home-manager switch for {}...",
+                    theme::style_header("[negma]"),
+                    theme::style_value(&user.name)
+                ),
+                None => println!("{} Applying home-manager switch...", theme::style_header("[negma]")),
+            }
+            let stderr = run_command_retrying(cfg, home_dir, &mut switch, "home-manager switch failed")?;
+            if let Some(count) = unread_news(&stderr) {
+                println!(
+                    "{} {} unread news item{} — run {}",
+                    theme::style_warning("[negma]"),
+                    count,
+                    if count == 1 { "" } else { "s" },
+                    theme::style_accent("negma home news")
+                );
+            }
+        }
+        "news" => run_passthrough(Command::new("home-manager").arg("news"), "home-manager news failed")?,
+        "check" => {
+            // `home-manager build` drops a `result` link in the working directory,
+            // so build from a scratch dir that is removed afterwards.
+            let scratch = env::temp_dir().join(format!("negma-home-check-{}", std::process::id()));
+            fs::create_dir_all(&scratch).map_err(|e| {
+                NegmaError::new("Failed to create scratch directory").details(e.to_string())
+            })?;
+
+            println!("{} Evaluating home-manager configuration...", theme::style_header("[negma]"));
+            let status = interrupt::status(
+                Command::new("home-manager")
+                    .arg("build")
+                    .args(cfg.with_command_flags("home check", Vec::new()))
+                    .envs(cfg.envs())
+                    .current_dir(&scratch)
+                    .stdin(Stdio::inherit())
+                    .stdout(Stdio::inherit())
+                    .stderr(Stdio::inherit()),
+            );
+            let _ = fs::remove_dir_all(&scratch);
+
+            match status {
+                Ok(s) if s.success() => {
+                    println!("{} Home configuration builds cleanly.", theme::style_header("[negma]"));
+                }
+                Ok(s) => {
+                    return Err(NegmaError::new("home-manager configuration failed to build")
+                        .details(format!("Code: {}", s))
+                        .hint("Fix the error reported above, then re-run: negma home check"));
+                }
+                Err(e) => {
+                    return Err(NegmaError::new("Failed to run home-manager build").details(e.to_string()));
+                }
+            }
+        }
+        "gc" => {
+            println!("{} Expiring old home-manager generations...", theme::style_header("[negma]"));
+            run_command(
+                cfg,
+                Command::new("home-manager").arg("expire-generations").arg("-d"),
+                "home-manager expire-generations failed",
+            )?;
+        }
+        "clean" => {
+            let keep = match flag_value(args, "--keep") {
+                Some(n) => n.parse::<usize>().map_err(|_| {
+                    NegmaError::new(format!("Invalid --keep value '{}'", n))
+                        .hint("Use a non-negative integer, e.g. --keep 3")
+                })?,
+                None => cfg.keep as usize,
+            };
+            let gens = generations::list_home().map_err(|e| {
+                NegmaError::new("Failed to list home-manager generations").details(e.to_string())
+            })?;
+            let doomed = generations::to_delete(&gens, keep);
+            if doomed.is_empty() {
+                println!(
+                    "{} Nothing to clean: at most {} generation(s) besides the current one.",
+                    theme::style_header("[negma]"),
+                    keep
+                );
+                return Ok(());
+            }
+
+            println!(
+                "{} Removing {} old Home Manager generation(s), keeping current + {}...",
+                theme::style_header("[negma]"),
+                doomed.len(),
+                keep
+            );
+            run_command(
+                cfg,
+                Command::new("home-manager")
+                    .arg("remove-generations")
+                    .args(doomed.iter().map(u32::to_string)),
+                "home-manager clean failed",
+            )?;
+        }
+        "backup" => backup_home_config(cfg, &home_config_dir)?,
+        "list-generations" => list_generations(args, cfg, home_dir, true)?,
+        "diff" => {
+            let profile = generations::home_profile(home_dir);
+            let current = generations::current_generation(&profile)
+                .ok_or_else(|| NegmaError::new("No current home-manager generation"));
+            let parse = |n: &String| {
+                n.parse::<u32>().map_err(|_| {
+                    NegmaError::new(format!("Invalid generation '{}'", n))
+                        .hint("Use: negma home diff [<a> [<b>]]")
+                })
+            };
+            let (from, to) = match (args.get(3), args.get(4)) {
+                (Some(a), Some(b)) => (parse(a)?, parse(b)?),
+                (Some(a), None) => (parse(a)?, current?),
+                (None, _) => {
+                    let current = current?;
+                    let previous = generations::generation_numbers(&profile)
+                        .into_iter()
+                        .rfind(|&n| n < current)
+                        .ok_or_else(|| {
+                            NegmaError::new("No previous home-manager generation to compare against")
+                        })?;
+                    (previous, current)
+                }
+            };
+
+            let [from_link, to_link] = [from, to].map(|n| generations::generation_link(&profile, n));
+            for (n, link) in [(from, &from_link), (to, &to_link)] {
+                if !link.exists() {
+                    return Err(NegmaError::new(format!("Home-manager generation {} not found", n))
+                        .details(link.display().to_string())
+                        .hint("Run 'negma home list-generations' to see available generations"));
+                }
+            }
+
+            println!(
+                "{} Comparing home-manager generations {} → {}:",
+                theme::style_header("[negma]"),
+                from,
+                to
+            );
+            run_passthrough(
+                Command::new("nix")
+                    .args(["--extra-experimental-features", "nix-command"])
+                    .args(["store", "diff-closures"])
+                    .arg(&from_link)
+                    .arg(&to_link),
+                "nix store diff-closures failed",
+            )?;
+        }
+        "rollback" if args.iter().any(|a| a == "--steps" || a.starts_with("--steps=")) => {
+            let gens = generations::list_home().map_err(|e| {
+                NegmaError::new("Failed to list home-manager generations").details(e.to_string())
+            })?;
+            let target = generation_steps_back(&gens, flag_value(args, "--steps"))?;
+            let Some(path) = &target.path else {
+                return Err(NegmaError::new(format!("No store path listed for generation {}", target.number)));
+            };
+            println!(
+                "{} Rolling back home-manager to generation {}...",
+                theme::style_header("[negma]"),
+                target.number
+            );
+            let activate = Path::new(path).join("activate");
+            run_command(cfg, &mut Command::new(&activate), "home-manager rollback failed")?;
+        }
+        "rollback" if args.get(3).is_some_and(|a| a == "--last") => {
+            let profile = generations::home_profile(home_dir);
+            let Some(last) = history::last_make(home_dir, "home") else {
+                ensure_older_generation(generations::list_home(), "home")?;
+                println!(
+                    "{} No home make recorded in history, rolling back to the previous generation...",
+                    theme::style_warning("[negma]")
+                );
+                return run_command(
+                    cfg,
+                    Command::new("home-manager").args(["switch", "--rollback"]),
+                    "home-manager rollback failed",
+                );
+            };
+            let r#gen = generation_before(&last, "home")?;
+
+            println!(
+                "{} Rolling back home-manager to generation {} (before last make)...",
+                theme::style_header("[negma]"),
+                r#gen
+            );
+            let activate = generations::generation_link(&profile, r#gen).join("activate");
+            run_command(cfg, &mut Command::new(&activate), "home-manager rollback failed")?;
+        }
+        "rollback" => {
+            if args.len() <= 3 {
+                ensure_older_generation(generations::list_home(), "home")?;
+            }
+            let r#gen = if args.len() > 3 { &args[3] } else { "--rollback" };
+            println!("{} Rolling back home-manager...", theme::style_header("[negma]"));
+            run_command(
+                cfg,
+                Command::new("home-manager").args(["switch", r#gen]),
+                "home-manager rollback failed",
+            )?;
+        }
+        _ => {
+            return Err(NegmaError::new(format!("Unknown home subcommand '{}'", args[2]))
+                .hint("Run 'negma' for available subcommands"));
+        }
+    }
+    Ok(())
+}
+
+/// Number of unread news items from home-manager's "There are N unread and relevant news
+/// items" notice, which is easy to lose in the scrollback of a long switch.
+fn unread_news(output: &str) -> Option<u32> {
+    output.lines().find_map(|line| {
+        let rest = line.split_once("There are ").or(line.split_once("There is "))?.1;
+        let (count, rest) = rest.split_once(' ')?;
+        rest.starts_with("unread").then_some(())?;
+        count.parse().ok().filter(|&n| n > 0)
+    })
+}
+
+/// Copies `home.nix` to `home.nix.bak`, for `home backup` and `home make --backup`.
+fn backup_home_config(cfg: &CFG, home_config_dir: &str) -> Result<()> {
+    let config_path = format!("{}/home.nix", home_config_dir);
+    let backup_path = format!("{}/home.nix.bak", home_config_dir);
+
+    if cfg.dry_run {
+        println!(
+            "{} copy {} -> {}",
+            theme::style_warning("[dry-run]"),
+            theme::style_dim(&config_path),
+            theme::style_dim(&backup_path)
+        );
+        return Ok(());
+    }
+
+    fs::copy(&config_path, &backup_path)
+        .map_err(|e| NegmaError::new("Failed to backup home.nix").details(e.to_string()))?;
+    println!(
+        "{} Backup created: {}",
+        theme::style_header("[negma]"),
+        theme::style_dim(&backup_path)
+    );
+    Ok(())
+}
+
+fn handle_nix(args: &[String], cfg: &CFG, home_dir: &str) -> Result<()> {
+    if args.len() < 3 {
+        return Err(NegmaError::new("Missing subcommand for 'nix'")
+            .hint("Run 'negma' to see available nix subcommands"));
+    }
+
+    if cfg.accept_flake_config
+        && cfg.system_flake.is_none()
+        && matches!(args[2].as_str(), "make" | "boot" | "test" | "build" | "check")
+    {
+        eprintln!(
+            "{} --accept-flake-config ignored: SYSTEM_FLAKE is not set (channel mode)",
+            theme::style_warning("[negma warning]")
+        );
+    }
+    if cfg.no_update_lockfile
+        && cfg.system_flake.is_none()
+        && matches!(args[2].as_str(), "make" | "switch" | "boot" | "test" | "build")
+    {
+        eprintln!(
+            "{} --no-update-lockfile ignored: SYSTEM_FLAKE is not set (channel mode)",
+            theme::style_warning("[negma warning]")
+        );
+    }
+    if cfg.allow_dirty
+        && cfg.system_flake.is_none()
+        && matches!(args[2].as_str(), "make" | "switch" | "boot" | "test" | "build")
+    {
+        eprintln!(
+            "{} --dirty ignored: SYSTEM_FLAKE is not set (channel mode)",
+            theme::style_warning("[negma warning]")
+        );
+    }
+    if cfg.use_remote_sudo
+        && cfg.system_flake.is_none()
+        && matches!(args[2].as_str(), "make" | "switch" | "boot" | "test")
+    {
+        eprintln!(
+            "{} --use-remote-sudo ignored: SYSTEM_FLAKE is not set (channel mode)",
+            theme::style_warning("[negma warning]")
+        );
+    }
+    if (cfg.build_host.is_some() || cfg.target_host.is_some())
+        && matches!(args[2].as_str(), "make" | "switch" | "boot" | "test" | "build")
+    {
+        check_remote_hosts(cfg, args)?;
+    }
+    if cfg.builders_use_substitutes
+        && cfg.builders.is_none()
+        && matches!(args[2].as_str(), "make" | "switch" | "boot" | "test" | "build" | "upgrade")
+    {
+        eprintln!(
+            "{} builders-use-substitutes ignored: no remote builders (set BUILDERS or pass --builders)",
+            theme::style_warning("[negma warning]")
+        );
+    }
+
+    let nixos_dir = cfg.nixos_config_dir();
+    if matches!(args[2].as_str(), "edit" | "fmt") {
+        require_config_dir(nixos_dir, cfg.nixos_dir.is_some(), "NIXOS_DIR")?;
+    }
+
+    match args[2].as_str() {
+        "edit" => {
+            let config_path = format!("{}/configuration.nix", nixos_dir);
+            println!("{} Editing {}...", theme::style_header("[negma]"), theme::style_dim(&config_path));
+            run_passthrough(
+                Command::new(&cfg.editor).args(cfg.editor_args()).arg(&config_path),
+                "Failed to edit NixOS configuration",
+            )?;
+
+            if cfg.auto_fmt && let Some(fmt) = &cfg.formatter {
+                println!("{} Formatting {}...", theme::style_header("[negma]"), theme::style_dim(&config_path));
+                run_formatter(cfg, fmt, &config_path, "Failed to format NixOS configuration")?;
+            }
+        }
+        "fmt" => {
+            if let Some(fmt) = &cfg.formatter {
+                let config_path = nixos_dir;
+                println!("{} Formatting {}...", theme::style_header("[negma]"), theme::style_dim(config_path));
+                let snapshot = run_formatter(cfg, fmt, config_path, "Failed to format NixOS configuration")?;
+                print_format_summary(cfg, args, &snapshot);
+            } else {
+                return Err(NegmaError::new("No formatter configured").hint("Set 'formatter' in negma config"));
+            }
+        }
+        "gc" => {
+            let mut opts = cfg.gc_options();
+            for (flag, name) in [("--keep-outputs", "keep-outputs"), ("--keep-derivations", "keep-derivations")] {
+                if args.iter().any(|a| a == flag) && !opts.iter().any(|o| o == name) {
+                    opts.extend(["--option".to_string(), name.to_string(), "true".to_string()]);
+                }
+            }
+            if args.iter().any(|a| a == "--max-freed" || a.starts_with("--max-freed=")) {
+                let size = flag_value(args, "--max-freed").unwrap_or_default();
+                let bytes = optimise::parse_size(size).ok_or_else(|| {
+                    NegmaError::new(format!("Invalid --max-freed value '{}'", size))
+                        .hint("Use a size such as 500M, 5G or 1T")
+                })?;
+                // Stops once enough is freed, so old generations are kept rather than deleted.
+                println!(
+                    "{} Collecting garbage until {} is freed...",
+                    theme::style_header("[negma]"),
+                    theme::style_value(optimise::human_size(bytes))
+                );
+                run_command(
+                    cfg,
+                    Command::new("nix-collect-garbage").args(["--max-freed", &bytes.to_string()]).args(&opts),
+                    "nix-collect-garbage failed",
+                )?;
+            } else {
+                let mut gc_args = vec!["collect-garbage", "-d"];
+                gc_args.extend(opts.iter().map(String::as_str));
+                run_nix_env(cfg, gc_args)?;
+            }
+        }
+        "make" | "switch" | "boot" | "test" => {
+            let action = if args[2] == "make" { cfg.make_mode.as_str() } else { args[2].as_str() };
+            let label = flag_value(args, "--label");
+            if label.is_some() && action == "test" {
+                return Err(NegmaError::new("--label needs a new generation, but 'test' does not create one")
+                    .hint("Use switch or boot to label the generation"));
+            }
+            if label.is_some() && cfg.target_profile.is_some() {
+                return Err(NegmaError::new("--label only applies to the main system profile"));
+            }
+            let reboot = args.iter().any(|a| a == "--reboot");
+            if reboot && action != "boot" {
+                return Err(NegmaError::new(format!("--reboot only applies to 'boot', not '{}'", action))
+                    .hint("Use: negma nix boot --reboot"));
+            }
+            if reboot && cfg.dry_run {
+                return Err(NegmaError::new("--reboot cannot be combined with --dry-run")
+                    .hint("Preview the build with 'negma --dry-run nix boot', then reboot yourself"));
+            }
+
+            ensure_clean_git(cfg, Path::new(nixos_dir), args)?;
+            pre_check(cfg, Path::new(nixos_dir))?;
+            let command = if args[2] == "switch" { "nix make" } else { &format!("nix {}", args[2]) };
+            print_make_summary(cfg, command, action);
+            let ask = (cfg.confirm_make || args.iter().any(|a| a == "--confirm")) && !cfg.dry_run;
+            if ask && !args.iter().any(|a| a == "--yes" || a == "-y") && !confirm("Proceed?") {
+                history::cancel();
+                println!("{} Cancelled; nothing was built.", theme::style_warning("[negma]"));
+                return Ok(());
+            }
+            let stderr = rebuild(cfg, home_dir, command, action)?;
+            if args.iter().any(|a| a == "--report") && !cfg.dry_run {
+                print_activation_report(action, &activation::parse(&stderr));
+            }
+            if let Some(label) = label
+                && !cfg.dry_run
+            {
+                match generations::current_generation(&cfg.system_profile()) {
+                    Some(r#gen) => {
+                        labels::set(home_dir, r#gen, label).map_err(|e| {
+                            NegmaError::new("Failed to save the generation label").details(e.to_string())
+                        })?;
+                        println!("{} Labeled generation {} \"{}\".", theme::style_header("[negma]"), r#gen, label);
+                    }
+                    None => eprintln!(
+                        "{} Could not determine the new generation; label not saved",
+                        theme::style_warning("[negma warning]")
+                    ),
+                }
+            }
+            if reboot {
+                let yes = args.iter().any(|a| a == "--yes" || a == "-y");
+                if !yes && !confirm("The new generation is staged. Reboot into it now?") {
+                    println!(
+                        "{} Not rebooting; the new generation is used on the next boot.",
+                        theme::style_warning("[negma]")
+                    );
+                    return Ok(());
+                }
+                println!("{} Rebooting...", theme::style_header("[negma]"));
+                run_command(cfg, Command::new("systemctl").arg("reboot"), "systemctl reboot failed")?;
+            }
+        }
+
+        "build" => {
+            let out_link = flag_value(args, "--out-link").unwrap_or("result");
+            // With --json, stdout carries only the JSON result; progress goes to stderr.
+            let json = args.iter().any(|a| a == "--json");
+            if json {
+                eprintln!("{} Running nixos-rebuild build...", theme::style_header("[negma]"));
+            } else {
+                println!("{} Running nixos-rebuild build...", theme::style_header("[negma]"));
+            }
+            let mut rebuild = Command::new("nixos-rebuild");
+            rebuild
+                .arg("build")
+                .args(cfg.with_command_flags("nix build", cfg.rebuild_args()))
+                .args(["--out-link", out_link]);
+            if json {
+                rebuild.stdout(io::stderr());
+            }
+            let stderr = run_command_retrying(cfg, home_dir, &mut rebuild, "nixos-rebuild build failed")?;
+            summarize_dirty_tree(&stderr);
+            if json && !cfg.dry_run {
+                let out = fs::read_link(out_link).map_err(|e| {
+                    NegmaError::new(format!("Build finished but '{}' is not a result link", out_link))
+                        .details(e.to_string())
+                })?;
+                println!("{}", serde_json::json!({ "out": out }));
+            } else if !cfg.dry_run {
+                let link = env::current_dir().map(|d| d.join(out_link)).unwrap_or_else(|_| out_link.into());
+                let target = fs::read_link(&link)
+                    .map(|t| format!(" -> {}", t.display()))
+                    .unwrap_or_default();
+                println!(
+                    "{} Build result: {}{}",
+                    theme::style_header("[negma]"),
+                    theme::style_value(link.display().to_string()),
+                    theme::style_dim(&target)
+                );
+            }
+        }
+        "upgrade" => {
+            let flake_dir = match &cfg.system_flake {
+                Some(flake) => {
+                    let path = flake.split('#').next().unwrap_or(flake);
+                    if !(path.starts_with('/') || path.starts_with('.')) {
+                        return Err(NegmaError::new("Only a local SYSTEM_FLAKE can be upgraded")
+                            .details(format!("SYSTEM_FLAKE is '{}'", flake))
+                            .hint("Point SYSTEM_FLAKE at a checkout, e.g. /etc/nixos#myhost"));
+                    }
+                    Some(path)
+                }
+                None => None,
+            };
+
+            if args.iter().any(|a| a == "--plan") {
+                upgrade_plan(cfg, flake_dir)?;
+                if !args.iter().any(|a| a == "--yes" || a == "-y") && !confirm("Apply this upgrade?") {
+                    println!("{} Upgrade cancelled.", theme::style_warning("[negma]"));
+                    return Ok(());
+                }
+            }
+
+            match flake_dir {
+                Some(dir) => {
+                    println!("{} Updating flake inputs in {}...", theme::style_header("[negma]"), theme::style_dim(dir));
+                    run_command(
+                        cfg,
+                        Command::new("nix")
+                            .args(["--extra-experimental-features", "nix-command flakes"])
+                            .args(["flake", "update"])
+                            .current_dir(dir),
+                        "nix flake update failed",
+                    )?;
+                }
+                None => {
+                    println!("{} Updating channels...", theme::style_header("[negma]"));
+                    run_command(cfg, Command::new("nix-channel").arg("--update"), "nix-channel --update failed")?;
+                }
+            }
+            rebuild(cfg, home_dir, "nix make", &cfg.make_mode)?;
+        }
+        "list-generations" => list_generations(args, cfg, home_dir, false)?,
+        "gen-info" => {
+            let Some(number) = args.get(3).and_then(|n| n.parse::<u32>().ok()) else {
+                return Err(NegmaError::new("Missing or invalid generation number")
+                    .hint("Usage: negma nix gen-info <n> [--size]"));
+            };
+            let gens = generations::list_system(Path::new(SYSTEM_PROFILE)).map_err(|e| {
+                NegmaError::new("Failed to list system generations").details(e.to_string())
+            })?;
+            let Some(r#gen) = gens.iter().find(|g| g.number == number) else {
+                return Err(NegmaError::new(format!("Generation {} not found", number))
+                    .hint("List them with: negma nix list-generations"));
+            };
+            let path = generations::store_path(Path::new(SYSTEM_PROFILE), number);
+
+            println!(
+                "{} Generation {}{}",
+                theme::style_header("[negma]"),
+                theme::style_accent(number.to_string()).bold(),
+                if r#gen.current { " (current)" } else { "" }
+            );
+            let shown_path = path.as_ref().map_or("unknown".to_string(), |p| p.display().to_string());
+            println!("  {:<9}{}", "path:", theme::style_dim(shown_path));
+            println!("  {:<9}{}", "created:", theme::style_value(&r#gen.date));
+            if let Some(label) = labels::read(home_dir).remove(&number) {
+                println!("  {:<9}{}", "label:", theme::style_label(label));
+            }
+            if args.iter().any(|a| a == "--size") {
+                let size = match &path {
+                    Some(path) => generations::closure_size(path)
+                        .map(optimise::human_size)
+                        .unwrap_or_else(|e| format!("unknown ({})", e)),
+                    None => "unknown (no store path)".to_string(),
+                };
+                println!("  {:<9}{}", "closure:", theme::style_value(size));
+            }
+        }
+        "list-profiles" => {
+            let profiles = generations::list_profiles(Path::new(generations::PROFILES_DIR));
+            if profiles.is_empty() {
+                println!("{} No profiles found in {}.", theme::style_header("[negma]"), generations::PROFILES_DIR);
+                return Ok(());
+            }
+            println!("{} Profiles in {}:", theme::style_header("[negma]"), theme::style_dim(generations::PROFILES_DIR));
+            let width = profiles.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+            for (name, r#gen) in &profiles {
+                let r#gen = r#gen.map_or("?".to_string(), |g| g.to_string());
+                println!("  {:<width$}   generation {}", theme::style_accent(name), r#gen, width = width);
+            }
+        }
+        "rollback" if args.iter().any(|a| a == "--steps" || a.starts_with("--steps=")) => {
+            let gens = generations::list_system(Path::new(SYSTEM_PROFILE)).map_err(|e| {
+                NegmaError::new("Failed to list system generations").details(e.to_string())
+            })?;
+            let target = generation_steps_back(&gens, flag_value(args, "--steps"))?;
+            println!("{} Rolling back to generation {}...", theme::style_header("[negma]"), target.number);
+            run_nix_env(cfg, vec![
+                "--profile",
+                SYSTEM_PROFILE,
+                "--switch-generation",
+                &target.number.to_string(),
+            ])?;
+        }
+        "rollback" if args.get(3).is_some_and(|a| a == "--last") => {
+            match history::last_make(home_dir, "nix") {
+                Some(last) => {
+                    let r#gen = generation_before(&last, "nix")?;
+                    println!(
+                        "{} Rolling back to generation {} (before last make)...",
+                        theme::style_header("[negma]"),
+                        r#gen
+                    );
+                    run_nix_env(cfg, vec![
+                        "--profile",
+                        SYSTEM_PROFILE,
+                        "--switch-generation",
+                        &r#gen.to_string(),
+                    ])?;
+                }
+                None => {
+                    ensure_older_generation(generations::list_system(Path::new(SYSTEM_PROFILE)), "nix")?;
+                    println!(
+                        "{} No nix make recorded in history, rolling back to the previous generation...",
+                        theme::style_warning("[negma]")
+                    );
+                    run_nix_env(cfg, vec!["--profile", SYSTEM_PROFILE, "--rollback"])?;
+                }
+            }
+        }
+        "rollback" => {
+            if args.len() > 3 {
+                run_nix_env(cfg, vec![
+                    "--profile",
+                    "/nix/var/nix/profiles/system",
+                    "--switch-generation",
+                    &args[3],
+                ])?;
+            } else {
+                ensure_older_generation(generations::list_system(Path::new(SYSTEM_PROFILE)), "nix")?;
+                run_nix_env(cfg, vec![
+                    "--profile",
+                    "/nix/var/nix/profiles/system",
+                    "--rollback",
+                ])?;
+            }
+        }
+        "clean" => run_nix_env(cfg, vec![
+            "--profile",
+            "/nix/var/nix/profiles/system",
+            "--delete-generations",
+            "old",
+        ])?,
+        "check" => {
+            let status = match &cfg.system_flake {
+                Some(flake) => {
+                    let flake = flake.split('#').next().unwrap_or(flake);
+                    println!("{} Checking flake {}...", theme::style_header("[negma]"), theme::style_dim(flake));
+                    interrupt::status(
+                        Command::new("nix")
+                            .args(["--extra-experimental-features", "nix-command flakes"])
+                            .args(["flake", "check", flake])
+                            .args((cfg.impure || cfg.needs_impure_env()).then_some("--impure"))
+                            .args(cfg.accept_flake_config.then_some("--accept-flake-config"))
+                            .args(cfg.with_command_flags("nix check", Vec::new()))
+                            .envs(cfg.envs())
+                            .stdin(Stdio::inherit())
+                            .stdout(Stdio::inherit())
+                            .stderr(Stdio::inherit()),
+                    )
+                }
+                None => {
+                    println!("{} Evaluating NixOS configuration...", theme::style_header("[negma]"));
+                    interrupt::status(
+                        Command::new("nix-instantiate")
+                            .args(["<nixpkgs/nixos>", "-A", "system"])
+                            .args(cfg.with_command_flags("nix check", Vec::new()))
+                            .envs(cfg.envs())
+                            .stdin(Stdio::inherit())
+                            .stdout(Stdio::null())
+                            .stderr(Stdio::inherit()),
+                    )
+                }
+            };
+            match status {
+                Ok(s) if s.success() => {
+                    println!("{} Configuration evaluates cleanly.", theme::style_header("[negma]"));
+                }
+                Ok(s) => {
+                    return Err(NegmaError::new("NixOS configuration failed to evaluate")
+                        .details(format!("Code: {}", s))
+                        .hint(format!(
+                            "Fix the error reported above, then re-run: {} negma nix check",
+                            cfg.sudo_cmd
+                        )));
+                }
+                Err(e) => {
+                    return Err(NegmaError::new("Failed to run the evaluation").details(e.to_string()));
+                }
+            }
+        }
+        "optimise" if cfg.dry_run => {
+            // nix's own dry run leaves the store untouched, so it runs even under --dry-run.
+            println!(
+                "{} Checking what optimising /nix/store would free (this can take a while)...",
+                theme::style_header("[negma]")
+            );
+            let mut cmd = Command::new("nix-store");
+            cmd.args(["--optimise", "--dry-run"]).envs(cfg.envs());
+            let (status, stderr) = match interrupt::status_capturing_stderr(&mut cmd) {
+                Ok((status, stderr)) => (Ok(status), stderr),
+                Err(e) => (Err(e), String::new()),
+            };
+            check_status(status, "nix-store --optimise --dry-run failed", &describe(&cmd))?;
+            match optimise::parse_report(&stderr) {
+                Some(report) => println!(
+                    "{} Optimising would free about {} by hard-linking {} files.",
+                    theme::style_header("[negma]"),
+                    theme::style_value(optimise::human_size(report.bytes)).bold(),
+                    theme::style_value(report.files.to_string()).bold()
+                ),
+                None => println!(
+                    "{} nix-store did not report what optimising would free.",
+                    theme::style_warning("[negma]")
+                ),
+            }
+        }
+        "optimise" => {
+            println!("{} Hard-linking identical files in /nix/store...", theme::style_header("[negma]"));
+            run_command(cfg, Command::new("nix-store").arg("--optimise"), "nix-store --optimise failed")?;
+        }
+        "rebuild-log" => {
+            let drv = match args.get(3) {
+                Some(path) => path.clone(),
+                None => buildlog::last(home_dir).ok_or_else(|| {
+                    NegmaError::new("No failed build recorded")
+                        .hint(format!(
+                            "Pass a derivation or store path: {} negma nix rebuild-log <path>",
+                            cfg.sudo_cmd
+                        ))
+                })?,
+            };
+            println!("{} Build log for {}:", theme::style_header("[negma]"), theme::style_dim(&drv));
+            run_passthrough(
+                Command::new("nix")
+                    .args(["--extra-experimental-features", "nix-command"])
+                    .args(["log", &drv]),
+                "nix log failed",
+            )?;
+        }
+        "show-config" => {
+            let Some(attr) = args.get(3).map(|a| a.trim_start_matches("config.")) else {
+                return Err(NegmaError::new("Missing attribute for 'show-config'").hint(format!(
+                    "Use: {} negma nix show-config <attr>, e.g. services.openssh.enable",
+                    cfg.sudo_cmd
+                )));
+            };
+            let mut cmd = match &cfg.system_flake {
+                Some(flake) => {
+                    let (flake, host) = flake_and_host(flake)?;
+                    let mut cmd = Command::new("nix");
+                    cmd.args(["--extra-experimental-features", "nix-command flakes"])
+                        .arg("eval")
+                        .arg(format!("{}#nixosConfigurations.{}.config.{}", flake, host, attr))
+                        .args((cfg.impure || cfg.needs_impure_env()).then_some("--impure"))
+                        .args(cfg.accept_flake_config.then_some("--accept-flake-config"));
+                    cmd
+                }
+                None => {
+                    let mut cmd = Command::new("nix-instantiate");
+                    cmd.args(["--eval", "--strict", "-E"])
+                        .arg(format!("with import <nixpkgs/nixos> {{}}; config.{}", attr));
+                    cmd
+                }
+            };
+            run_passthrough(cmd.envs(cfg.envs()), &format!("Failed to evaluate config.{}", attr))?;
+        }
+        "why-depends" => {
+            let Some(pkg) = args.get(3) else {
+                return Err(NegmaError::new("Missing package for 'why-depends'")
+                    .hint(format!("Use: {} negma nix why-depends <pkg|store-path>", cfg.sudo_cmd)));
+            };
+            let target = resolve_closure_path(pkg).unwrap_or_else(|| pkg.clone());
+            println!(
+                "{} Why does the current system depend on {}?",
+                theme::style_header("[negma]"),
+                theme::style_dim(&target)
+            );
+            run_passthrough(
+                Command::new("nix")
+                    .args(["--extra-experimental-features", "nix-command"])
+                    .args(["why-depends", "/run/current-system", &target]),
+                "nix why-depends failed",
+            )?;
+        }
+        _ => {
+            return Err(NegmaError::new(format!("Unknown nix subcommand '{}'", args[2]))
+                .hint("Run 'negma' for available subcommands"));
+        }
+    }
+    Ok(())
+}
+
+/// Splits `SYSTEM_FLAKE` into the flake reference and the `nixosConfigurations` host,
+/// defaulting the host to this machine's hostname like `nixos-rebuild` does.
+fn flake_and_host(flake: &str) -> Result<(&str, String)> {
+    if let Some((flake, host)) = flake.split_once('#')
+        && !host.is_empty()
+    {
+        return Ok((flake, host.to_string()));
+    }
+    let flake = flake.trim_end_matches('#');
+    let host = hostname().ok_or_else(|| {
+        NegmaError::new("Could not determine the hostname for the flake")
+            .hint(format!("Name the host in SYSTEM_FLAKE, e.g. {}#myhost", flake))
+    })?;
+    Ok((flake, host))
+}
+
+/// `--option` pairs for the caches the local `SYSTEM_FLAKE` declares in its `nixConfig`.
+/// Anything that can't be read or parsed yields no options.
+fn flake_cache_options(cfg: &CFG) -> Vec<(String, String)> {
+    let Some(flake) = &cfg.system_flake else {
+        return Vec::new();
+    };
+    let dir = flake.split('#').next().unwrap_or(flake);
+    let Ok(source) = fs::read_to_string(Path::new(dir).join("flake.nix")) else {
+        return Vec::new();
+    };
+    let caches = nixconfig::caches(&source);
+    [("extra-substituters", caches.substituters), ("extra-trusted-public-keys", caches.keys)]
+        .into_iter()
+        .filter(|(_, values)| !values.is_empty())
+        .map(|(name, values)| (name.to_string(), values.join(" ")))
+        .collect()
+}
+
+fn hostname() -> Option<String> {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|h| h.trim().to_string())
+        .ok()
+        .filter(|h| !h.is_empty())
+}
+
+/// Resolves a `--flake` reference. Relative paths such as `.` become absolute, must contain a
+/// `flake.nix`, and get this machine's hostname as the configuration when none is given.
+fn resolve_flake_ref(flake: &str) -> Result<String> {
+    let invalid = || {
+        NegmaError::new(format!("Invalid flake reference '{}'", flake))
+            .hint("Use e.g. --flake . , --flake .#myhost or --flake github:me/nixos#myhost")
+    };
+    if flake.is_empty() || flake.chars().any(char::is_whitespace) {
+        return Err(invalid());
+    }
+    let (path, host) = match flake.split_once('#') {
+        Some((_, "")) | Some(("", _)) => return Err(invalid()),
+        Some((path, host)) => (path, Some(host)),
+        None => (flake, None),
+    };
+    if !(path == "." || path == ".." || path.starts_with("./") || path.starts_with("../")) {
+        return Ok(flake.to_string());
+    }
+
+    let dir = env::current_dir()
+        .map_err(|e| NegmaError::new("Could not determine the current directory").details(e.to_string()))?
+        .join(path);
+    let dir = dir.canonicalize().unwrap_or(dir);
+    if !dir.join("flake.nix").is_file() {
+        return Err(NegmaError::new(format!("No flake.nix in {}", dir.display()))
+            .hint("Run from a flake checkout or pass its path with --flake <dir>"));
+    }
+    let host = match host {
+        Some(host) => host.to_string(),
+        None => hostname().ok_or_else(|| {
+            NegmaError::new("Could not determine the hostname for the flake")
+                .hint(format!("Name the host explicitly, e.g. --flake {}#myhost", path))
+        })?,
+    };
+    Ok(format!("{}#{}", dir.display(), host))
+}
+
+/// Runs the formatter on `target`, then checks that it did not fail or empty any file.
+///
+/// The formatter's own output is only shown when it fails; the returned snapshot tells the
+/// caller which files changed. On a bad run the pre-format contents can be restored from it.
+fn run_formatter(cfg: &CFG, fmt: &str, target: &str, msg: &str) -> Result<format::Snapshot> {
+    let snapshot = format::Snapshot::take(Path::new(target));
+    let mut cmd = Command::new(fmt);
+    cmd.arg(target);
+    if cfg.dry_run {
+        run_command(cfg, &mut cmd, msg)?;
+        return Ok(snapshot);
+    }
+
+    let output = interrupt::output(cmd.envs(cfg.envs()).stdin(Stdio::inherit()));
+    let noise = match &output {
+        Ok(o) if o.stderr.is_empty() => String::from_utf8_lossy(&o.stdout).trim().to_string(),
+        Ok(o) => String::from_utf8_lossy(&o.stderr).trim().to_string(),
+        Err(_) => String::new(),
+    };
+    let result = check_status(output.map(|o| o.status), msg, &describe(&cmd)).map_err(|e| {
+        if noise.is_empty() {
+            e
+        } else {
+            let details = format!("{}\n{}", e.details.clone().unwrap_or_default(), noise);
+            e.details(details)
+        }
+    });
+
+    let emptied = snapshot.emptied();
+    if result.is_ok() && emptied.is_empty() {
+        return Ok(snapshot);
+    }
+
+    for path in &emptied {
+        eprintln!(
+            "{} {} is empty after formatting",
+            theme::style_warning("[negma warning]"),
+            theme::style_value(path.display().to_string())
+        );
+    }
+    if confirm("Restore the files as they were before formatting?") {
+        let restored = snapshot
+            .restore()
+            .map_err(|e| NegmaError::new("Failed to restore pre-format contents").details(e.to_string()))?;
+        println!("{} Restored {} file(s).", theme::style_header("[negma]"), restored);
+    }
+
+    match result {
+        Ok(()) => Err(NegmaError::new(format!("{}: {} emptied file(s)", msg, emptied.len()))
+            .hint("Check the formatter, then re-run the command")),
+        Err(e) => Err(e),
+    }
+}
+
+/// Prints `Formatted N file(s), M changed.` after `fmt`, unless `--quiet` was given.
+fn print_format_summary(cfg: &CFG, args: &[String], snapshot: &format::Snapshot) {
+    if cfg.dry_run || args.iter().any(|a| a == "--quiet") {
+        return;
+    }
+    let changed = snapshot.changed();
+    println!(
+        "{} Formatted {} file(s), {} changed.",
+        theme::style_header("[negma]"),
+        snapshot.file_count(),
+        changed.len()
+    );
+    for path in changed {
+        println!("  {}", theme::style_dim(path.display().to_string()));
+    }
+}
+
+/// Fails if a configured `NIXOS_DIR`/`HOME_MANAGER_DIR` does not exist. The default
+/// locations are left for the tools themselves to complain about.
+fn require_config_dir(dir: &str, configured: bool, key: &str) -> Result<()> {
+    if !configured || Path::new(dir).is_dir() {
+        return Ok(());
+    }
+    Err(NegmaError::new(format!("Configuration directory '{}' does not exist", dir))
+        .hint(format!("Create it, or fix {} in the negma config", key)))
+}
+
+/// With `REQUIRE_CLEAN_GIT`, refuses to continue while `dir` has uncommitted changes.
+///
+/// Does nothing unless `GIT` is configured and `dir` is a git repo; `--force` skips the check.
+fn ensure_clean_git(cfg: &CFG, dir: &Path, args: &[String]) -> Result<()> {
+    if !cfg.require_clean_git || cfg.git.is_empty() || args.iter().any(|a| a == "--force") {
+        return Ok(());
+    }
+
+    // Not a git repo, or git is not installed.
+    let Some(changes) = git_changes(dir) else {
+        return Ok(());
+    };
+    if changes.trim().is_empty() {
+        return Ok(());
+    }
+    Err(NegmaError::new(format!("Uncommitted changes in {}", dir.display()))
+        .details(changes.lines().take(10).collect::<Vec<_>>().join("\n  "))
+        .hint("Commit or stash them first (REQUIRE_CLEAN_GIT), or pass --force to rebuild anyway"))
+}
+
+/// `git status --porcelain` output for `dir`, or `None` if it is not a git repo or git is missing.
+fn git_changes(dir: &Path) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["status", "--porcelain"])
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// With `PRE_CHECK`, parses every `.nix` file in `dir` and stops at the first syntax error.
+fn pre_check(cfg: &CFG, dir: &Path) -> Result<()> {
+    if !cfg.pre_check {
+        return Ok(());
+    }
+
+    let files = format::nix_files(dir);
+    println!(
+        "{} Checking syntax of {} file(s) in {}...",
+        theme::style_header("[negma]"),
+        files.len(),
+        theme::style_dim(dir.display().to_string())
+    );
+    for file in &files {
+        let output = Command::new("nix-instantiate")
+            .arg("--parse")
+            .arg(file)
+            .stdout(Stdio::null())
+            .output()
+            .map_err(|e| NegmaError::new("Failed to run nix-instantiate --parse").details(e.to_string()))?;
+        if !output.status.success() {
+            return Err(NegmaError::new(format!("Syntax error in {}", file.display()))
+                .details(String::from_utf8_lossy(&output.stderr).trim().to_string())
+                .hint("Fix the file, then re-run make (PRE_CHECK)"));
+        }
+    }
+    Ok(())
+}
+
+/// Runs `nixos-rebuild <action>` with the configured flake, flags, and shortcuts, returning its stderr.
+fn rebuild(cfg: &CFG, home_dir: &str, command: &str, action: &str) -> Result<String> {
+    require_tool(cfg, "nixos-rebuild")?;
+    // A named profile's or a remote target's generations aren't the main system profile's, so
+    // `rollback --last` must not use them.
+    if cfg.target_profile.is_none() && cfg.target_host.is_none() {
+        history::set_previous_generation(generations::current_generation(&cfg.system_profile()));
+    }
+    if !cfg.permitted_insecure.is_empty() {
+        eprintln!(
+            "{} Permitting insecure packages ({}): NIXPKGS_ALLOW_INSECURE=1 allows every insecure package",
+            theme::style_warning("[negma warning]"),
+            cfg.permitted_insecure.join(", ")
+        );
+    }
+    println!("{} Running nixos-rebuild {}...", theme::style_header("[negma]"), action);
+    let msg = if cfg.builders.is_some() {
+        format!(
+            "nixos-rebuild {} failed (remote builds need your user in nix.settings.trusted-users on each builder)",
+            action
+        )
+    } else {
+        format!("nixos-rebuild {} failed", action)
+    };
+    run_command_retrying(
+        cfg,
+        home_dir,
+        Command::new("nixos-rebuild").arg(action).args(cfg.with_command_flags(command, cfg.rebuild_args())),
+        &msg,
+    )
+    .inspect(|stderr| summarize_dirty_tree(stderr))
+    .map_err(|e| {
+        if cfg.needs_impure_env() {
+            e.hint(
+                "--unfree/--broken/--permit-insecure only reach a flake through --impure (added automatically); \
+                 if the flake pins its own nixpkgs, set nixpkgs.config.allowUnfree / allowBroken / \
+                 permittedInsecurePackages there instead",
+            )
+        } else {
+            e
+        }
+    })
+}
+
+/// Validates a `--build-host` / `--target-host` split before anything runs. Either host alone
+/// is fine (remote build + local activation, or local build + remote activation), but only
+/// flakes support it, and steps that act on this machine's system profile don't mix with a
+/// remote target.
+fn check_remote_hosts(cfg: &CFG, args: &[String]) -> Result<()> {
+    if cfg.system_flake.is_none() {
+        return Err(NegmaError::new("--build-host / --target-host need a flake")
+            .details("BUILD_HOST / TARGET_HOST are only passed to flake rebuilds")
+            .hint("Set SYSTEM_FLAKE or pass --flake, or clear BUILD_HOST / TARGET_HOST"));
+    }
+    let Some(target) = &cfg.target_host else {
+        return Ok(());
+    };
+    if args[2] == "build" {
+        return Err(NegmaError::new(format!("'nix build' activates nothing, so --target-host {} has no effect", target))
+            .hint("Use nix switch, boot, or test to activate on the target host"));
+    }
+    for (flag, what) in [("--label", "labels a generation"), ("--reboot", "reboots")] {
+        if args.iter().any(|a| a == flag || a.starts_with(&format!("{}=", flag))) {
+            return Err(NegmaError::new(format!("{} cannot be combined with --target-host {}", flag, target))
+                .details(format!("{} {} on this machine, not on the target host", flag, what)));
+        }
+    }
+    Ok(())
+}
+
+/// After a flake build nix warned about a dirty git tree (possibly once per evaluation),
+/// says so once more at the end, where it isn't lost in the build output.
+fn summarize_dirty_tree(stderr: &str) {
+    let warnings = buildlog::dirty_tree_warnings(stderr);
+    if warnings > 0 {
+        eprintln!(
+            "{} Built from a git tree with uncommitted changes (nix warned {}x); commit them, or pass --dirty \
+             to build a dirty tree without the warning",
+            theme::style_warning("[negma warning]"),
+            warnings
+        );
+    }
+}
+
+/// Previews `nix upgrade` without applying anything: the inputs or channels that would
+/// update, and for flakes what the rebuild would build or fetch against the updated lock.
+fn upgrade_plan(cfg: &CFG, flake_dir: Option<&str>) -> Result<()> {
+    let Some(dir) = flake_dir else {
+        println!("{} Channels that would update to their latest revision:", theme::style_header("[negma]"));
+        run_passthrough(Command::new("nix-channel").arg("--list"), "nix-channel --list failed")?;
+        println!(
+            "{} The rebuild preview needs the updated channels, so it is skipped in channel mode.",
+            theme::style_warning("[negma]")
+        );
+        return Ok(());
+    };
+
+    // Lock into a scratch file so the real flake.lock is untouched.
+    let scratch = env::temp_dir().join(format!("negma-upgrade-{}.lock", std::process::id()));
+    println!("{} Resolving the latest flake inputs...", theme::style_header("[negma]"));
+    let output = Command::new("nix")
+        .args(["--extra-experimental-features", "nix-command flakes"])
+        .args(["flake", "lock", "--recreate-lock-file", "--output-lock-file"])
+        .arg(&scratch)
+        .arg(dir)
+        .envs(cfg.envs())
+        .stdout(Stdio::null())
+        .output()
+        .map_err(|e| NegmaError::new("Failed to run nix flake lock").details(e.to_string()))?;
+    let updated = fs::read_to_string(&scratch);
+    let _ = fs::remove_file(&scratch);
+    if !output.status.success() {
+        return Err(NegmaError::new("Failed to resolve the latest flake inputs")
+            .details(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+
+    let current = fs::read_to_string(Path::new(dir).join("flake.lock")).unwrap_or_else(|_| "{}".to_string());
+    let changes = updated
+        .map_err(serde_json::Error::io)
+        .and_then(|updated| flakelock::diff(&current, &updated))
+        .map_err(|e| NegmaError::new("Failed to compare flake.lock files").details(e.to_string()))?;
+    if changes.is_empty() {
+        println!("{} All flake inputs are up to date.", theme::style_header("[negma]"));
+    } else {
+        println!("\n{}", theme::style_title("Inputs that would update:"));
+        let width = changes.iter().map(|c| c.name.len()).max().unwrap_or(0);
+        for change in &changes {
+            println!(
+                "  {:<width$}  {} → {}",
+                theme::style_accent(&change.name),
+                theme::style_dim(change.from.as_deref().unwrap_or("(new)")),
+                theme::style_value(change.to.as_deref().unwrap_or("(removed)")),
+                width = width
+            );
+        }
+        println!();
+    }
+
+    println!("{} What the rebuild would build or fetch:", theme::style_header("[negma]"));
+    run_passthrough(
+        Command::new("nixos-rebuild")
+            .arg("dry-build")
+            .args(cfg.rebuild_args())
+            .args(["--recreate-lock-file", "--no-write-lock-file"])
+            .envs(cfg.envs()),
+        "nixos-rebuild dry-build failed",
+    )
+}
+
+/// Shared `list-generations` for the home (`home = true`) and system profiles.
+///
+/// Renders the parsed listing as an aligned table; `--limit <n>` shows only the newest
+/// n generations (plus the current one), and `--sort`/`--reverse` reorder what is shown.
+fn list_generations(args: &[String], cfg: &CFG, home_dir: &str, home: bool) -> Result<()> {
+    let label = if home { "home-manager" } else { "system" };
+
+    let limit = flag_value(args, "--limit")
+        .map(|limit| {
+            limit.parse::<usize>().map_err(|_| {
+                NegmaError::new(format!("Invalid --limit value '{}'", limit))
+                    .hint("Use a non-negative integer, e.g. --limit 10")
+            })
+        })
+        .transpose()?;
+
+    let since = flag_value(args, "--since")
+        .map(|since| {
+            generations::parse_day(since).ok_or_else(|| {
+                NegmaError::new(format!("Invalid --since date '{}'", since))
+                    .hint("Use YYYY-MM-DD, e.g. --since 2024-06-01")
+            })
+        })
+        .transpose()?;
+
+    let sort_by_date = match flag_value(args, "--sort") {
+        None | Some("number") => false,
+        Some("date") => true,
+        Some(other) => {
+            return Err(NegmaError::new(format!("Invalid --sort value '{}'", other))
+                .hint("Use --sort number or --sort date"));
+        }
+    };
+    let reverse = args.iter().any(|a| a == "--reverse");
+
+    let named = flag_value(args, "--profile").map(|name| profile_name("--profile", name)).transpose()?.flatten();
+    let profile = if home {
+        generations::home_profile(home_dir)
+    } else {
+        match &named {
+            Some(name) => generations::named_system_profile(name),
+            None => SYSTEM_PROFILE.into(),
+        }
+    };
+    let gens = if home { generations::list_home() } else { generations::list_system(&profile) };
+    let mut gens = gens.map_err(|e| {
+        NegmaError::new(format!("Failed to list {} generations", label)).details(e.to_string())
+    })?;
+    if !home && named.is_none() {
+        let mut labels = labels::read(home_dir);
+        for g in &mut gens {
+            g.label = labels.remove(&g.number);
+        }
+    }
+    let json = args.iter().any(|a| a == "--json");
+    if gens.is_empty() && !json {
+        println!("{} No {} generations found.", theme::style_header("[negma]"), label);
+        return Ok(());
+    }
+
+    let total = gens.len();
+    // Taken before filtering: a shown generation is compared with the one right before it.
+    let all: Vec<(u32, Option<PathBuf>)> = gens
+        .iter()
+        .map(|g| (g.number, g.path.as_ref().map(PathBuf::from).or_else(|| generations::store_path(&profile, g.number))))
+        .collect();
+    if let Some(since) = since {
+        gens.retain(|g| match g.day {
+            Some(day) => day >= since,
+            None => {
+                eprintln!(
+                    "{} Could not parse the date of generation {} ('{}'); keeping it",
+                    theme::style_warning("[negma warning]"),
+                    g.number,
+                    g.date
+                );
+                true
+            }
+        });
+    }
+
+    let mut shown = match limit {
+        Some(limit) => generations::newest(gens, limit),
+        None => gens,
+    };
+    if sort_by_date {
+        shown.sort_by(|a, b| a.day.cmp(&b.day).then_with(|| a.date.cmp(&b.date)));
+    }
+    if reverse {
+        shown.reverse();
+    }
+    if json {
+        println!("{}", generations::to_json(&shown));
+        return Ok(());
+    }
+    let path_of = |number: u32| all.iter().find(|(n, _)| *n == number).and_then(|(_, path)| path.as_deref());
+    let mut columns = Vec::new();
+    // One `nix path-info` per generation, so only on request.
+    if args.iter().any(|a| a == "--size") {
+        columns.push(
+            shown
+                .iter()
+                .map(|g| {
+                    path_of(g.number)
+                        .and_then(|p| generations::closure_size(p).ok())
+                        .map_or_else(|| "?".to_string(), optimise::human_size)
+                })
+                .collect(),
+        );
+    }
+    // One closure query per generation and its predecessor, so only on request.
+    if args.iter().any(|a| a == "--diff-adjacent") {
+        let mut cache = generations::ClosureCache::default();
+        columns.push(
+            shown
+                .iter()
+                .map(|g| {
+                    let index = all.iter().position(|(n, _)| *n == g.number);
+                    let Some(previous) = index.and_then(|i| i.checked_sub(1)) else {
+                        return String::new();
+                    };
+                    match (all[previous].1.as_deref(), path_of(g.number)) {
+                        (Some(old), Some(new)) => cache
+                            .changes(old, new)
+                            .map_or_else(|| "?".to_string(), |(added, removed)| format!("+{} -{}", added, removed)),
+                        _ => "?".to_string(),
+                    }
+                })
+                .collect(),
+        );
+    }
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{} Showing {} of {} {} generations:",
+        theme::style_header("[negma]"),
+        shown.len(),
+        total,
+        label
+    );
+    render_generations(&mut out, &shown, &columns);
+    pager::show(cfg.use_pager.then_some(cfg.pager.as_str()), &out);
+    Ok(())
+}
+
+/// One-line "about to run" summary before a system rebuild: channel or flake mode, the flake
+/// ref and the host it builds, every flag passed, and the auto GC / auto-format settings.
+fn print_make_summary(cfg: &CFG, command: &str, action: &str) {
+    let source = match &cfg.system_flake {
+        Some(flake) => {
+            let host = match flake.split_once('#') {
+                Some((_, host)) => host.to_string(),
+                None => hostname().unwrap_or_else(|| "?".to_string()),
+            };
+            let mut source = format!("flake {} (host {})", theme::style_value(flake), theme::style_value(host));
+            if let Some(build_host) = &cfg.build_host {
+                source.push_str(&format!(", built on {}", theme::style_value(build_host)));
+            }
+            if let Some(target_host) = &cfg.target_host {
+                source.push_str(&format!(", activated on {}", theme::style_value(target_host)));
+            }
+            source
+        }
+        None => format!("channel {}", theme::style_value(cfg.channel.as_deref().unwrap_or("(system default)"))),
+    };
+    let flags = cfg.with_command_flags(command, cfg.rebuild_args()).join(" ");
+    let on_off = |on: bool| if on { "on" } else { "off" };
+    println!(
+        "{} About to run nixos-rebuild {}: {}, flags: {}, auto GC {}, auto fmt {}",
+        theme::style_header("[negma]"),
+        theme::style_accent(action),
+        source,
+        theme::style_dim(if flags.is_empty() { "(none)" } else { &flags }),
+        on_off(cfg.auto_gc),
+        on_off(cfg.auto_fmt && cfg.formatter.is_some())
+    );
+}
+
+/// Summarizes which systemd units a switch or test stopped, restarted, reloaded, or started.
+fn print_activation_report(action: &str, changes: &activation::UnitChanges) {
+    if action == "boot" {
+        println!(
+            "{} Nothing activated: 'boot' only takes effect after a reboot.",
+            theme::style_header("[negma]")
+        );
+        return;
+    }
+    if changes.is_empty() {
+        println!("{} Activation report: no units changed.", theme::style_header("[negma]"));
+        return;
+    }
+    println!("\n{}", theme::style_title("Activation report:"));
+    for (kind, units) in [
+        ("restarted", &changes.restarted),
+        ("reloaded", &changes.reloaded),
+        ("started", &changes.started),
+        ("stopped", &changes.stopped),
+    ] {
+        if !units.is_empty() {
+            println!("  {:<10} {}", format!("{}:", kind), theme::style_value(units.join(", ")));
+        }
+    }
+}
+
+/// Renders generations into `out` as an aligned table with the current one highlighted,
+/// with extra right-aligned `columns` (one value per generation each, e.g. closure sizes) after
+/// the date.
+fn render_generations(out: &mut String, gens: &[generations::Generation], columns: &[Vec<String>]) {
+    let num_width = gens.iter().map(|g| g.number.to_string().len()).max().unwrap_or(1);
+    let date_width = gens.iter().map(|g| g.date.len()).max().unwrap_or(0);
+
+    for (i, g) in gens.iter().enumerate() {
+        let number = format!("{:>width$}", g.number, width = num_width);
+        let mut date = format!("{:<width$}", g.date, width = date_width);
+        for column in columns {
+            let width = column.iter().map(String::len).max().unwrap_or(0);
+            date.push_str(&format!("  {:>width$}", column.get(i).map_or("", String::as_str), width = width));
+        }
+        let path = g.path.as_deref().map(|p| format!("  {}", p)).unwrap_or_default();
+        let label = g.label.as_deref().map(|l| format!("  [{}]", l)).unwrap_or_default();
+        if g.current {
+            let _ = writeln!(
+                out,
+                "  {}  {}{}{}  {}",
+                theme::style_accent(&number).bold(),
+                theme::style_value(&date).bold(),
+                theme::style_dim(&path),
+                theme::style_label(&label),
+                theme::style_header("← current")
+            );
+        } else {
+            let _ = writeln!(
+                out,
+                "  {}  {}{}{}",
+                theme::style_accent(&number),
+                date,
+                theme::style_dim(&path),
+                theme::style_label(&label)
+            );
+        }
+    }
+}
+
+/// Asks a yes/no question on stdin; anything but `y`/`yes` (including EOF) is a no.
+fn confirm(question: &str) -> bool {
+    print!("{} {} [y/N] ", theme::style_warning("[negma]"), question);
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).is_ok()
+        && matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Resolves the `--steps <n>` value to the generation `n` steps before the current one.
+fn generation_steps_back<'a>(
+    gens: &'a [generations::Generation],
+    steps: Option<&str>,
+) -> Result<&'a generations::Generation> {
+    let Some(steps) = steps else {
+        return Err(NegmaError::new("Missing value for --steps").hint("Use e.g. --steps 2"));
+    };
+    let n = steps.parse::<usize>().ok().filter(|&n| n > 0).ok_or_else(|| {
+        NegmaError::new(format!("Invalid --steps value '{}'", steps)).hint("Use a positive integer, e.g. --steps 2")
+    })?;
+    generations::steps_back(gens, n).map_err(|available| {
+        NegmaError::new(format!("Cannot roll back {} step(s)", n))
+            .details(format!("Only {} generation(s) are older than the current one", available))
+            .hint("List them with: negma <home|nix> list-generations")
+    })
+}
+
+/// Refuses a rollback to the previous generation when the listing has nothing older than
+/// the current one, where nix-env and home-manager would fail cryptically or do nothing.
+/// A listing that failed or came back empty proves nothing, so the rollback goes ahead.
+/// The generation `rollback --last` returns to. A make that recorded none (one deployed with
+/// `--target-host`, say) is refused rather than guessed at with `--rollback`.
+fn generation_before(last: &history::Entry, scope: &str) -> Result<u32> {
+    last.previous_generation.ok_or_else(|| {
+        NegmaError::new(format!("The last {} make has no recorded previous generation", scope))
+            .details(format!("{}  negma {}", last.timestamp, last.command))
+            .hint(format!(
+                "Pick one from negma {} list-generations, then: negma {} rollback <generation>",
+                scope, scope
+            ))
+    })
+}
+
+fn ensure_older_generation(gens: io::Result<Vec<generations::Generation>>, scope: &str) -> Result<()> {
+    let Ok(gens) = gens else {
+        return Ok(());
+    };
+    if gens.is_empty() || generations::steps_back(&gens, 1).is_ok() {
+        return Ok(());
+    }
+    Err(NegmaError::new("Already on the oldest generation")
+        .details(format!("Generation {} is the oldest one; there is nothing to roll back to", gens[0].number))
+        .hint(format!("List them with: negma {} list-generations", scope)))
+}
+
+/// Validates a `--target-profile` / `--profile` name before it becomes part of a path under
+/// `system-profiles/`. `system` names the main system profile explicitly and resolves to `None`.
+fn profile_name(flag: &str, name: &str) -> Result<Option<String>> {
+    if name == "system" {
+        return Ok(None);
+    }
+    if !generations::is_valid_profile_name(name) {
+        return Err(NegmaError::new(format!("Invalid {} name '{}'", flag, name))
+            .details("A profile name is one non-empty word without '/', not '.' or '..', and not starting with '-'")
+            .hint("Use a plain name such as 'testing', or 'system' for the main system profile"));
+    }
+    Ok(Some(name.to_string()))
+}
+
+/// Value following `flag` (or given as `flag=value`) in `args`, if present.
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter().enumerate().find_map(|(i, a)| {
+        if a == flag {
+            args.get(i + 1).map(String::as_str)
+        } else {
+            a.strip_prefix(flag).and_then(|r| r.strip_prefix('='))
+        }
+    })
+}
+
+/// Finds the store path of `pkg` in the current system closure.
+///
+/// Matches either the full `<name>-<version>` or just the name, e.g. `openssl` matches
+/// `/nix/store/<hash>-openssl-3.0.13`. Store paths are returned unchanged.
+fn resolve_closure_path(pkg: &str) -> Option<String> {
+    if pkg.starts_with("/nix/store/") {
+        return Some(pkg.to_string());
+    }
+
+    let output = Command::new("nix-store")
+        .args(["--query", "--requisites", "/run/current-system"])
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let closure = String::from_utf8_lossy(&output.stdout);
+
+    closure
+        .lines()
+        .find(|path| {
+            let Some((_, name)) = path.trim_start_matches("/nix/store/").split_once('-') else {
+                return false;
+            };
+            name == pkg
+                || name
+                    .strip_prefix(pkg)
+                    .and_then(|rest| rest.strip_prefix('-'))
+                    .is_some_and(|version| version.starts_with(|c: char| c.is_ascii_digit()))
+        })
+        .map(String::from)
+}
+
+fn run_nix_env(cfg: &CFG, args: Vec<&str>) -> Result<()> {
+    require_tool(cfg, "nix-env")?;
+    println!("{} Running nix-env {}...", theme::style_header("[negma]"), theme::style_dim(args.join(" ")));
+    run_command(cfg, Command::new("nix-env").args(args), "nix-env command failed")
+}
+
+/// Tells the user to re-run `negma <command>` with `SUDO_CMD`, which is only checked against
+/// `PATH` here, when it is actually needed.
+fn escalation_hint(cfg: &CFG, command: &str) -> String {
+    let hint = format!("Use: {} negma {}", cfg.sudo_cmd, command);
+    let program = cfg.sudo_cmd.split_whitespace().next().unwrap_or_default();
+    if tools::find_in_path(program).is_some() {
+        return hint;
+    }
+    format!("{} (SUDO_CMD '{}' is not on PATH: install it or set SUDO_CMD to sudo, doas, or run0)", hint, program)
+}
+
+/// Looks up the user `home make --target-user <name>` rebuilds for; only root may switch users.
+fn target_user(cfg: &CFG, name: &str) -> Result<nix::unistd::User> {
+    if !cfg.issu {
+        return Err(NegmaError::new("--target-user requires superuser privileges")
+            .hint(escalation_hint(cfg, &format!("home make --target-user {}", name))));
+    }
+    nix::unistd::User::from_name(name)
+        .ok()
+        .flatten()
+        .ok_or_else(|| NegmaError::new(format!("Unknown user '{}'", name)).hint("Check the name in /etc/passwd"))
+}
+
+/// Runs `cmd` as `user` with their home directory, for root acting on a user's home-manager setup.
+fn as_user(cmd: &mut Command, user: &nix::unistd::User) {
+    cmd.uid(user.uid.as_raw())
+        .gid(user.gid.as_raw())
+        .env("HOME", &user.dir)
+        .env("USER", &user.name)
+        .env("LOGNAME", &user.name);
+}
+
+/// Fails with a precise error when `program` can't be run: not installed at all, or installed
+/// but missing from the `PATH` negma runs with (usually because `sudo` resets it).
+/// Skipped in dry-run mode, which needs no tools.
+fn require_tool(cfg: &CFG, program: &str) -> Result<()> {
+    if cfg.dry_run {
+        return Ok(());
+    }
+    let who = if cfg.issu { "root" } else { "your user" };
+    match tools::lookup(program) {
+        tools::Lookup::Found(_) => Ok(()),
+        tools::Lookup::OffPath(path) => {
+            let dir = path.parent().unwrap_or(Path::new("/")).display().to_string();
+            let hint = if env::var_os("SUDO_USER").is_some() {
+                format!(
+                    "sudo replaced your PATH; run '{} env PATH=\"$PATH\" negma ...' or add {} to sudo's secure_path",
+                    cfg.sudo_cmd, dir
+                )
+            } else {
+                format!("Add {} to PATH", dir)
+            };
+            Err(NegmaError::new(format!("'{}' is installed but not on the PATH for {}", program, who))
+                .details(format!("found at {}", path.display()))
+                .hint(hint))
+        }
+        tools::Lookup::Missing => Err(NegmaError::new(format!("'{}' is not installed", program))
+            .details(format!("not on the PATH for {}, nor in the system or user nix profiles", who))
+            .hint(match program {
+                "home-manager" => "Install Home Manager: https://nix-community.github.io/home-manager/",
+                "nixos-rebuild" => "nixos-rebuild ships with NixOS; negma's nix commands need a NixOS system",
+                _ => "Install nix: https://nixos.org/download",
+            })),
+    }
+}
+
+/// Runs a mutating command with inherited stdio, or only prints it in dry-run mode.
+fn run_command(cfg: &CFG, cmd: &mut Command, msg: &str) -> Result<()> {
+    cmd.envs(cfg.envs());
+    if cfg.dry_run {
+        println!("{} {}", theme::style_warning("[dry-run]"), theme::style_dim(describe(cmd)));
+        return Ok(());
+    }
+    run_passthrough(cmd, msg)
+}
+
+/// Error output that indicates a flaky network or substituter rather than a real failure.
+const TRANSIENT_ERRORS: &[&str] = &[
+    "unable to download",
+    "Connection timed out",
+    "Connection reset by peer",
+    "Could not resolve host",
+    "Timeout was reached",
+    "HTTP error 502",
+    "HTTP error 503",
+    "HTTP error 504",
+];
+
+/// Like [`run_command`], but retries up to `cfg.retry` times with exponential backoff when
+/// stderr shows a transient network failure.
+///
+/// If the build fails, the failing derivation is remembered for `nix rebuild-log`.
+/// Returns the command's stderr on success so callers can look for notices in it.
+fn run_command_retrying(cfg: &CFG, home_dir: &str, cmd: &mut Command, msg: &str) -> Result<String> {
+    if cfg.dry_run {
+        return run_command(cfg, cmd, msg).map(|_| String::new());
+    }
+
+    cmd.envs(cfg.envs());
+    let mut attempt = 0;
+    loop {
+        // stdout is left to the caller, e.g. `nix build --json` sends it to stderr.
+        let result = interrupt::status_capturing_stderr(cmd.stdin(Stdio::inherit()));
+        let transient = match &result {
+            Ok((status, stderr)) => {
+                !status.success() && TRANSIENT_ERRORS.iter().any(|p| stderr.contains(p))
+            }
+            Err(_) => false,
+        };
+
+        if !transient || attempt >= cfg.retry {
+            let drv = result.as_ref().ok().and_then(|(_, stderr)| buildlog::failed_derivation(stderr));
+            let (status, stderr) = match result {
+                Ok((status, stderr)) => (Ok(status), stderr),
+                Err(e) => (Err(e), String::new()),
+            };
+            return check_status(status, msg, &describe(cmd))
+                .map(|_| stderr)
+                .map_err(|e| match drv {
+                    Some(drv) => {
+                        let _ = buildlog::record(home_dir, &drv);
+                        e.hint(format!("See the build log with: nix log {}", drv))
+                    }
+                    None => e,
+                });
+        }
+
+        attempt += 1;
+        let delay = Duration::from_secs(2u64.pow(attempt).min(60));
+        println!(
+            "{} Transient network failure, retrying in {}s (attempt {} of {})...",
+            theme::style_warning("[negma]"),
+            delay.as_secs(),
+            attempt,
+            cfg.retry
+        );
+        std::thread::sleep(delay);
+    }
+}
+
+/// Runs a command with inherited stdio regardless of dry-run, for read-only operations.
+fn run_passthrough(cmd: &mut Command, msg: &str) -> Result<()> {
+    let status = interrupt::status(
+        cmd.stdin(Stdio::inherit())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit()),
+    );
+    check_status(status, msg, &describe(cmd))
+}
+
+/// Renders a command as a shell-like line for previews, including any extra environment.
+fn describe(cmd: &Command) -> String {
+    cmd.get_envs()
+        .filter_map(|(k, v)| Some(format!("{}={}", k.to_string_lossy(), v?.to_string_lossy())))
+        .chain(
+            std::iter::once(cmd.get_program())
+                .chain(cmd.get_args())
+                .map(|a| a.to_string_lossy().into_owned()),
+        )
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Turns a command's outcome into an error naming the failed `command` and how it ended.
+fn check_status(status: std::io::Result<std::process::ExitStatus>, msg: &str, command: &str) -> Result<()> {
+    let reason = match status {
+        Ok(s) if s.success() => return Ok(()),
+        Ok(s) => match (s.code(), s.signal()) {
+            (Some(code), _) => format!("exit code {}", code),
+            (None, Some(sig)) => format!("killed by signal {}", sig),
+            (None, None) => "unknown exit status".to_string(),
+        },
+        Err(e) => e.to_string(),
+    };
+    Err(NegmaError::new(format!("{} ({})", msg, reason)).details(command))
+}
+
+fn print_error(title: &str, details: Option<&str>, hint: Option<&str>) {
+    eprintln!("{} {}", theme::style_error("[negma error]"), theme::style_value(title));
+    if let Some(d) = details {
+        eprintln!("{} {}", "↳".color(theme::palette().error), theme::style_dim(d));
+    }
+    if let Some(h) = hint {
+        eprintln!("{} {}", theme::style_warning("hint:"), theme::style_value(h));
+    }
+}
+
+fn print_help() {
+    println!(
+        "\n{}\n{}",
+        theme::style_accent("[negma]").bold(),
+        theme::style_value("A clean, practical NixOS & Home Manager CLI helper.")
+    );
+    println!("\n{} {}", theme::style_title("Usage:"), theme::style_accent("negma <command> [subcommand] [args]"));
+    println!("\n{}", theme::style_title("Commands:"));
+    println!("  {} {}", theme::style_accent("home, h").bold(), theme::style_value("<subcommand>"));
+    println!("  {} {}", theme::style_accent("nix, n").bold(), theme::style_value("<subcommand>"));
+    println!("  {}", theme::style_accent("edit-cfg").bold());
+    println!("  {}   {}", theme::style_accent("gc").bold(), theme::style_value("home-manager expiry + system GC in one go"));
+    println!("  {} {}", theme::style_accent("config").bold(), theme::style_value("init [--force], reset [--yes], default,"));
+    println!("         {}", theme::style_value("get <KEY>, set <KEY> <VALUE>, check [--json]"));
+    println!("  {} {}", theme::style_accent("history").bold(), theme::style_value("[N] [--all] [--clear]"));
+    println!("  {} {}", theme::style_accent("doctor").bold(), theme::style_value("[--json]"));
+    println!("  {} {}", theme::style_accent("status").bold(), theme::style_value("system + home at a glance"));
+    println!("  {} {}", theme::style_accent("uninstall").bold(), theme::style_value("[--keep-config] [--yes]"));
+    println!("  {} {}", theme::style_accent("explain").bold(), theme::style_value("<command>   show what it would run"));
+    println!("  {} {}", theme::style_accent("completions").bold(), theme::style_value("<bash|zsh|fish> [--dynamic]"));
+
+    println!("\n{}:", theme::style_title("Home Manager Subcommands"));
+    println!("  edit, fmt [--quiet], make [--backup] [--target-user name] (or switch), check, gc");
+    println!("  clean [--keep n], backup");
+    println!("  list-generations [--limit n] [--since date] [--sort number|date] [--reverse] [--size]");
+    println!("  list-generations [--diff-adjacent] [--json]");
+    println!("  rollback [gen|--last|--steps n], diff [a [b]], news");
 
-    println!("\n{}:", "Home Manager Subcommands".bright_white().underline());
-    println!("  edit, fmt, make, gc, clean, backup, list-generations, rollback [gen]");
+    println!("\n{}:", theme::style_title("NixOS Subcommands (requires sudo)"));
+    println!("  edit, fmt [--quiet], make [--label text] [--report] [--confirm], switch, test");
+    println!("  boot [--reboot [--yes]]");
+    println!("  build [--out-link path] [--json], check, clean");
+    println!("  gc [--keep-outputs] [--keep-derivations] [--max-freed size]");
+    println!("  list-generations [--limit n] [--since date] [--sort number|date] [--reverse] [--profile name]");
+    println!("  list-generations [--size] [--diff-adjacent] [--json]");
+    println!("  list-profiles, gen-info <n> [--size], rollback [gen|--last|--steps n]");
+    println!("  why-depends <pkg>, rebuild-log [drv]");
+    println!("  optimise, show-config <attr>, upgrade [--plan] [--yes]");
 
-    println!("\n{}:", "NixOS Subcommands (requires sudo)".bright_white().underline());
-    println!("  edit, fmt, make, gc, clean, list-generations, rollback [gen]");
+    println!("\n{}:", theme::style_title("Global Flags"));
+    println!("  --dry-run           print mutating commands instead of running them");
+    println!("  --config <path|->   read the config from a file, or from stdin with '-'");
+    println!("  --no-color          disable colored output (NO_COLOR is honored too; off when not on a terminal)");
+    println!("  --pager             page history / list-generations output on a terminal (PAGER, $PAGER, less)");
+    println!("  --apply             run for real even when DRY_RUN_DEFAULT = true");
+    println!("  --builders <spec>   build on remote machines during 'nix make'");
+    println!("  --flake <ref>       use this flake instead of SYSTEM_FLAKE ('.' = the flake in this directory)");
+    println!("  --builders-use-substitutes   let remote builders fetch from binary caches");
+    println!("  --build-host <host> build a flake rebuild on another machine over ssh (BUILD_HOST)");
+    println!("  --target-host <host> activate a flake rebuild on another machine over ssh (TARGET_HOST)");
+    println!("  --keep-going        keep building the rest after a failure, report errors at the end");
+    println!("  --fallback          build locally when a binary cache can't provide a path, like FALLBACK");
+    println!("  --cores <n>         cores per build job (0 = all), like BUILD_CORES");
+    println!("  --max-jobs <n>      parallel build jobs, like BUILD_MAX_JOBS");
+    println!("  --timeout <secs>    kill spawned commands that run longer (0 = no limit), like COMMAND_TIMEOUT");
+    println!("  --impure            allow impure flake evaluation for make/check");
+    println!("  --accept-flake-config   apply the flake's nixConfig without prompting (flake mode)");
+    println!("  --substituters-from-lock   use the caches in the flake's nixConfig, nothing else from it");
+    println!("  --no-update-lockfile   never update or write flake.lock during rebuilds (flake mode)");
+    println!("  --dirty                build an uncommitted flake without nix's dirty-tree warning (flake mode)");
+    println!("  --use-remote-sudo   build as your user, sudo only for activation (flake mode, no root needed)");
+    println!("  --option <key> <value>   pass a nix option to rebuilds and home-manager (repeatable)");
+    println!("  --env KEY=VALUE     set an environment variable for spawned nix commands (repeatable)");
+    println!("  --target-profile <name>   build 'nix make' into system-profiles/<name>");
+    println!("  --verbose-nix[=n]   pass -v (up to -vvvvv) to nixos-rebuild for nix's own build detail");
+    println!("  --fast              skip auto-GC and auto-formatting for this run");
+    println!("  --skip-gc           skip auto-GC for this run without touching its schedule");
+    println!("  --auto-fmt, --no-auto-fmt   force or skip formatting after edit (overrides AUTO_FMT)");
+    println!("  --unfree, --broken  allow unfree / broken packages (NIXPKGS_ALLOW_UNFREE/BROKEN=1)");
+    println!("  --permit-insecure <pkg>   allow an insecure package, repeatable (NIXPKGS_ALLOW_INSECURE=1)");
 
-    println!("\n{}:", "Examples".bright_white().underline());
+    println!("\n{}:", theme::style_title("Examples"));
     println!("  negma home edit");
+    println!("  negma h make");
+    println!("  sudo negma nix switch");
     println!("  negma home fmt");
     println!("  sudo negma nix edit");
     println!("  sudo negma nix fmt");
     println!("  negma edit-cfg");
+    println!("  negma history 50");
 
-    println!("\n{}", "✨ Keep your NixOS clean and workflow calm with negma ✨".bright_purple());
+    println!("\n{}", theme::style_label("✨ Keep your NixOS clean and workflow calm with negma ✨"));
 }
@@ -0,0 +1,50 @@
+/// Binary caches a flake declares in its `nixConfig`, e.g.
+/// `nixConfig.extra-substituters = [ "https://cache.example.org" ];`.
+#[derive(Debug, Default, PartialEq)]
+pub struct FlakeCaches {
+    pub substituters: Vec<String>,
+    pub keys: Vec<String>,
+}
+
+/// Best-effort scan of `flake.nix` source for the caches in its `nixConfig`, without evaluating
+/// it. Both the list and the space-separated string form are understood; anything computed is
+/// missed.
+pub fn caches(flake_nix: &str) -> FlakeCaches {
+    let Some(start) = flake_nix.find("nixConfig") else {
+        return FlakeCaches::default();
+    };
+    let config = &flake_nix[start..];
+    FlakeCaches {
+        substituters: values(config, &["extra-substituters", "substituters"]),
+        keys: values(config, &["extra-trusted-public-keys", "trusted-public-keys"]),
+    }
+}
+
+/// Strings assigned to any of `names`, in order of appearance and without duplicates.
+fn values(config: &str, names: &[&str]) -> Vec<String> {
+    let mut found: Vec<String> = Vec::new();
+    for name in names {
+        let mut rest = config;
+        while let Some(i) = rest.find(name) {
+            let preceded_by_name_char = rest[..i].ends_with(|c: char| c.is_alphanumeric() || c == '-');
+            rest = &rest[i + name.len()..];
+            let Some(value) = rest.trim_start().strip_prefix('=') else {
+                continue;
+            };
+            if preceded_by_name_char {
+                continue;
+            }
+            let value = value.trim_start();
+            let body = match value.strip_prefix('[') {
+                Some(list) => &list[..list.find(']').unwrap_or(list.len())],
+                None => &value[..value.find(';').unwrap_or(value.len())],
+            };
+            for entry in body.split('"').skip(1).step_by(2).flat_map(str::split_whitespace) {
+                if !found.iter().any(|f| f == entry) {
+                    found.push(entry.to_string());
+                }
+            }
+        }
+    }
+    found
+}
@@ -0,0 +1,60 @@
+/// What `nix-store --optimise` reported having freed (or, with `--dry-run`, would free).
+#[derive(Debug, PartialEq)]
+pub struct Report {
+    /// Bytes freed by hard-linking.
+    pub bytes: u64,
+    /// Files replaced by a hard link.
+    pub files: u64,
+}
+
+/// Finds nix's summary in `output`, e.g. `12.50 MiB freed by hard-linking 340 files`.
+pub fn parse_report(output: &str) -> Option<Report> {
+    output.lines().find_map(|line| {
+        let (size, files) = line.trim().split_once(" freed by hard-linking ")?;
+        let files = files.strip_suffix(" files")?.parse().ok()?;
+        let mut words = size.split_whitespace().rev();
+        let (unit, number) = (words.next()?, words.next()?.parse::<f64>().ok()?);
+        let shift = match unit {
+            "B" | "bytes" => 0,
+            "KiB" => 10,
+            "MiB" => 20,
+            "GiB" => 30,
+            "TiB" => 40,
+            _ => return None,
+        };
+        Some(Report { bytes: (number * (1u64 << shift) as f64) as u64, files })
+    })
+}
+
+/// Parses a size such as `500M`, `5G` or `1TiB` into bytes. Units are binary (`K` = 1024);
+/// a bare number is bytes. `None` for anything malformed or too large.
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let digits = size.find(|c: char| !c.is_ascii_digit()).unwrap_or(size.len());
+    let (number, unit) = size.split_at(digits);
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// Formats a byte count with a binary unit, e.g. `1.5 GiB`.
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
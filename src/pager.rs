@@ -0,0 +1,25 @@
+use std::{
+    env,
+    io::{self, IsTerminal, Write},
+    process::{Command, Stdio},
+};
+
+/// Writes `text` to stdout, through `pager` (a shell command) when one is given and stdout
+/// is a terminal. Falls back to printing directly if the pager can't be started.
+pub fn show(pager: Option<&str>, text: &str) {
+    if let Some(pager) = pager.filter(|_| io::stdout().is_terminal()) {
+        // Like git: keep colors and don't page output that fits on one screen.
+        let less = env::var("LESS").unwrap_or_else(|_| "FRX".to_string());
+        let child = Command::new("sh").args(["-c", pager]).env("LESS", less).stdin(Stdio::piped()).spawn();
+        if let Ok(mut child) = child {
+            if let Some(mut stdin) = child.stdin.take() {
+                // Quitting the pager early closes the pipe; that's not an error.
+                let _ = stdin.write_all(text.as_bytes());
+            }
+            if child.wait().is_ok() {
+                return;
+            }
+        }
+    }
+    print!("{}", text);
+}
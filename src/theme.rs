@@ -0,0 +1,117 @@
+use colored::{Color, ColoredString, Colorize};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Color palette selected with `THEME`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Theme {
+    /// Bright colors for dark terminal backgrounds (the default).
+    Dark,
+    /// Darker colors that stay readable on light backgrounds.
+    Light,
+    /// No colors at all, like `--no-color`.
+    Mono,
+}
+
+impl Theme {
+    pub fn parse(name: &str) -> Option<Theme> {
+        match name.to_lowercase().as_str() {
+            "dark" => Some(Theme::Dark),
+            "light" => Some(Theme::Light),
+            "mono" => Some(Theme::Mono),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Theme::Dark => "dark",
+            Theme::Light => "light",
+            Theme::Mono => "mono",
+        }
+    }
+}
+
+/// The colors behind every named style.
+pub struct Palette {
+    pub ok: Color,
+    pub warning: Color,
+    pub error: Color,
+    pub accent: Color,
+    pub value: Color,
+    pub dim: Color,
+    pub label: Color,
+}
+
+const DARK: Palette = Palette {
+    ok: Color::Green,
+    warning: Color::Yellow,
+    error: Color::Red,
+    accent: Color::BrightCyan,
+    value: Color::BrightWhite,
+    dim: Color::BrightBlack,
+    label: Color::BrightMagenta,
+};
+
+// Yellow, bright white and bright cyan all wash out on a white background.
+const LIGHT: Palette = Palette {
+    ok: Color::Green,
+    warning: Color::Magenta,
+    error: Color::Red,
+    accent: Color::Blue,
+    value: Color::Black,
+    dim: Color::BrightBlack,
+    label: Color::Cyan,
+};
+
+static CURRENT: AtomicU8 = AtomicU8::new(Theme::Dark as u8);
+
+/// Selects the palette for the rest of the process. `Mono` turns color off entirely.
+pub fn set(theme: Theme) {
+    CURRENT.store(theme as u8, Ordering::Relaxed);
+    if theme == Theme::Mono {
+        colored::control::set_override(false);
+    }
+}
+
+pub fn palette() -> &'static Palette {
+    if CURRENT.load(Ordering::Relaxed) == Theme::Light as u8 { &LIGHT } else { &DARK }
+}
+
+/// The `[negma]` prefix and other success markers.
+pub fn style_header(text: impl AsRef<str>) -> ColoredString {
+    text.as_ref().color(palette().ok).bold()
+}
+
+/// Warning and `[dry-run]` prefixes, hints, and prompts.
+pub fn style_warning(text: impl AsRef<str>) -> ColoredString {
+    text.as_ref().color(palette().warning).bold()
+}
+
+pub fn style_error(text: impl AsRef<str>) -> ColoredString {
+    text.as_ref().color(palette().error).bold()
+}
+
+/// Paths, commands, and other secondary detail.
+pub fn style_dim(text: impl AsRef<str>) -> ColoredString {
+    text.as_ref().color(palette().dim)
+}
+
+/// Text that should stand out from the surrounding output.
+pub fn style_value(text: impl AsRef<str>) -> ColoredString {
+    text.as_ref().color(palette().value)
+}
+
+/// Command names and generation numbers.
+pub fn style_accent(text: impl AsRef<str>) -> ColoredString {
+    text.as_ref().color(palette().accent)
+}
+
+/// Section titles in help and summaries.
+pub fn style_title(text: impl AsRef<str>) -> ColoredString {
+    style_value(text).underline()
+}
+
+/// User-given generation labels.
+pub fn style_label(text: impl AsRef<str>) -> ColoredString {
+    text.as_ref().color(palette().label)
+}
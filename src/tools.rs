@@ -0,0 +1,58 @@
+use std::{env, os::unix::fs::PermissionsExt, path::PathBuf};
+
+/// Resolves `program` against `PATH` like a shell would, returning the executable found.
+///
+/// Names containing a `/` are checked as given.
+pub fn find_in_path(program: &str) -> Option<PathBuf> {
+    let is_executable = |path: &PathBuf| {
+        path.metadata()
+            .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+    };
+
+    if program.contains('/') {
+        let path = PathBuf::from(program);
+        return is_executable(&path).then_some(path);
+    }
+
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(is_executable)
+}
+
+/// Where a program was found by [`lookup`].
+#[derive(Debug, PartialEq)]
+pub enum Lookup {
+    /// On this process's `PATH`.
+    Found(PathBuf),
+    /// Installed in a standard nix profile, but not on this `PATH`. Typically `sudo`
+    /// replacing `PATH` with its `secure_path`, or a user profile that root doesn't see.
+    OffPath(PathBuf),
+    Missing,
+}
+
+/// Resolves `program` on `PATH`, falling back to the bin directories of the system,
+/// default, root, and invoking user's (`SUDO_USER` or `USER`) nix profiles.
+pub fn lookup(program: &str) -> Lookup {
+    if let Some(path) = find_in_path(program) {
+        return Lookup::Found(path);
+    }
+    if program.contains('/') {
+        return Lookup::Missing;
+    }
+
+    let mut dirs = vec![
+        PathBuf::from("/run/current-system/sw/bin"),
+        PathBuf::from("/nix/var/nix/profiles/default/bin"),
+        PathBuf::from("/root/.nix-profile/bin"),
+    ];
+    if let Some(user) = env::var("SUDO_USER").ok().or_else(|| env::var("USER").ok()) {
+        dirs.push(PathBuf::from(format!("/etc/profiles/per-user/{}/bin", user)));
+        if let Ok(Some(user)) = nix::unistd::User::from_name(&user) {
+            dirs.push(user.dir.join(".nix-profile/bin"));
+        }
+    }
+    match dirs.iter().find_map(|dir| find_in_path(&dir.join(program).to_string_lossy())) {
+        Some(path) => Lookup::OffPath(path),
+        None => Lookup::Missing,
+    }
+}
@@ -0,0 +1,1613 @@
+//! End-to-end tests that run the negma binary against stub `nix-env`, `home-manager`,
+//! `nixos-rebuild`, etc. placed first on `PATH`. Each stub appends its name and
+//! arguments to a log so tests can assert exactly which commands negma spawned;
+//! `NEGMA_STUB_EXIT`, `NEGMA_STUB_STDOUT` and `NEGMA_STUB_STDERR` make them fail or print.
+//! `nix` commands need root, so those run in a user namespace when the tests are not root.
+
+use assert_cmd::Command;
+use predicates::{prelude::*, str::contains};
+use std::{fs, os::unix::fs::PermissionsExt, path::PathBuf};
+use tempfile::TempDir;
+
+const STUBS: &[&str] = &[
+    "nixos-rebuild",
+    "home-manager",
+    "nix-env",
+    "nix",
+    "nix-collect-garbage",
+    "nix-channel",
+    "nix-instantiate",
+    "nix-store",
+    "alejandra",
+];
+
+struct Sandbox {
+    dir: TempDir,
+}
+
+impl Sandbox {
+    /// A fresh HOME with `config` as the negma config and a directory of recording stubs.
+    fn new(config: &str) -> Self {
+        let dir = TempDir::new().unwrap();
+        let config_dir = dir.path().join("home/.config/negma");
+        fs::create_dir_all(&config_dir).unwrap();
+        fs::write(config_dir.join("config.cfg"), config).unwrap();
+
+        let bin = dir.path().join("bin");
+        fs::create_dir_all(&bin).unwrap();
+        for name in STUBS {
+            let stub = bin.join(name);
+            fs::write(
+                &stub,
+                format!(
+                    "#!/bin/sh\necho \"{} $*\" >> \"$NEGMA_STUB_LOG\"\n\
+                     [ -n \"$NEGMA_STUB_STDOUT\" ] && printf '%s\\n' \"$NEGMA_STUB_STDOUT\"\n\
+                     [ -n \"$NEGMA_STUB_STDERR\" ] && echo \"$NEGMA_STUB_STDERR\" >&2\n\
+                     exit \"${{NEGMA_STUB_EXIT:-0}}\"\n",
+                    name
+                ),
+            )
+            .unwrap();
+            fs::set_permissions(&stub, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        Self { dir }
+    }
+
+    fn home(&self) -> PathBuf {
+        self.dir.path().join("home")
+    }
+
+    fn negma(&self, args: &[&str]) -> Command {
+        self.with_env(Command::cargo_bin("negma").unwrap(), args)
+    }
+
+    /// Like [`Sandbox::negma`], for commands negma only runs as root (`nix ...`). Without root
+    /// it runs in a user namespace that maps the caller to root.
+    fn negma_as_root(&self, args: &[&str]) -> Command {
+        if is_root() {
+            return self.negma(args);
+        }
+        // Looked up here: some tests give negma a PATH without it.
+        let unshare = std::env::split_paths(&std::env::var_os("PATH").unwrap_or_default())
+            .map(|dir| dir.join("unshare"))
+            .find(|path| path.exists())
+            .expect("unshare (util-linux) is needed to run the nix tests without root");
+        let mut cmd = Command::new(unshare);
+        cmd.args(["--user", "--map-root-user", env!("CARGO_BIN_EXE_negma")]);
+        self.with_env(cmd, args)
+    }
+
+    fn with_env(&self, mut cmd: Command, args: &[&str]) -> Command {
+        let path = format!(
+            "{}:{}",
+            self.dir.path().join("bin").display(),
+            std::env::var("PATH").unwrap_or_default()
+        );
+        cmd.args(args)
+            .current_dir(self.dir.path())
+            .env("HOME", self.home())
+            .env("PATH", path)
+            .env("NO_COLOR", "1")
+            .env("NEGMA_STUB_LOG", self.dir.path().join("calls.log"));
+        cmd
+    }
+
+    /// Commands the stubs recorded, one `<program> <args>` line each.
+    fn calls(&self) -> Vec<String> {
+        fs::read_to_string(self.dir.path().join("calls.log"))
+            .unwrap_or_default()
+            .lines()
+            .map(|l| l.trim_end().to_string())
+            .collect()
+    }
+
+    fn history(&self) -> String {
+        fs::read_to_string(self.home().join(".config/negma/history.log")).unwrap_or_default()
+    }
+}
+
+const BASE_CONFIG: &str = "EDITOR = true\nAUTO_GC = false\nAUTO_FMT = false\nFORMATTER = alejandra\n";
+
+fn config(extra: &str) -> String {
+    format!("{}{}", BASE_CONFIG, extra)
+}
+
+fn is_root() -> bool {
+    nix::unistd::Uid::effective().is_root()
+}
+
+#[test]
+fn home_make_runs_home_manager_switch() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox.negma(&["home", "make"]).assert().success();
+    assert_eq!(sandbox.calls(), ["home-manager switch"]);
+}
+
+#[test]
+fn home_make_target_user_resolves_the_user_from_passwd() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox
+        .negma_as_root(&["home", "make", "--target-user", "negma-no-such-user"])
+        .assert()
+        .code(1)
+        .stderr(contains("Unknown user 'negma-no-such-user'"));
+    sandbox
+        .negma_as_root(&["home", "make", "--target-user", "root"])
+        .assert()
+        .success()
+        .stdout(contains("Applying home-manager switch for root"));
+    assert_eq!(sandbox.calls(), ["home-manager switch"]);
+}
+
+#[test]
+fn home_switch_is_a_synonym_for_make() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox.negma(&["h", "switch"]).assert().success();
+    assert_eq!(sandbox.calls(), ["home-manager switch"]);
+}
+
+#[test]
+fn home_gc_expires_generations() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox.negma(&["home", "gc"]).assert().success();
+    assert_eq!(sandbox.calls(), ["home-manager expire-generations -d"]);
+}
+
+#[test]
+fn home_fmt_runs_the_configured_formatter() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox.negma(&["home", "fmt"]).assert().success();
+    let home_manager_dir = sandbox.home().join(".config/home-manager");
+    assert_eq!(sandbox.calls(), [format!("alejandra {}", home_manager_dir.display())]);
+}
+
+#[test]
+fn dry_run_spawns_nothing() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox
+        .negma(&["--dry-run", "home", "make"])
+        .assert()
+        .success()
+        .stdout(contains("[dry-run] home-manager switch"));
+    assert!(sandbox.calls().is_empty());
+    assert!(sandbox.history().is_empty());
+}
+
+#[test]
+fn failed_command_exits_nonzero_and_is_recorded() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox
+        .negma(&["home", "make"])
+        .env("NEGMA_STUB_EXIT", "3")
+        .assert()
+        .code(1)
+        .stderr(contains("home-manager switch failed (exit code 3)"));
+
+    let history = sandbox.history();
+    let fields: Vec<&str> = history.lines().last().unwrap().split('\t').collect();
+    assert_eq!(&fields[1..3], ["1", "home make"]);
+}
+
+#[test]
+fn unknown_subcommand_fails() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox.negma(&["home", "frobnicate"]).assert().code(1);
+    assert!(sandbox.calls().is_empty());
+}
+
+#[test]
+fn nix_make_with_flake_passes_flake_to_nixos_rebuild() {
+    let sandbox = Sandbox::new(&config("SYSTEM_FLAKE = /etc/nixos#host\n"));
+    sandbox.negma_as_root(&["nix", "make"]).assert().success();
+    assert_eq!(sandbox.calls(), ["nixos-rebuild switch --flake /etc/nixos#host"]);
+}
+
+#[test]
+fn nix_make_follows_make_mode() {
+    let sandbox = Sandbox::new(&config("MAKE_MODE = boot\n"));
+    sandbox.negma_as_root(&["nix", "make"]).assert().success();
+    assert_eq!(sandbox.calls(), ["nixos-rebuild boot"]);
+}
+
+#[test]
+fn nix_make_appends_rebuild_flags_and_shortcuts() {
+    let sandbox = Sandbox::new(&config(
+        "SYSTEM_FLAKE = .#host\nREBUILD_FLAGS = --show-trace\n",
+    ));
+    sandbox.negma_as_root(&["--impure", "nix", "make"]).assert().success();
+    assert_eq!(
+        sandbox.calls(),
+        ["nixos-rebuild switch --flake .#host --show-trace --impure"]
+    );
+}
+
+#[test]
+fn per_command_flags_reach_only_their_command() {
+    let sandbox = Sandbox::new(&config("NIX_CHECK_FLAGS = --show-trace\nNIX_MAKE_FLAGS = --cores 8 --fallback\n"));
+    sandbox.negma_as_root(&["nix", "check"]).assert().success();
+    sandbox.negma_as_root(&["--cores", "2", "nix", "make"]).assert().success();
+    assert_eq!(
+        sandbox.calls(),
+        ["nix-instantiate <nixpkgs/nixos> -A system --show-trace", "nixos-rebuild switch --cores 2 --fallback"]
+    );
+}
+
+#[test]
+fn confirm_make_prints_the_summary_and_can_be_declined() {
+    let sandbox = Sandbox::new(&config("SYSTEM_FLAKE = /etc/nixos#box\nCONFIRM_MAKE = true\n"));
+    sandbox
+        .negma_as_root(&["nix", "make"])
+        .write_stdin("n\n")
+        .assert()
+        .success()
+        .stdout(contains("About to run nixos-rebuild switch").and(contains("host box")).and(contains("Cancelled")));
+    assert!(sandbox.calls().is_empty());
+    assert!(sandbox.history().is_empty());
+
+    sandbox.negma_as_root(&["nix", "make", "--yes"]).assert().success();
+    assert_eq!(sandbox.calls(), ["nixos-rebuild switch --flake /etc/nixos#box"]);
+}
+
+#[test]
+fn nix_build_json_prints_only_the_result_path() {
+    let sandbox = Sandbox::new(&config(""));
+    let toplevel = "/nix/store/3x1k9c0a-nixos-system-box-24.05";
+    std::os::unix::fs::symlink(toplevel, sandbox.dir.path().join("result")).unwrap();
+    sandbox
+        .negma_as_root(&["nix", "build", "--json"])
+        .env("NEGMA_STUB_STDOUT", "building the system configuration...")
+        .assert()
+        .success()
+        .stdout(format!("{{\"out\":\"{}\"}}\n", toplevel))
+        .stderr(contains("building the system configuration..."));
+}
+
+#[test]
+fn dirty_tree_warning_is_summarized_or_silenced_with_dirty() {
+    let sandbox = Sandbox::new(&config("SYSTEM_FLAKE = /etc/nixos#box\n"));
+    sandbox
+        .negma_as_root(&["nix", "make"])
+        .env("NEGMA_STUB_STDERR", "warning: Git tree '/etc/nixos' is dirty")
+        .assert()
+        .success()
+        .stderr(contains("uncommitted changes (nix warned 1x)"));
+    sandbox.negma_as_root(&["nix", "make", "--dirty"]).assert().success();
+    assert_eq!(
+        sandbox.calls(),
+        [
+            "nixos-rebuild switch --flake /etc/nixos#box",
+            "nixos-rebuild switch --flake /etc/nixos#box --option warn-dirty false",
+        ]
+    );
+}
+
+#[test]
+fn nix_gc_collects_garbage() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox.negma_as_root(&["nix", "gc"]).assert().success();
+    assert_eq!(sandbox.calls(), ["nix-env collect-garbage -d"]);
+}
+
+#[test]
+fn nix_gc_max_freed_converts_the_size_to_bytes() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox.negma_as_root(&["nix", "gc", "--max-freed", "5G"]).assert().success();
+    sandbox
+        .negma_as_root(&["nix", "gc", "--max-freed", "5X"])
+        .assert()
+        .code(1)
+        .stderr(contains("Invalid --max-freed value '5X'"));
+    assert_eq!(sandbox.calls(), ["nix-collect-garbage --max-freed 5368709120"]);
+}
+
+#[test]
+fn fallback_is_passed_once_from_config_or_flag() {
+    let sandbox = Sandbox::new(&config("FALLBACK = true\n"));
+    sandbox.negma(&["--fallback", "home", "make"]).assert().success();
+    assert_eq!(sandbox.calls(), ["home-manager switch --fallback"]);
+}
+
+#[test]
+fn env_assignments_reach_the_spawned_command() {
+    let sandbox = Sandbox::new(&config("env NIX_PATH = nixpkgs=/etc/nixpkgs\n"));
+    sandbox
+        .negma(&["--dry-run", "--env", "NIXPKGS_ALLOW_UNFREE=1", "home", "make"])
+        .assert()
+        .success()
+        .stdout(contains(
+            "[dry-run] NIXPKGS_ALLOW_UNFREE=1 NIX_PATH=nixpkgs=/etc/nixpkgs home-manager switch",
+        ));
+}
+
+#[test]
+fn malformed_env_assignment_is_rejected() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox
+        .negma(&["--env", "NIXPKGS_ALLOW_UNFREE", "home", "make"])
+        .assert()
+        .code(1)
+        .stderr(contains("Invalid --env value"));
+    assert!(sandbox.calls().is_empty());
+}
+
+#[test]
+fn unfree_with_flake_sets_env_and_impure() {
+    let sandbox = Sandbox::new(&config("SYSTEM_FLAKE = .#host\n"));
+    sandbox
+        .negma_as_root(&["--dry-run", "--unfree", "nix", "make"])
+        .assert()
+        .success()
+        .stdout(contains(
+            "[dry-run] NIXPKGS_ALLOW_UNFREE=1 nixos-rebuild switch --flake .#host --impure",
+        ));
+}
+
+#[test]
+fn permit_insecure_sets_env_and_impure_and_names_the_packages() {
+    let sandbox = Sandbox::new(&config("SYSTEM_FLAKE = .#host\nPERMITTED_INSECURE = openssl-1.1.1w\n"));
+    sandbox
+        .negma_as_root(&["--dry-run", "--permit-insecure", "python-2.7.18", "nix", "make"])
+        .assert()
+        .success()
+        .stdout(contains("[dry-run] NIXPKGS_ALLOW_INSECURE=1 nixos-rebuild switch --flake .#host --impure"))
+        .stderr(contains("Permitting insecure packages (openssl-1.1.1w, python-2.7.18)"));
+}
+
+#[test]
+fn failed_build_remembers_the_derivation_for_rebuild_log() {
+    let drv = "/nix/store/0c9xrl8h6n4x8r1yqs2zfiag3pmd1wl4-foo-1.0.drv";
+    let sandbox = Sandbox::new(&config(""));
+    sandbox
+        .negma_as_root(&["nix", "make"])
+        .env("NEGMA_STUB_EXIT", "1")
+        .env(
+            "NEGMA_STUB_STDERR",
+            format!("error: builder for '{}' failed with exit code 2", drv),
+        )
+        .assert()
+        .code(1)
+        .stderr(contains(format!("nix log {}", drv)));
+
+    sandbox.negma_as_root(&["nix", "rebuild-log"]).assert().success();
+    assert_eq!(
+        sandbox.calls().last().unwrap(),
+        &format!("nix --extra-experimental-features nix-command log {}", drv)
+    );
+}
+
+#[test]
+fn empty_config_is_replaced_with_the_default() {
+    let sandbox = Sandbox::new("  \n");
+    sandbox
+        .negma(&["history"])
+        .assert()
+        .success()
+        .stdout(contains("configuration file is empty"));
+    let written = fs::read_to_string(sandbox.home().join(".config/negma/config.cfg")).unwrap();
+    assert!(written.contains("# Negma Configuration File"));
+}
+
+#[test]
+fn config_init_only_overwrites_with_force() {
+    let sandbox = Sandbox::new(&config(""));
+    let path = sandbox.home().join(".config/negma/config.cfg");
+
+    sandbox.negma(&["config", "init"]).assert().success();
+    assert_eq!(fs::read_to_string(&path).unwrap(), config(""));
+
+    sandbox.negma(&["config", "init", "--force"]).assert().success();
+    assert!(fs::read_to_string(&path).unwrap().contains("# Negma Configuration File"));
+}
+
+#[test]
+fn config_reset_backs_up_after_confirmation() {
+    let sandbox = Sandbox::new(&config(""));
+    let path = sandbox.home().join(".config/negma/config.cfg");
+    let backup = sandbox.home().join(".config/negma/config.cfg.bak");
+
+    sandbox.negma(&["config", "reset"]).write_stdin("n\n").assert().success();
+    assert!(!backup.exists());
+
+    sandbox.negma(&["config", "reset"]).write_stdin("y\n").assert().success();
+    assert_eq!(fs::read_to_string(&backup).unwrap(), config(""));
+    assert!(fs::read_to_string(&path).unwrap().contains("# Negma Configuration File"));
+}
+
+#[test]
+fn config_default_prints_the_template() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox
+        .negma(&["config", "default"])
+        .assert()
+        .success()
+        .stdout(contains("# Negma Configuration File"));
+}
+
+#[test]
+fn sudo_cmd_is_not_checked_when_the_config_is_read() {
+    let sandbox = Sandbox::new(&config("SUDO_CMD = negma-no-such-sudo\n"));
+    sandbox
+        .negma(&["config", "get", "SUDO_CMD"])
+        .assert()
+        .success()
+        .stdout("negma-no-such-sudo\n")
+        .stderr("");
+    sandbox.negma(&["config", "default"]).assert().success().stdout(contains("\n# SUDO_CMD = doas\n"));
+}
+
+#[test]
+fn config_from_stdin_replaces_the_file() {
+    let sandbox = Sandbox::new("FORMATTER = nixfmt\n");
+    sandbox
+        .negma(&["--config", "-", "home", "fmt"])
+        .write_stdin("FORMATTER = alejandra\nAUTO_GC = true\n")
+        .assert()
+        .success();
+    assert_eq!(sandbox.calls().len(), 1);
+    assert!(sandbox.calls()[0].starts_with("alejandra "));
+    assert!(!sandbox.home().join(".config/negma/auto_gc_marker").exists());
+}
+
+#[test]
+fn home_diff_defaults_to_previous_and_current_generation() {
+    let sandbox = Sandbox::new(&config(""));
+    let profiles = sandbox.home().join(".local/state/nix/profiles");
+    fs::create_dir_all(&profiles).unwrap();
+    for n in [1, 3] {
+        fs::create_dir(profiles.join(format!("home-manager-{}-link", n))).unwrap();
+    }
+    std::os::unix::fs::symlink("home-manager-3-link", profiles.join("home-manager")).unwrap();
+
+    sandbox.negma(&["home", "diff"]).assert().success();
+    let profile = profiles.join("home-manager");
+    assert_eq!(
+        sandbox.calls(),
+        [format!(
+            "nix --extra-experimental-features nix-command store diff-closures {0}-1-link {0}-3-link",
+            profile.display()
+        )]
+    );
+}
+
+#[test]
+fn auto_fmt_flags_override_the_config() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox.negma(&["--auto-fmt", "home", "edit"]).assert().success();
+    assert_eq!(sandbox.calls().len(), 1);
+
+    let sandbox = Sandbox::new(&config("AUTO_FMT = true\n"));
+    sandbox.negma(&["--no-auto-fmt", "home", "edit"]).assert().success();
+    assert!(sandbox.calls().is_empty());
+}
+
+#[test]
+fn formatter_that_empties_a_file_can_be_undone() {
+    let sandbox = Sandbox::new("");
+    let clobber = sandbox.dir.path().join("clobber");
+    fs::write(&clobber, "#!/bin/sh\n: > \"$1/home.nix\"\n").unwrap();
+    fs::set_permissions(&clobber, fs::Permissions::from_mode(0o755)).unwrap();
+    fs::write(
+        sandbox.home().join(".config/negma/config.cfg"),
+        config(&format!("FORMATTER = {}\n", clobber.display())),
+    )
+    .unwrap();
+
+    let home_nix = sandbox.home().join(".config/home-manager/home.nix");
+    fs::create_dir_all(home_nix.parent().unwrap()).unwrap();
+    fs::write(&home_nix, "{ ... }: { }\n").unwrap();
+
+    sandbox
+        .negma(&["home", "fmt"])
+        .write_stdin("y\n")
+        .assert()
+        .code(1)
+        .stderr(contains("is empty after formatting"));
+    assert_eq!(fs::read_to_string(&home_nix).unwrap(), "{ ... }: { }\n");
+}
+
+#[test]
+fn doctor_json_lists_checks_and_overall_health() {
+    let sandbox = Sandbox::new(&config("FORMATTER = definitely-not-a-formatter\n"));
+    let output = sandbox.negma(&["doctor", "--json"]).assert().code(1).get_output().stdout.clone();
+
+    let report: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    let checks = report["checks"].as_array().unwrap();
+    let check = |name: &str| checks.iter().find(|c| c["name"] == name).unwrap()["ok"].clone();
+    assert_eq!(check("nix_on_path"), true);
+    assert_eq!(check("formatter_on_path"), false);
+    assert_eq!(report["ok"], false);
+}
+
+#[test]
+fn top_level_gc_runs_home_then_system_phase() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox
+        .negma_as_root(&["gc"])
+        .env_remove("SUDO_USER")
+        .assert()
+        .success()
+        .stdout(contains("GC summary"));
+    assert_eq!(
+        sandbox.calls(),
+        ["home-manager expire-generations -d", "nix-collect-garbage -d"]
+    );
+}
+
+#[test]
+fn home_clean_keeps_current_and_newest_n() {
+    let sandbox = Sandbox::new(&config(""));
+    let listing = (1..=5)
+        .rev()
+        .map(|n| {
+            let current = if n == 4 { " (current)" } else { "" };
+            format!("2024-01-0{0} 10:00 : id {0} -> /nix/store/hash-home-manager-generation{1}", n, current)
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    sandbox
+        .negma(&["home", "clean", "--keep", "1"])
+        .env("NEGMA_STUB_STDOUT", listing)
+        .assert()
+        .success();
+    assert_eq!(
+        sandbox.calls(),
+        ["home-manager generations", "home-manager remove-generations 1 2 3"]
+    );
+}
+
+#[test]
+fn list_generations_since_filters_by_date() {
+    let sandbox = Sandbox::new(&config(""));
+    let listing = "2024-03-01 10:00 : id 3 -> /nix/store/c-home (current)\n\
+                   2024-02-01 10:00 : id 2 -> /nix/store/b-home\n\
+                   2024-01-01 10:00 : id 1 -> /nix/store/a-home";
+    sandbox
+        .negma(&["home", "list-generations", "--since", "2024-02-01"])
+        .env("NEGMA_STUB_STDOUT", listing)
+        .assert()
+        .success()
+        .stdout(contains("Showing 2 of 3"))
+        .stdout(contains("/nix/store/b-home"))
+        .stdout(contains("/nix/store/a-home").not());
+}
+
+#[test]
+fn target_profile_is_passed_as_profile_name() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox.negma_as_root(&["nix", "make", "--target-profile", "testing"]).assert().success();
+    assert_eq!(sandbox.calls(), ["nixos-rebuild switch --profile-name testing"]);
+}
+
+#[test]
+fn rollback_last_ignores_makes_into_a_target_profile() {
+    let sandbox = Sandbox::new(&config(""));
+    fs::write(sandbox.home().join(".config/negma/history.log"), "2024-01-01 10:00:00\t0\tnix make\t41\n").unwrap();
+    sandbox.negma_as_root(&["nix", "make", "--target-profile", "testing"]).assert().success();
+    assert!(sandbox.history().ends_with("\t0\tnix make --target-profile testing\n"));
+
+    sandbox
+        .negma_as_root(&["nix", "rollback", "--last"])
+        .assert()
+        .success()
+        .stdout(contains("Rolling back to generation 41"));
+    assert_eq!(
+        sandbox.calls().last().unwrap(),
+        "nix-env --profile /nix/var/nix/profiles/system --switch-generation 41"
+    );
+}
+
+#[test]
+fn rollback_last_refuses_a_make_without_a_recorded_generation() {
+    let sandbox = Sandbox::new(&config(""));
+    fs::write(
+        sandbox.home().join(".config/negma/history.log"),
+        "2024-01-01 10:00:00\t0\tnix make\t41\n\
+         2024-01-02 10:00:00\t0\tnix make --target-host root@web\n\
+         2024-01-03 10:00:00\t0\thome make\n",
+    )
+    .unwrap();
+    sandbox
+        .negma(&["home", "rollback", "--last"])
+        .assert()
+        .code(1)
+        .stderr(contains("The last home make has no recorded previous generation"));
+    sandbox
+        .negma_as_root(&["nix", "rollback", "--last"])
+        .assert()
+        .code(1)
+        .stderr(contains("The last nix make has no recorded previous generation"))
+        .stderr(contains("negma nix make --target-host root@web"));
+    assert!(sandbox.calls().is_empty());
+}
+
+#[test]
+fn profile_names_are_validated_before_building_paths() {
+    let sandbox = Sandbox::new(&config(""));
+    for name in ["", "..", "../../etc", "a/b"] {
+        sandbox
+            .negma(&["nix", "make", "--target-profile", name])
+            .assert()
+            .code(1)
+            .stderr(contains(format!("Invalid --target-profile name '{}'", name)));
+    }
+    sandbox
+        .negma_as_root(&["nix", "list-generations", "--profile", "../system"])
+        .assert()
+        .code(1)
+        .stderr(contains("Invalid --profile name '../system'"));
+    assert!(sandbox.calls().is_empty());
+
+    // `system` is the main system profile, not `system-profiles/system`.
+    sandbox.negma_as_root(&["nix", "list-generations", "--profile", "system"]).assert().success();
+    assert_eq!(sandbox.calls(), ["nix-env --profile /nix/var/nix/profiles/system --list-generations"]);
+}
+
+#[test]
+fn require_clean_git_refuses_dirty_config_unless_forced() {
+    let sandbox = Sandbox::new(&config("GIT = https://example.com/dotfiles\nREQUIRE_CLEAN_GIT = true\n"));
+    let repo = sandbox.home().join(".config/home-manager");
+    fs::create_dir_all(&repo).unwrap();
+    let git = |args: &[&str]| {
+        std::process::Command::new("git").arg("-C").arg(&repo).args(args).output().unwrap()
+    };
+    if !git(&["init", "-q"]).status.success() {
+        return;
+    }
+    fs::write(repo.join("home.nix"), "{ }\n").unwrap();
+
+    sandbox
+        .negma(&["home", "make"])
+        .assert()
+        .code(1)
+        .stderr(contains("Uncommitted changes"));
+    assert!(sandbox.calls().is_empty());
+
+    sandbox.negma(&["home", "make", "--force"]).assert().success();
+    assert_eq!(sandbox.calls(), ["home-manager switch"]);
+}
+
+#[test]
+fn pre_check_aborts_make_on_a_parse_error() {
+    let sandbox = Sandbox::new(&config("PRE_CHECK = true\n"));
+    let home_nix = sandbox.home().join(".config/home-manager/home.nix");
+    fs::create_dir_all(home_nix.parent().unwrap()).unwrap();
+    fs::write(&home_nix, "{ ... }: {\n").unwrap();
+
+    sandbox
+        .negma(&["home", "make"])
+        .env("NEGMA_STUB_EXIT", "1")
+        .env("NEGMA_STUB_STDERR", "error: syntax error, unexpected end of file")
+        .assert()
+        .code(1)
+        .stderr(contains("Syntax error in"))
+        .stderr(contains("unexpected end of file"));
+    assert_eq!(sandbox.calls(), [format!("nix-instantiate --parse {}", home_nix.display())]);
+}
+
+#[test]
+fn show_config_evaluates_the_attribute_of_the_flake_host() {
+    let sandbox = Sandbox::new(&config("SYSTEM_FLAKE = /etc/nixos#box\n"));
+    sandbox.negma_as_root(&["nix", "show-config", "services.openssh.enable"]).assert().success();
+    assert_eq!(
+        sandbox.calls(),
+        ["nix --extra-experimental-features nix-command flakes eval /etc/nixos#nixosConfigurations.box.config.services.openssh.enable"]
+    );
+}
+
+#[test]
+fn home_rollback_steps_back_from_the_current_generation() {
+    let sandbox = Sandbox::new(&config(""));
+    let listing = "2024-01-04 10:00 : id 4 -> /nix/store/d-home\n\
+                   2024-01-03 10:00 : id 3 -> /nix/store/c-home (current)\n\
+                   2024-01-02 10:00 : id 2 -> /nix/store/b-home\n\
+                   2024-01-01 10:00 : id 1 -> /nix/store/a-home";
+    sandbox
+        .negma(&["--dry-run", "home", "rollback", "--steps", "2"])
+        .env("NEGMA_STUB_STDOUT", listing)
+        .assert()
+        .success()
+        .stdout(contains("[dry-run] /nix/store/a-home/activate"));
+
+    sandbox
+        .negma(&["home", "rollback", "--steps", "3"])
+        .env("NEGMA_STUB_STDOUT", listing)
+        .assert()
+        .code(1)
+        .stderr(contains("Only 2 generation(s) are older"));
+}
+
+#[test]
+fn rollback_refuses_on_the_oldest_generation() {
+    let sandbox = Sandbox::new(&config(""));
+    let listing = "2024-01-02 10:00 : id 2 -> /nix/store/b-home\n\
+                   2024-01-01 10:00 : id 1 -> /nix/store/a-home (current)";
+    sandbox
+        .negma(&["home", "rollback"])
+        .env("NEGMA_STUB_STDOUT", listing)
+        .assert()
+        .code(1)
+        .stderr(contains("Already on the oldest generation").and(contains("Generation 1 is the oldest")));
+    assert_eq!(sandbox.calls(), ["home-manager generations"]);
+}
+
+#[test]
+fn upgrade_plan_previews_then_applies_with_yes() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox
+        .negma_as_root(&["nix", "upgrade", "--plan", "--yes"])
+        .assert()
+        .success()
+        .stdout(contains("Channels that would update"));
+    assert_eq!(
+        sandbox.calls(),
+        ["nix-channel --list", "nix-channel --update", "nixos-rebuild switch"]
+    );
+
+    let sandbox = Sandbox::new(&config(""));
+    sandbox.negma_as_root(&["nix", "upgrade", "--plan", "-y"]).assert().success();
+    assert_eq!(
+        sandbox.calls(),
+        ["nix-channel --list", "nix-channel --update", "nixos-rebuild switch"]
+    );
+}
+
+#[test]
+fn upgrade_plan_applies_nothing_when_declined() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox.negma_as_root(&["nix", "upgrade", "--plan"]).write_stdin("n\n").assert().success();
+    assert_eq!(sandbox.calls(), ["nix-channel --list"]);
+}
+
+#[test]
+fn builders_use_substitutes_only_applies_with_builders() {
+    let sandbox = Sandbox::new(&config("BUILDERS_USE_SUBSTITUTES = true\n"));
+    sandbox
+        .negma_as_root(&["nix", "make"])
+        .assert()
+        .success()
+        .stderr(contains("builders-use-substitutes ignored"));
+    sandbox.negma_as_root(&["nix", "make", "--builders", "ssh://builder"]).assert().success();
+    assert_eq!(
+        sandbox.calls(),
+        [
+            "nixos-rebuild switch",
+            "nixos-rebuild switch --builders ssh://builder --max-jobs 0 --option builders-use-substitutes true",
+        ]
+    );
+}
+
+#[test]
+fn list_generations_reverse_with_limit_shows_newest_first() {
+    let sandbox = Sandbox::new(&config(""));
+    let listing = "2024-03-01 10:00 : id 3 -> /nix/store/c-home (current)\n\
+                   2024-02-01 10:00 : id 2 -> /nix/store/b-home\n\
+                   2024-01-01 10:00 : id 1 -> /nix/store/a-home";
+    let output = sandbox
+        .negma(&["home", "list-generations", "--reverse", "--limit", "2"])
+        .env("NEGMA_STUB_STDOUT", listing)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let output = String::from_utf8(output).unwrap();
+    let c = output.find("/nix/store/c-home").unwrap();
+    let b = output.find("/nix/store/b-home").unwrap();
+    assert!(c < b);
+    assert!(!output.contains("/nix/store/a-home"));
+}
+
+#[test]
+fn missing_home_directory_is_reported_up_front() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox
+        .negma(&["home", "make"])
+        .env("HOME", sandbox.dir.path().join("nowhere"))
+        .assert()
+        .code(1)
+        .stderr(contains("does not exist"));
+    assert!(sandbox.calls().is_empty());
+}
+
+#[test]
+fn cores_and_max_jobs_do_not_duplicate_rebuild_flags() {
+    let sandbox = Sandbox::new(&config("REBUILD_FLAGS = --max-jobs 8\nBUILD_CORES = 2\nBUILD_MAX_JOBS = 1\n"));
+    sandbox.negma_as_root(&["nix", "make", "--cores", "4"]).assert().success();
+    sandbox.negma_as_root(&["nix", "make", "--cores", "-1"]).assert().code(1).stderr(contains("Invalid --cores value"));
+    assert_eq!(sandbox.calls(), ["nixos-rebuild switch --max-jobs 8 --cores 4"]);
+}
+
+#[test]
+fn status_summarizes_without_spawning_nix() {
+    let sandbox = Sandbox::new(&config("SYSTEM_FLAKE = /etc/nixos#box\n"));
+    sandbox
+        .negma(&["status"])
+        .assert()
+        .success()
+        .stdout(contains("flake (/etc/nixos#box)"))
+        .stdout(contains("last auto GC never"));
+    assert!(sandbox.calls().is_empty());
+}
+
+#[test]
+fn skip_gc_leaves_the_marker_alone() {
+    let sandbox = Sandbox::new(&config("").replace("AUTO_GC = false", "AUTO_GC = true"));
+    let marker = sandbox.home().join(".config/negma/auto_gc_marker");
+    sandbox.negma(&["--skip-gc", "home", "make"]).assert().success();
+    assert!(!marker.exists());
+    assert_eq!(sandbox.calls(), ["home-manager switch"]);
+}
+
+#[test]
+fn fmt_prints_a_summary_of_changed_files() {
+    let sandbox = Sandbox::new("");
+    let touchy = sandbox.dir.path().join("touchy");
+    fs::write(&touchy, "#!/bin/sh\necho noisy formatter output\necho '{ }' > \"$1/a.nix\"\n").unwrap();
+    fs::set_permissions(&touchy, fs::Permissions::from_mode(0o755)).unwrap();
+    fs::write(
+        sandbox.home().join(".config/negma/config.cfg"),
+        config(&format!("FORMATTER = {}\n", touchy.display())),
+    )
+    .unwrap();
+    let dir = sandbox.home().join(".config/home-manager");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("a.nix"), "{}\n").unwrap();
+    fs::write(dir.join("b.nix"), "{ }\n").unwrap();
+
+    sandbox
+        .negma(&["home", "fmt"])
+        .assert()
+        .success()
+        .stdout(contains("Formatted 2 file(s), 1 changed."))
+        .stdout(contains("noisy").not());
+    sandbox
+        .negma(&["home", "fmt", "--quiet"])
+        .assert()
+        .success()
+        .stdout(contains("Formatted").not());
+}
+
+#[test]
+fn home_manager_dir_overrides_the_config_location() {
+    let sandbox = Sandbox::new("");
+    let dir = sandbox.dir.path().join("dotfiles/home");
+    fs::write(
+        sandbox.home().join(".config/negma/config.cfg"),
+        config(&format!("HOME_MANAGER_DIR = {}\n", dir.display())),
+    )
+    .unwrap();
+
+    sandbox
+        .negma(&["home", "fmt"])
+        .assert()
+        .code(1)
+        .stderr(contains("does not exist"))
+        .stderr(contains("HOME_MANAGER_DIR"));
+
+    fs::create_dir_all(&dir).unwrap();
+    sandbox.negma(&["home", "fmt"]).assert().success();
+    assert_eq!(sandbox.calls(), [format!("alejandra {}", dir.display())]);
+}
+
+#[test]
+fn use_remote_sudo_is_passed_in_flake_mode_only() {
+    let sandbox = Sandbox::new(&config("SYSTEM_FLAKE = /etc/nixos#box\n"));
+    sandbox.negma_as_root(&["--use-remote-sudo", "nix", "make"]).assert().success();
+    assert_eq!(
+        sandbox.calls(),
+        ["nixos-rebuild switch --flake /etc/nixos#box --use-remote-sudo"]
+    );
+
+    let sandbox = Sandbox::new(&config(""));
+    sandbox
+        .negma_as_root(&["--use-remote-sudo", "nix", "make"])
+        .assert()
+        .success()
+        .stderr(contains("--use-remote-sudo ignored"));
+    assert_eq!(sandbox.calls(), ["nixos-rebuild switch"]);
+}
+
+#[test]
+fn build_and_target_hosts_are_validated_and_passed() {
+    let sandbox = Sandbox::new(&config("SYSTEM_FLAKE = /etc/nixos#box\nTARGET_HOST = root@web\n"));
+    sandbox.negma_as_root(&["--build-host", "builder", "nix", "make"]).assert().success();
+    assert_eq!(
+        sandbox.calls(),
+        ["nixos-rebuild switch --flake /etc/nixos#box --build-host builder --target-host root@web"]
+    );
+    sandbox
+        .negma_as_root(&["nix", "boot", "--reboot"])
+        .assert()
+        .code(1)
+        .stderr(contains("--reboot cannot be combined with --target-host root@web"));
+    sandbox.negma_as_root(&["nix", "build"]).assert().code(1).stderr(contains("activates nothing"));
+
+    let sandbox = Sandbox::new(&config(""));
+    sandbox
+        .negma_as_root(&["--target-host", "root@web", "nix", "make"])
+        .assert()
+        .code(1)
+        .stderr(contains("need a flake"));
+    assert!(sandbox.calls().is_empty());
+}
+
+#[test]
+fn complete_rollback_lists_home_generations_newest_first() {
+    let sandbox = Sandbox::new(&config(""));
+    let profiles = sandbox.home().join(".local/state/nix/profiles");
+    fs::create_dir_all(&profiles).unwrap();
+    for n in [3, 10, 7] {
+        std::os::unix::fs::symlink("/nonexistent", profiles.join(format!("home-manager-{}-link", n))).unwrap();
+    }
+    sandbox
+        .negma(&["__complete", "rollback", "home"])
+        .assert()
+        .success()
+        .stdout("10\n7\n3\n");
+
+    sandbox
+        .negma(&["completions", "bash", "--dynamic"])
+        .assert()
+        .success()
+        .stdout(contains("negma __complete rollback $scope"));
+}
+
+#[test]
+fn verbose_nix_appends_capped_verbosity() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox.negma_as_root(&["--verbose-nix", "nix", "make"]).assert().success();
+    sandbox
+        .negma_as_root(&["nix", "make", "--verbose-nix=9"])
+        .assert()
+        .success()
+        .stderr(contains("capped at 5"));
+    assert_eq!(
+        sandbox.calls(),
+        ["nixos-rebuild switch -v", "nixos-rebuild switch -vvvvv"]
+    );
+}
+
+#[test]
+fn negative_keep_is_rejected_and_defaults_to_five() {
+    let sandbox = Sandbox::new(&config("KEEP = -3\n"));
+    let listing = (1..=7)
+        .rev()
+        .map(|n| {
+            let current = if n == 7 { " (current)" } else { "" };
+            format!("2024-01-0{0} 10:00 : id {0} -> /nix/store/hash-home-manager-generation{1}", n, current)
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    sandbox
+        .negma(&["home", "clean"])
+        .env("NEGMA_STUB_STDOUT", listing)
+        .assert()
+        .success()
+        .stderr(contains("invalid KEEP value at line 5, using 5"));
+    assert_eq!(
+        sandbox.calls(),
+        ["home-manager generations", "home-manager remove-generations 1"]
+    );
+}
+
+#[test]
+fn home_make_backup_copies_home_nix_before_switching() {
+    let sandbox = Sandbox::new(&config(""));
+    let dir = sandbox.home().join(".config/home-manager");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("home.nix"), "{ }\n").unwrap();
+
+    sandbox.negma(&["home", "make", "--backup"]).assert().success();
+    assert_eq!(fs::read_to_string(dir.join("home.nix.bak")).unwrap(), "{ }\n");
+    assert_eq!(sandbox.calls(), ["home-manager switch"]);
+}
+
+#[test]
+fn home_make_surfaces_unread_news() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox
+        .negma(&["home", "make"])
+        .env("NEGMA_STUB_STDERR", "There are 3 unread and relevant news items.")
+        .assert()
+        .success()
+        .stdout(contains("3 unread news items — run negma home news"));
+
+    sandbox.negma(&["home", "news"]).assert().success();
+    assert_eq!(sandbox.calls(), ["home-manager switch", "home-manager news"]);
+}
+
+#[test]
+fn gen_info_shows_one_generation() {
+    let sandbox = Sandbox::new(&config(""));
+    let listing = "  41   2024-01-01 09:00:00\n  42   2024-01-02 10:00:00   (current)";
+    sandbox
+        .negma_as_root(&["nix", "gen-info", "42"])
+        .env("NEGMA_STUB_STDOUT", listing)
+        .assert()
+        .success()
+        .stdout(contains("Generation 42 (current)").and(contains("2024-01-02 10:00:00")));
+    sandbox
+        .negma_as_root(&["nix", "gen-info", "7"])
+        .env("NEGMA_STUB_STDOUT", listing)
+        .assert()
+        .failure()
+        .stderr(contains("Generation 7 not found"));
+}
+
+#[test]
+fn timeout_kills_a_hung_command() {
+    let sandbox = Sandbox::new(&config(""));
+    fs::write(sandbox.dir.path().join("bin/home-manager"), "#!/bin/sh\nsleep 30\n").unwrap();
+    let started = std::time::Instant::now();
+    sandbox
+        .negma(&["--timeout", "1", "home", "make"])
+        .assert()
+        .failure()
+        .stderr(contains("timed out after 1s"));
+    assert!(started.elapsed() < std::time::Duration::from_secs(10));
+}
+
+#[test]
+fn interrupt_reaches_the_whole_child_process_group() {
+    let sandbox = Sandbox::new(&config(""));
+    let marker = sandbox.dir.path().join("grandchild");
+    fs::write(
+        sandbox.dir.path().join("bin/home-manager"),
+        format!("#!/bin/sh\nsh -c 'echo $$ > {}; exec sleep 30'\n", marker.display()),
+    )
+    .unwrap();
+    let path = format!("{}:{}", sandbox.dir.path().join("bin").display(), std::env::var("PATH").unwrap_or_default());
+    let mut negma = std::process::Command::new(assert_cmd::cargo::cargo_bin("negma"))
+        .args(["home", "make"])
+        .env("HOME", sandbox.home())
+        .env("PATH", path)
+        .env("NO_COLOR", "1")
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    let started = std::time::Instant::now();
+    let grandchild = loop {
+        if let Ok(pid) = fs::read_to_string(&marker)
+            && !pid.trim().is_empty()
+        {
+            break pid.trim().to_string();
+        }
+        assert!(started.elapsed() < std::time::Duration::from_secs(10), "home-manager stub never started");
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    };
+
+    let kill = |args: &[&str]| {
+        let mut kill = std::process::Command::new("kill");
+        kill.args(args).stderr(std::process::Stdio::null()).status().unwrap().success()
+    };
+    assert!(kill(&["-INT", &negma.id().to_string()]));
+    assert_eq!(negma.wait().unwrap().code(), Some(130));
+    assert!(started.elapsed() < std::time::Duration::from_secs(10));
+    assert!(!kill(&["-0", &grandchild]), "the grandchild survived the interrupt");
+}
+
+#[test]
+fn no_update_lockfile_is_passed_in_flake_mode_only() {
+    let sandbox = Sandbox::new(&config("SYSTEM_FLAKE = /etc/nixos#box\nNO_UPDATE_LOCKFILE = true\n"));
+    sandbox.negma_as_root(&["nix", "make"]).assert().success();
+    assert_eq!(
+        sandbox.calls(),
+        ["nixos-rebuild switch --flake /etc/nixos#box --no-update-lock-file --no-write-lock-file"]
+    );
+
+    let sandbox = Sandbox::new(&config(""));
+    sandbox
+        .negma_as_root(&["nix", "make", "--no-update-lockfile"])
+        .assert()
+        .success()
+        .stderr(contains("--no-update-lockfile ignored"));
+    assert_eq!(sandbox.calls(), ["nixos-rebuild switch"]);
+}
+
+#[test]
+fn explain_prints_the_resolved_command_without_running_it() {
+    let sandbox = Sandbox::new(&config("SYSTEM_FLAKE = /etc/nixos#box\nREBUILD_FLAGS = --show-trace\n"));
+    sandbox
+        .negma(&["explain", "nix", "make"])
+        .assert()
+        .success()
+        .stdout(contains("negma nix make").and(contains("$ nixos-rebuild switch --flake /etc/nixos#box --show-trace")));
+    sandbox
+        .negma(&["explain", "nix", "rebuild-log"])
+        .assert()
+        .failure()
+        .stderr(contains("Nothing to explain for 'nix rebuild-log'"));
+    assert!(sandbox.calls().is_empty());
+}
+
+#[test]
+fn explain_gc_includes_the_gc_options() {
+    let sandbox = Sandbox::new(&config("GC_KEEP_OUTPUTS = true\nGC_KEEP_DERIVATIONS = true\n"));
+    sandbox
+        .negma(&["explain", "nix", "gc"])
+        .assert()
+        .success()
+        .stdout(contains(
+            "$ nix-env collect-garbage -d --option keep-outputs true --option keep-derivations true",
+        ));
+    sandbox
+        .negma(&["explain", "gc"])
+        .assert()
+        .success()
+        .stdout(contains("$ nix-collect-garbage -d --option keep-outputs true --option keep-derivations true"));
+    assert!(sandbox.calls().is_empty());
+}
+
+#[test]
+fn aliases_substitute_placeholders() {
+    let sandbox = Sandbox::new(&config("alias hm = home $1\nalias deploy = nix $1 --label $2\n"));
+    sandbox.negma(&["hm", "make"]).assert().success();
+    assert_eq!(sandbox.calls(), ["home-manager switch"]);
+
+    sandbox
+        .negma(&["deploy", "make"])
+        .assert()
+        .failure()
+        .stderr(contains("Alias 'deploy' needs an argument for $2"));
+}
+
+#[test]
+fn alias_bodies_keep_their_flags() {
+    let sandbox = Sandbox::new(&config(
+        "alias why = explain nix make --impure --option sandbox false --keep-going\nalias try = home make --dry-run\n",
+    ));
+    sandbox
+        .negma(&["why"])
+        .assert()
+        .success()
+        .stdout(contains("$ nixos-rebuild switch --keep-going --impure --option sandbox false"));
+    sandbox.negma(&["try"]).assert().success().stdout(contains("[dry-run]"));
+    assert!(sandbox.calls().is_empty());
+}
+
+#[test]
+fn make_report_summarizes_restarted_units() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox
+        .negma_as_root(&["nix", "make", "--report"])
+        .env(
+            "NEGMA_STUB_STDERR",
+            "restarting the following units: nginx.service, sshd.service\nreloading the following units: dbus.service",
+        )
+        .assert()
+        .success()
+        .stdout(contains("restarted: nginx, sshd").and(contains("reloaded:  dbus")));
+}
+
+#[test]
+fn flake_flag_resolves_the_current_directory() {
+    let sandbox = Sandbox::new(&config("SYSTEM_FLAKE = /etc/nixos#box\n"));
+    let checkout = sandbox.dir.path().canonicalize().unwrap();
+    fs::write(checkout.join("flake.nix"), "{ }\n").unwrap();
+    sandbox.negma_as_root(&["nix", "make", "--flake", ".#dev"]).assert().success();
+    assert_eq!(
+        sandbox.calls(),
+        [format!("nixos-rebuild switch --flake {}#dev", checkout.display())]
+    );
+
+    fs::remove_file(checkout.join("flake.nix")).unwrap();
+    sandbox
+        .negma_as_root(&["nix", "make", "--flake", "."])
+        .assert()
+        .failure()
+        .stderr(contains("No flake.nix"));
+}
+
+#[test]
+fn home_list_generations_json_parses_home_manager_lines() {
+    let sandbox = Sandbox::new(&config(""));
+    let listing = "2024-01-02 10:00 : id 42 -> /nix/store/8kz6rbhqp3x8-home-manager-generation (current)\n\
+                   2023-12-30 18:45 : id 41 -> /nix/store/1m0q8v2a7c9d-home-manager-generation";
+    let output = sandbox
+        .negma(&["home", "list-generations", "--json"])
+        .env("NEGMA_STUB_STDOUT", listing)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let gens: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    assert_eq!(
+        gens,
+        serde_json::json!([
+            {
+                "number": 41,
+                "date": "2023-12-30 18:45",
+                "current": false,
+                "path": "/nix/store/1m0q8v2a7c9d-home-manager-generation",
+                "label": null,
+            },
+            {
+                "number": 42,
+                "date": "2024-01-02 10:00",
+                "current": true,
+                "path": "/nix/store/8kz6rbhqp3x8-home-manager-generation",
+                "label": null,
+            },
+        ])
+    );
+}
+
+#[test]
+fn list_generations_size_shows_closure_sizes() {
+    let sandbox = Sandbox::new(&config(""));
+    // Like a default install: `nix path-info` only works with the nix-command feature enabled.
+    fs::write(
+        sandbox.dir.path().join("bin/nix"),
+        "#!/bin/sh\n[ \"$1 $2\" = \"--extra-experimental-features nix-command\" ] || exit 1\necho \"$5\t1572864\"\n",
+    )
+    .unwrap();
+    let listing = "2024-01-02 10:00 : id 42 -> /nix/store/8kz6rbhqp3x8-home-manager-generation (current)";
+    sandbox
+        .negma(&["home", "list-generations", "--size"])
+        .env("NEGMA_STUB_STDOUT", listing)
+        .assert()
+        .success()
+        .stdout(contains("2024-01-02 10:00  1.5 MiB  /nix/store/8kz6rbhqp3x8-home-manager-generation"));
+}
+
+#[test]
+fn list_generations_diff_adjacent_counts_package_changes() {
+    let sandbox = Sandbox::new(&config(""));
+    let log = sandbox.dir.path().join("calls.log");
+    fs::write(
+        sandbox.dir.path().join("bin/nix-store"),
+        format!(
+            "#!/bin/sh\necho \"nix-store $*\" >> {}\ncase \"$3\" in\n\
+             */a-home) printf '/nix/store/h-bash-5.2\\n/nix/store/h-vim-9.0\\n' ;;\n\
+             */b-home) printf '/nix/store/h-bash-5.3\\n/nix/store/h-git-2.44\\n/nix/store/h-jq-1.7\\n' ;;\n\
+             */c-home) printf '/nix/store/h-bash-5.3\\n/nix/store/h-git-2.45\\n' ;;\nesac\n",
+            log.display()
+        ),
+    )
+    .unwrap();
+    let listing = "2024-01-03 10:00 : id 3 -> /nix/store/c-home (current)\n\
+                   2024-01-02 10:00 : id 2 -> /nix/store/b-home\n\
+                   2024-01-01 10:00 : id 1 -> /nix/store/a-home";
+    sandbox
+        .negma(&["home", "list-generations", "--diff-adjacent", "--limit", "2"])
+        .env("NEGMA_STUB_STDOUT", listing)
+        .assert()
+        .success()
+        .stdout(contains("2  2024-01-02 10:00  +2 -1  /nix/store/b-home"))
+        .stdout(contains("3  2024-01-03 10:00  +0 -1  /nix/store/c-home"));
+    // Generation 2's closure is compared with both neighbours but queried once.
+    let queries = sandbox.calls().iter().filter(|c| c.starts_with("nix-store")).count();
+    assert_eq!(queries, 3);
+}
+
+#[test]
+fn home_make_in_module_mode_points_to_nix_make() {
+    let sandbox = Sandbox::new(&config("HOME_MANAGER_MODE = module\n"));
+    sandbox
+        .negma(&["home", "make"])
+        .assert()
+        .success()
+        .stdout(contains("installed as a NixOS module").and(contains("negma nix make")));
+    assert!(sandbox.calls().is_empty());
+    assert_eq!(sandbox.history(), "");
+}
+
+#[test]
+fn home_list_generations_json_is_empty_without_generations() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox
+        .negma(&["home", "list-generations", "--json"])
+        .assert()
+        .success()
+        .stdout("[]\n");
+}
+
+#[test]
+fn nix_gc_keeps_outputs_and_derivations_on_request() {
+    let sandbox = Sandbox::new(&config("GC_KEEP_DERIVATIONS = true\n"));
+    sandbox.negma_as_root(&["nix", "gc", "--keep-outputs"]).assert().success();
+    assert_eq!(
+        sandbox.calls(),
+        ["nix-env collect-garbage -d --option keep-derivations true --option keep-outputs true"]
+    );
+}
+
+#[test]
+fn option_pairs_are_forwarded_and_validated() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox
+        .negma(&["home", "make", "--option", "sandbox", "false", "--option", "cores", "2"])
+        .assert()
+        .success();
+    assert_eq!(sandbox.calls(), ["home-manager switch --option sandbox false --option cores 2"]);
+
+    sandbox
+        .negma(&["home", "make", "--option", "sandbox"])
+        .assert()
+        .failure()
+        .stderr(contains("'--option' needs a key and a value"));
+}
+
+#[test]
+fn optimise_dry_run_reports_what_nix_store_would_free() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox
+        .negma_as_root(&["nix", "optimise", "--dry-run"])
+        .env("NEGMA_STUB_STDERR", "1536.00 MiB freed by hard-linking 4200 files")
+        .assert()
+        .success()
+        .stdout(contains("Optimising would free about 1.5 GiB by hard-linking 4200 files."));
+    assert_eq!(sandbox.calls(), ["nix-store --optimise --dry-run"]);
+}
+
+#[test]
+fn missing_nixos_rebuild_is_reported_as_not_installed() {
+    if std::path::Path::new("/run/current-system/sw/bin/nixos-rebuild").exists() {
+        return;
+    }
+    let sandbox = Sandbox::new(&config(""));
+    let bin = sandbox.dir.path().join("bin");
+    fs::remove_file(bin.join("nixos-rebuild")).unwrap();
+    sandbox
+        .negma_as_root(&["nix", "make"])
+        .env("PATH", &bin)
+        .assert()
+        .failure()
+        .stderr(contains("'nixos-rebuild' is not installed"));
+}
+
+#[test]
+fn config_set_rewrites_or_appends_lines() {
+    let sandbox = Sandbox::new(&config("# keep this comment\nKEEP = 5\n"));
+    sandbox.negma(&["config", "set", "KEEP", "3"]).assert().success();
+    sandbox.negma(&["config", "set", "alias", "mk", "nix", "make"]).assert().success();
+    let written = fs::read_to_string(sandbox.home().join(".config/negma/config.cfg")).unwrap();
+    assert_eq!(written, config("# keep this comment\nKEEP = 3\nalias mk = nix make\n"));
+
+    sandbox
+        .negma(&["config", "set", "NOT_A_KEY", "1"])
+        .assert()
+        .failure()
+        .stderr(contains("Unknown config key 'NOT_A_KEY'"));
+}
+
+#[test]
+fn config_set_keeps_flag_values_verbatim() {
+    let sandbox = Sandbox::new(&config(""));
+    sandbox.negma(&["config", "set", "REBUILD_FLAGS", "--impure", "--show-trace"]).assert().success();
+    sandbox.negma(&["config", "set", "NIX_CHECK_FLAGS", "--option", "sandbox", "false"]).assert().success();
+    sandbox.negma(&["--dry-run", "config", "set", "alias", "dev", "nix", "make", "--keep-going"]).assert().success();
+    let written = fs::read_to_string(sandbox.home().join(".config/negma/config.cfg")).unwrap();
+    assert!(written.contains("REBUILD_FLAGS = --impure --show-trace\n"), "{}", written);
+    assert!(written.contains("NIX_CHECK_FLAGS = --option sandbox false\n"), "{}", written);
+    assert!(!written.contains("alias dev"), "{}", written);
+}
+
+#[test]
+fn config_get_prints_resolved_values() {
+    let sandbox = Sandbox::new(&config("KEEP = 7\nalias mk = nix make\n"));
+    sandbox.negma(&["config", "get", "keep"]).assert().success().stdout("7\n");
+    sandbox.negma(&["--cores", "3", "config", "get", "BUILD_CORES"]).assert().success().stdout("3\n");
+    sandbox.negma(&["config", "get", "alias.mk"]).assert().success().stdout("nix make\n");
+    sandbox.negma(&["config", "get", "NOPE"]).assert().failure().stderr(contains("Unknown config key"));
+}
+
+#[test]
+fn config_check_exit_code_encodes_severity() {
+    Sandbox::new(&config("")).negma(&["config", "check"]).assert().code(0).stdout(contains("valid"));
+
+    let sandbox = Sandbox::new(&config("KEEP = lots\n"));
+    sandbox
+        .negma(&["config", "check", "--json"])
+        .assert()
+        .code(2)
+        .stdout(contains(r#""line":5"#).and(contains(r#""severity":"warning""#)))
+        .stdout(contains("invalid KEEP value"));
+
+    Sandbox::new(&config("KEEP = lots\nnonsense\n"))
+        .negma(&["config", "check"])
+        .assert()
+        .code(3)
+        .stdout(contains("line 6: error: unrecognized line at 6."))
+        .stderr(contains("1 error(s) and 1 warning(s)").and(contains("[negma:config]").not()));
+}
+
+#[test]
+fn config_problems_print_with_their_severity() {
+    Sandbox::new(&config("KEEP = lots\nnonsense\n"))
+        .negma(&["config", "get", "keep"])
+        .assert()
+        .success()
+        .stderr(contains("warning: invalid KEEP value").and(contains("error: unrecognized line at 6.")))
+        .stderr(contains("warning: unrecognized").not());
+}
+
+#[test]
+fn substituters_from_lock_passes_the_flake_caches() {
+    let sandbox = Sandbox::new(&config(""));
+    let flake = sandbox.dir.path().join("flake");
+    fs::create_dir_all(&flake).unwrap();
+    fs::write(
+        flake.join("flake.nix"),
+        "{\n  nixConfig = {\n    extra-substituters = [ \"https://cache.example.org\" ];\n    \
+         extra-trusted-public-keys = \"cache.example.org-1:abc=\";\n  };\n  outputs = _: { };\n}\n",
+    )
+    .unwrap();
+    let flake_ref = format!("{}#box", flake.display());
+    sandbox
+        .negma_as_root(&["nix", "make", "--flake", &flake_ref, "--substituters-from-lock"])
+        .assert()
+        .success();
+    assert_eq!(
+        sandbox.calls(),
+        [format!(
+            "nixos-rebuild switch --flake {} --option extra-substituters https://cache.example.org \
+             --option extra-trusted-public-keys cache.example.org-1:abc=",
+            flake_ref
+        )]
+    );
+}
+
+#[test]
+fn uninstall_removes_negma_state_but_can_keep_the_config() {
+    let sandbox = Sandbox::new(&config(""));
+    let dir = sandbox.home().join(".config/negma");
+    fs::write(dir.join("history.log"), "").unwrap();
+    fs::write(dir.join("labels.json"), "{}").unwrap();
+
+    sandbox
+        .negma(&["uninstall", "--keep-config", "--yes"])
+        .assert()
+        .success()
+        .stdout(contains("history.log").and(contains("labels.json")));
+    let left: Vec<_> = fs::read_dir(&dir).unwrap().flatten().map(|e| e.file_name()).collect();
+    assert_eq!(left, ["config.cfg"]);
+
+    sandbox.negma(&["uninstall", "--yes"]).assert().success();
+    assert!(!dir.exists());
+}
+
+#[test]
+fn uninstall_aborts_when_the_lock_cannot_be_taken() {
+    let sandbox = Sandbox::new(&config(""));
+    let dir = sandbox.home().join(".config/negma");
+    // A directory where the lock file belongs makes opening it fail.
+    fs::create_dir(dir.join("negma.lock")).unwrap();
+
+    sandbox
+        .negma(&["uninstall", "--yes"])
+        .assert()
+        .code(1)
+        .stderr(contains("Failed to acquire the negma lock"));
+    assert!(dir.join("config.cfg").exists());
+}
+
+#[test]
+fn history_clear_keeps_the_log_under_dry_run() {
+    let sandbox = Sandbox::new(&config(""));
+    let log = sandbox.home().join(".config/negma/history.log");
+    fs::write(&log, "2024-06-01 10:00:00\t0\thome make\n").unwrap();
+    sandbox
+        .negma(&["--dry-run", "history", "--clear"])
+        .assert()
+        .success()
+        .stdout(contains("[dry-run] remove").and(contains("History cleared.").not()));
+    assert!(log.exists());
+
+    sandbox.negma(&["history", "--clear"]).assert().success().stdout(contains("History cleared."));
+    assert!(!log.exists());
+}
+
+#[test]
+fn pager_is_bypassed_when_stdout_is_not_a_terminal() {
+    let sandbox = Sandbox::new(&config("PAGER = false\n"));
+    fs::write(sandbox.home().join(".config/negma/history.log"), "2024-06-01 10:00:00\t0\thome make\n").unwrap();
+    sandbox
+        .negma(&["--pager", "history"])
+        .assert()
+        .success()
+        .stdout(contains("Showing 1 of 1").and(contains("home make")));
+    sandbox.negma(&["config", "get", "PAGER"]).assert().success().stdout("false\n");
+}
+
+#[test]
+fn log_level_filters_the_diagnostic_log() {
+    let sandbox = Sandbox::new(&config("LOG_LEVEL = info\n"));
+    let log = sandbox.home().join(".config/negma/negma.log");
+    sandbox.negma(&["home", "make"]).assert().success();
+    sandbox.negma(&["history", "x"]).assert().code(1);
+    let content = fs::read_to_string(&log).unwrap();
+    assert!(content.contains("INFO  negma home make"));
+    assert!(content.contains("ERROR Invalid history count 'x'"));
+    assert!(!content.contains("DEBUG"));
+}
+
+#[test]
+fn path_config_values_expand_a_leading_tilde() {
+    let sandbox = Sandbox::new(&config("SYSTEM_FLAKE = ~/nixos#box\nNIXOS_DIR = ~\nHOME_MANAGER_DIR = /srv/~/hm\n"));
+    let home = sandbox.home().display().to_string();
+    let get = |key: &str| sandbox.negma(&["config", "get", key]).assert().success();
+    get("SYSTEM_FLAKE").stdout(format!("{}/nixos#box\n", home));
+    get("NIXOS_DIR").stdout(format!("{}\n", home));
+    get("HOME_MANAGER_DIR").stdout("/srv/~/hm\n");
+}
+
+#[test]
+fn piped_output_has_no_color_codes_unless_forced() {
+    let sandbox = Sandbox::new(&config(""));
+    let run = |force: Option<&str>| {
+        let mut cmd = sandbox.negma(&["history"]);
+        cmd.env_remove("NO_COLOR");
+        if let Some(force) = force {
+            cmd.env("CLICOLOR_FORCE", force);
+        }
+        String::from_utf8(cmd.assert().success().get_output().stdout.clone()).unwrap()
+    };
+    assert!(!run(None).contains('\x1b'));
+    assert!(run(Some("1")).contains('\x1b'));
+}
+
+#[test]
+fn config_warnings_follow_the_configured_theme() {
+    let sandbox = Sandbox::new(&config("KEEP = lots\nTHEME = mono\n"));
+    let output = sandbox.negma(&["history"]).env("CLICOLOR_FORCE", "1").assert().success().get_output().clone();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("warning: invalid KEEP value"), "{}", stderr);
+    assert!(!stderr.contains('\x1b'), "{}", stderr);
+}
+
+#[test]
+fn gui_editors_get_their_wait_flag_unless_editor_args_is_set() {
+    for (extra, args) in [("", "--wait"), ("EDITOR_ARGS = -n\n", "-n")] {
+        let sandbox = Sandbox::new(&config(&format!("EDITOR = code\n{}", extra)));
+        let code = sandbox.dir.path().join("bin/code");
+        fs::write(&code, "#!/bin/sh\necho \"code $*\" >> \"$NEGMA_STUB_LOG\"\n").unwrap();
+        fs::set_permissions(&code, fs::Permissions::from_mode(0o755)).unwrap();
+        let cfg = sandbox.home().join(".config/negma/config.cfg");
+        sandbox.negma(&["edit-cfg"]).assert().success();
+        assert_eq!(sandbox.calls(), [format!("code {} {}", args, cfg.display())]);
+    }
+}
+
+#[test]
+fn due_auto_gc_is_skipped_while_another_run_holds_the_lock() {
+    let sandbox = Sandbox::new(&config("GC_AGE_DAYS = 0\n").replace("AUTO_GC = false", "AUTO_GC = true"));
+    let negma_dir = sandbox.home().join(".config/negma");
+    fs::write(negma_dir.join("auto_gc_marker"), "").unwrap();
+    let lock_file = fs::File::create(negma_dir.join("negma.lock")).unwrap();
+    let held = nix::fcntl::Flock::lock(lock_file, nix::fcntl::FlockArg::LockExclusiveNonblock).unwrap();
+
+    sandbox
+        .negma(&["history"])
+        .assert()
+        .success()
+        .stdout(contains("Auto GC skipped"));
+    assert!(sandbox.calls().is_empty());
+
+    drop(held);
+    sandbox.negma(&["history"]).assert().success();
+    assert_eq!(sandbox.calls(), ["nix-collect-garbage -d"]);
+}
+
+#[test]
+fn boot_reboot_reboots_after_a_successful_build() {
+    let sandbox = Sandbox::new(&config(""));
+    let bin = sandbox.dir.path().join("bin");
+    fs::write(bin.join("systemctl"), "#!/bin/sh\necho \"systemctl $*\" >> \"$NEGMA_STUB_LOG\"\n").unwrap();
+    fs::set_permissions(bin.join("systemctl"), fs::Permissions::from_mode(0o755)).unwrap();
+
+    sandbox.negma_as_root(&["nix", "boot", "--reboot", "--yes"]).assert().success();
+    assert_eq!(sandbox.calls(), ["nixos-rebuild boot", "systemctl reboot"]);
+
+    sandbox
+        .negma_as_root(&["--dry-run", "nix", "boot", "--reboot"])
+        .assert()
+        .failure()
+        .stderr(contains("--reboot cannot be combined with --dry-run"));
+}